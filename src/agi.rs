@@ -0,0 +1,283 @@
+//! Caller ID names for Asterisk, looked up in the phone book.
+//!
+//! Asterisk starts an AGI session by sending `agi_key: value` lines ending in an empty line.
+//! The session then sends commands one line at a time and Asterisk answers each with a line
+//! like `200 result=1`. A session here reads `agi_callerid`, looks the number up and, if a
+//! contact has it, sends `SET VARIABLE "CALLERID(name)" "<name>"`.
+//!
+//! With `AGI(phone_book_with_data,agi)` in the dialplan, Asterisk runs the program for every
+//! call and speaks to it on standard input and output. FastAGI does the same over TCP,
+//! so one long-running listener serves `AGI(agi://127.0.0.1:4573)` without starting a
+//! process per call. A session can be tried out by hand by typing what Asterisk would send:
+//! `printf 'agi_callerid: +442079460958\n\n200 result=1\n' | phone_book_with_data agi`.
+
+use crate::{database::NumberMatch, error::PhoneBookError, store::PhoneBookStore};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+/// How long a FastAGI connection may stay silent before it is dropped,
+/// so that one stuck connection cannot keep the calls after it waiting.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs one AGI session and returns the name the caller ID was set to, if any.
+/// A caller without a number, or with a number nobody has, is left alone.
+pub fn serve_session(
+    mut reader: impl BufRead,
+    mut writer: impl Write,
+    phone_book_db: &dyn PhoneBookStore,
+    number_match: NumberMatch,
+) -> Result<Option<String>, PhoneBookError> {
+    let environment = read_environment(&mut reader)?;
+    let caller = match environment.get("agi_callerid").map(|caller| caller.trim()) {
+        None | Some("" | "unknown") => return Ok(None),
+        Some(caller) => caller,
+    };
+
+    let contacts = phone_book_db.find_contacts_by_number(caller, number_match)?;
+    let Some(contact) = contacts.into_iter().next() else {
+        return Ok(None);
+    };
+    command(
+        &mut reader,
+        &mut writer,
+        &format!("SET VARIABLE \"CALLERID(name)\" {}", quote(&contact.name)),
+    )?;
+
+    Ok(Some(contact.name))
+}
+
+/// Answers FastAGI connections on `address` one after another, for as long as it runs.
+/// What happens is passed to `report` as a line of text: the address listened on,
+/// connections that cannot be accepted and sessions that fail, neither of which stops
+/// the others.
+pub fn listen(
+    address: &str,
+    phone_book_db: &dyn PhoneBookStore,
+    number_match: NumberMatch,
+    mut report: impl FnMut(&str),
+) -> Result<(), PhoneBookError> {
+    let listener = TcpListener::bind(address)?;
+    report(&format!(
+        "Listening for FastAGI on {}",
+        listener.local_addr()?
+    ));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                report(&format!("Accepting a FastAGI connection failed: {err}"));
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "an unknown address".to_owned(), |peer| peer.to_string());
+        if let Err(err) = serve_connection(stream, phone_book_db, number_match) {
+            report(&format!("FastAGI session from {peer} failed: {err}"));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the session of one FastAGI connection.
+pub fn serve_connection(
+    stream: TcpStream,
+    phone_book_db: &dyn PhoneBookStore,
+    number_match: NumberMatch,
+) -> Result<Option<String>, PhoneBookError> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let reader = BufReader::new(stream.try_clone()?);
+    serve_session(reader, stream, phone_book_db, number_match)
+}
+
+/// Reads `agi_key: value` lines up to the empty line that ends them.
+fn read_environment(reader: &mut impl BufRead) -> Result<BTreeMap<String, String>, PhoneBookError> {
+    let mut environment = BTreeMap::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(environment);
+        }
+        match line.split_once(':') {
+            Some((key, value)) => {
+                environment.insert(key.trim().to_owned(), value.trim().to_owned());
+            }
+            None => {
+                return Err(PhoneBookError::Agi(format!(
+                    "expected agi_key: value, got {line:?}"
+                )))
+            }
+        }
+    }
+}
+
+/// Sends a command and checks that Asterisk accepted it.
+fn command(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    command: &str,
+) -> Result<(), PhoneBookError> {
+    writeln!(writer, "{command}")?;
+    writer.flush()?;
+
+    let reply = read_line(reader)?;
+    if reply.starts_with("200 ") {
+        Ok(())
+    } else if reply == "HANGUP" {
+        Err(PhoneBookError::Agi("the call hung up".to_owned()))
+    } else {
+        Err(PhoneBookError::Agi(format!(
+            "{command:?} was answered with {reply:?}"
+        )))
+    }
+}
+
+/// Reads a line without its line ending, failing if the other side is gone.
+fn read_line(reader: &mut impl BufRead) -> Result<String, PhoneBookError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(PhoneBookError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Asterisk closed the AGI session",
+        )));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Quotes a command argument, which may not span lines.
+fn quote(argument: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in argument.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push(' '),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve_connection, serve_session};
+    use crate::{
+        database::{NumberMatch, PhoneBookDB},
+        entry::{NumberLabel, PhoneEntry, PhoneNumber},
+        error::PhoneBookError,
+    };
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    fn phone_book() -> PhoneBookDB {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        phone_book_db
+            .insert_contact(
+                "Arnold \"Arnie\" Baker".to_owned(),
+                PhoneEntry {
+                    numbers: vec![PhoneNumber::new(NumberLabel::Work, "+44 20 7946 0958")],
+                    ..PhoneEntry::default()
+                },
+            )
+            .unwrap();
+        phone_book_db
+    }
+
+    /// Plays Asterisk: sends `environment` and answers every command with `reply`.
+    fn session(environment: &str, reply: &str) -> (Result<Option<String>, PhoneBookError>, String) {
+        let input = format!("agi_request: phone_book\n{environment}\n{reply}\n");
+        let mut output = Vec::new();
+        let result = serve_session(
+            input.as_bytes(),
+            &mut output,
+            &phone_book(),
+            NumberMatch::Whole,
+        );
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn sets_the_name_of_known_callers() {
+        let (result, output) = session("agi_callerid: +442079460958\n", "200 result=1");
+
+        assert_eq!(result.unwrap().as_deref(), Some("Arnold \"Arnie\" Baker"));
+        assert_eq!(
+            output,
+            "SET VARIABLE \"CALLERID(name)\" \"Arnold \\\"Arnie\\\" Baker\"\n"
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_callers_alone() {
+        for environment in [
+            "agi_callerid: +12125550100\n",
+            "agi_callerid: unknown\n",
+            "",
+        ] {
+            let (result, output) = session(environment, "");
+            assert_eq!(result.unwrap(), None, "{environment}");
+            assert_eq!(output, "");
+        }
+    }
+
+    #[test]
+    fn reports_protocol_errors() {
+        let (result, _) = session(
+            "agi_callerid: +442079460958\n",
+            "510 Invalid or unknown command",
+        );
+        assert!(matches!(result, Err(PhoneBookError::Agi(_))));
+        let (result, _) = session("agi_callerid: +442079460958\n", "HANGUP");
+        assert!(matches!(result, Err(PhoneBookError::Agi(message)) if message.contains("hung up")));
+        let (result, _) = session("not an environment line\n", "");
+        assert!(matches!(result, Err(PhoneBookError::Agi(_))));
+
+        let mut output = Vec::new();
+        let result = serve_session(
+            "agi_callerid: +442079460958\n".as_bytes(),
+            &mut output,
+            &phone_book(),
+            NumberMatch::Whole,
+        );
+        assert!(matches!(result, Err(PhoneBookError::Io(_))));
+    }
+
+    #[test]
+    fn speaks_fastagi_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let asterisk = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(
+                    b"agi_network: yes\nagi_request: agi://127.0.0.1/\nagi_callerid: 02079460958\n\n",
+                )
+                .unwrap();
+            let mut command = String::new();
+            BufReader::new(&stream).read_line(&mut command).unwrap();
+            stream.write_all(b"200 result=1\n").unwrap();
+            command
+        });
+
+        let mut phone_book_db = phone_book();
+        phone_book_db.set_default_country(Some("GB")).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let name = serve_connection(stream, &phone_book_db, NumberMatch::Whole).unwrap();
+
+        assert_eq!(name.as_deref(), Some("Arnold \"Arnie\" Baker"));
+        assert!(asterisk
+            .join()
+            .unwrap()
+            .starts_with("SET VARIABLE \"CALLERID(name)\""));
+    }
+}
@@ -0,0 +1,1325 @@
+//! The non-interactive command line, for calling the phone book from scripts.
+//! Running the program without a command starts the interactive mode instead.
+
+use crate::{
+    show_audit_log, show_phone_book, show_search_hits, show_similar_contacts, HIGHLIGHT_MARKS,
+};
+use phone_book_with_data::{
+    agi,
+    config::HISTORY_VARIABLE,
+    csv_io::{self, CsvOptions},
+    desk_phone::{self, DeskPhone},
+    history::GitHistory,
+    json_io,
+    vcard::{self, VCardVersion},
+    AddressLabel, Contact, ContactId, EmailAddress, NameMatch, NumberLabel, NumberMatch,
+    OnConflict, PhoneBookDB, PhoneBookError, PhoneBookStore, PhoneEntry, PhoneNumber,
+    PostalAddress,
+};
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+const HELP: &str = "\
+Usage: phone_book_with_data [--db <FILE> | --vdir <DIR>] [--history <DIR>] [COMMAND]
+
+Without a command the interactive mode starts.
+
+Commands:
+  add <NAME> [DETAILS]              Add a contact
+  get <NAME> | --id <ID>            Show a contact
+  list                              Show all contacts
+  remove <NAME> | --id <ID>         Remove a contact
+  modify <NAME> | --id <ID> [DETAILS] [--name <NEW NAME>] [--clear <KIND>]
+                                    Replace the kinds of details given, or rename
+  search <TEXT> [--prefix]          Show contacts whose name contains TEXT, or starts with it,
+                                    ignoring case and accents
+  find <QUERY> [--fuzzy]            Search names, numbers, emails and addresses, best matches
+                                    first. \"two words\" finds a phrase, word* any word starting
+                                    with word. Without matches, or with --fuzzy, names alike
+                                    are shown instead
+  who <NUMBER> [--last <N>]         Show whose number this is, however it is written, or
+                                    whose numbers end in the same last N digits
+  agi [--listen <[ADDRESS:]PORT>] [--last <N>]
+                                    Set the caller ID name of Asterisk calls, speaking AGI on
+                                    standard input and output, or FastAGI on a TCP port
+                                    (4573 is the usual one). A bare port only listens on
+                                    this machine, give 0.0.0.0:PORT to listen on every
+                                    address. --last is as with who
+  import <FILE> [FORMAT] [--on-conflict <skip|overwrite|merge>]
+                                    Add all contacts of a file. When a name is taken, the
+                                    contact is skipped (the default), overwritten or merged
+  export <FILE> [FORMAT]            Write all contacts to a new file
+  where                             Show which database file or directory is used
+  history <NAME>                    Show every change made to the contacts that had NAME,
+                                    also after they were renamed or removed. Only databases
+                                    keep this audit log
+  log [<NAME> | --id <ID>]          Show the recorded changes, of one contact or of all
+  revert <COMMIT>                   Undo a change shown by log, recording that as a change
+
+Details:
+  --mobile, --work, --home, --fax, --pager, --main, --other <NUMBER>
+                                    A phone number with that label, may be repeated
+  --phone <LABEL>=<NUMBER>          A phone number with any label
+  --preferred <NUMBER>              Which of the numbers is preferred
+  --email [<LABEL>=]<ADDRESS>       An email address, labelled other by default
+  --address [<LABEL>=]<STREET>;<CITY>;<REGION>;<POSTCODE>;<COUNTRY>
+                                    A postal address, labelled other by default
+  --clear <numbers|emails|addresses>
+                                    With modify, remove all details of that kind
+
+Format:
+  --format <sqlite|csv|vcard|json|jsonl>
+                                    The file format, csv for files ending in .csv or for -,
+                                    which is standard input or output, vcard for .vcf or .vcard,
+                                    json for .json, jsonl for .jsonl or .ndjson, else sqlite.
+                                    Exports can also be cisco, yealink or grandstream desk
+                                    phone directories, which leave out what the phones
+                                    have no room for
+  --delimiter <CHAR>                The CSV cell delimiter, ',' by default; use tab for tabs
+  --quote <CHAR>                    The CSV quote character, a double quote by default
+  --vcard-version <3|4>             The vCard version to export, 3 by default
+
+Options:
+  --db <FILE>                       The database file to use. Without it, PHONEBOOK_DB is used,
+                                    then the database setting in ~/.config/phonebook/config,
+                                    then ~/.local/share/phonebook/phonebook.sqlite
+  --vdir <DIR>                      Keep the contacts in DIR instead, as one vCard file each,
+                                    the way vdirsyncer and khard do. Cards other programs
+                                    changed since they were read are never overwritten
+  --history <DIR>                   Commit every change to a git repository in DIR, which
+                                    log and revert read. Without it, PHONEBOOK_HISTORY is used
+  -h, --help                        Show this help
+
+Exit codes: 0 on success, 1 when the command failed, 2 when it was used wrongly.";
+
+/// A command given on the command line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Add {
+        name: String,
+        details: Details,
+    },
+    Get(Target),
+    List,
+    Remove(Target),
+    Modify {
+        target: Target,
+        details: Details,
+        new_name: Option<String>,
+    },
+    Search {
+        text: String,
+        name_match: NameMatch,
+    },
+    Find {
+        query: String,
+        /// Shows names alike even when the query matches exactly.
+        fuzzy: bool,
+    },
+    Who {
+        number: String,
+        number_match: NumberMatch,
+    },
+    Agi {
+        /// Where to listen for FastAGI connections, or None to speak AGI on stdin and stdout.
+        listen: Option<String>,
+        number_match: NumberMatch,
+    },
+    Import {
+        file_path: PathBuf,
+        format: Format,
+        on_conflict: OnConflict,
+    },
+    Export {
+        file_path: PathBuf,
+        format: Format,
+    },
+    Where,
+    History(String),
+    Log(Option<Target>),
+    Revert(String),
+}
+
+/// The format of a file to import or export.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Format {
+    Sqlite,
+    Csv(CsvOptions),
+    VCard(VCardVersion),
+    Json,
+    JsonLines,
+    /// Only for exports.
+    DeskPhone(DeskPhone),
+}
+
+/// Everything given on the command line.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invocation {
+    /// The value of `--db`.
+    pub database: Option<PathBuf>,
+    /// The value of `--vdir`, which is used instead of a database.
+    pub vdir: Option<PathBuf>,
+    /// The value of `--history`.
+    pub history: Option<PathBuf>,
+    /// None when the interactive mode should start.
+    pub command: Option<Command>,
+}
+
+/// Which contact a command is about.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Target {
+    Name(String),
+    Id(ContactId),
+}
+
+/// The details given with `add` or `modify`.
+/// A kind that was not mentioned at all is None, so `modify` leaves it alone.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Details {
+    numbers: Option<Vec<PhoneNumber>>,
+    emails: Option<Vec<EmailAddress>>,
+    addresses: Option<Vec<PostalAddress>>,
+}
+
+impl Details {
+    /// Replaces the kinds of details that were given.
+    fn apply_to(self, entry: &mut PhoneEntry) {
+        if let Some(numbers) = self.numbers {
+            entry.numbers = numbers;
+        }
+        if let Some(emails) = self.emails {
+            entry.emails = emails;
+        }
+        if let Some(addresses) = self.addresses {
+            entry.addresses = addresses;
+        }
+    }
+}
+
+/// The command line could not be understood.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn usage_error<T>(message: impl Into<String>) -> Result<T, UsageError> {
+    Err(UsageError(message.into()))
+}
+
+/// Reads the arguments after the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Invocation, UsageError> {
+    let args = args.into_iter().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(Invocation {
+            database: None,
+            vdir: None,
+            history: None,
+            command: Some(Command::Help),
+        });
+    }
+
+    let mut args = Args {
+        args: args.into_iter(),
+        pending_value: None,
+    };
+    let mut database = None;
+    let mut vdir = None;
+    let mut history = None;
+    let command = loop {
+        match args.args.next() {
+            Some(arg) if arg == "--db" => database = Some(args.value("--db")?.into()),
+            Some(arg) if arg.starts_with("--db=") => database = Some(arg["--db=".len()..].into()),
+            Some(arg) if arg == "--vdir" => vdir = Some(args.value("--vdir")?.into()),
+            Some(arg) if arg.starts_with("--vdir=") => vdir = Some(arg["--vdir=".len()..].into()),
+            Some(arg) if arg == "--history" => history = Some(args.value("--history")?.into()),
+            Some(arg) if arg.starts_with("--history=") => {
+                history = Some(arg["--history=".len()..].into())
+            }
+            Some(arg) => break Some(arg),
+            None => break None,
+        }
+    };
+    if database.is_some() && vdir.is_some() {
+        return usage_error("--db and --vdir cannot be used together");
+    }
+    let Some(command) = command else {
+        return Ok(Invocation {
+            database,
+            vdir,
+            history,
+            command: None,
+        });
+    };
+
+    let command = match command.as_str() {
+        "help" => Command::Help,
+        "add" => Command::Add {
+            name: args.positional("NAME")?,
+            details: args.details(false)?.0,
+        },
+        "get" => Command::Get(args.target()?),
+        "list" => Command::List,
+        "remove" => Command::Remove(args.target()?),
+        "modify" => {
+            let target = args.target()?;
+            let (details, new_name) = args.details(true)?;
+            if details == Details::default() && new_name.is_none() {
+                return usage_error("modify needs something to change");
+            }
+            Command::Modify {
+                target,
+                details,
+                new_name,
+            }
+        }
+        "search" => {
+            let text = args.positional("TEXT")?;
+            let mut name_match = NameMatch::Substring;
+            while let Some(flag) = args.next_flag()? {
+                match flag.as_str() {
+                    "--prefix" => name_match = NameMatch::Prefix,
+                    _ => return usage_error(format!("unknown option {flag:?}")),
+                }
+            }
+            Command::Search { text, name_match }
+        }
+        "find" => {
+            let query = args.positional("QUERY")?;
+            let mut fuzzy = false;
+            while let Some(flag) = args.next_flag()? {
+                match flag.as_str() {
+                    "--fuzzy" => fuzzy = true,
+                    _ => return usage_error(format!("unknown option {flag:?}")),
+                }
+            }
+            Command::Find { query, fuzzy }
+        }
+        "who" => {
+            let number = args.positional("NUMBER")?;
+            let mut number_match = NumberMatch::Whole;
+            while let Some(flag) = args.next_flag()? {
+                match flag.as_str() {
+                    "--last" => number_match = args.last_digits()?,
+                    _ => return usage_error(format!("unknown option {flag:?}")),
+                }
+            }
+            Command::Who {
+                number,
+                number_match,
+            }
+        }
+        "agi" => {
+            let mut listen = None;
+            let mut number_match = NumberMatch::Whole;
+            while let Some(flag) = args.next_flag()? {
+                match flag.as_str() {
+                    "--listen" => {
+                        let address = args.value(&flag)?;
+                        // A bare port listens on this machine only, as the phone book should
+                        // not be open to the network unless that is asked for.
+                        listen = Some(match address.parse::<u16>() {
+                            Ok(port) => format!("127.0.0.1:{port}"),
+                            Err(_) => address,
+                        });
+                    }
+                    "--last" => number_match = args.last_digits()?,
+                    _ => return usage_error(format!("unknown option {flag:?}")),
+                }
+            }
+            Command::Agi {
+                listen,
+                number_match,
+            }
+        }
+        "import" => {
+            let (file_path, format, on_conflict) = args.file(true)?;
+            Command::Import {
+                file_path,
+                format,
+                on_conflict,
+            }
+        }
+        "export" => {
+            let (file_path, format, _) = args.file(false)?;
+            Command::Export { file_path, format }
+        }
+        "where" => Command::Where,
+        "history" => Command::History(args.positional("NAME")?),
+        "log" if args.args.len() == 0 => Command::Log(None),
+        "log" => Command::Log(Some(args.target()?)),
+        "revert" => Command::Revert(args.positional("COMMIT")?),
+        command => return usage_error(format!("unknown command {command:?}")),
+    };
+
+    match args.args.next() {
+        Some(arg) => usage_error(format!("unexpected argument {arg:?}")),
+        None => Ok(Invocation {
+            database,
+            vdir,
+            history,
+            command: Some(command),
+        }),
+    }
+}
+
+/// Walks through the arguments, splitting "--flag=value" into flag and value.
+struct Args {
+    args: std::vec::IntoIter<String>,
+    pending_value: Option<String>,
+}
+
+impl Args {
+    fn positional(&mut self, what: &str) -> Result<String, UsageError> {
+        match self.args.next() {
+            Some(arg) if !arg.starts_with("--") => Ok(arg),
+            _ => usage_error(format!("missing {what}")),
+        }
+    }
+
+    /// Reads either a name or "--id <ID>".
+    fn target(&mut self) -> Result<Target, UsageError> {
+        let id = match self.args.next() {
+            Some(arg) if arg == "--id" => self.value("--id")?,
+            Some(arg) if arg.starts_with("--id=") => arg["--id=".len()..].to_owned(),
+            Some(arg) if !arg.starts_with("--") => return Ok(Target::Name(arg)),
+            _ => return usage_error("missing NAME or --id <ID>"),
+        };
+        match id.parse() {
+            Ok(id) => Ok(Target::Id(ContactId(id))),
+            Err(_) => usage_error(format!("{id:?} is not a contact ID")),
+        }
+    }
+
+    fn next_flag(&mut self) -> Result<Option<String>, UsageError> {
+        let Some(arg) = self.args.next() else {
+            return Ok(None);
+        };
+        if !arg.starts_with("--") {
+            return usage_error(format!("unexpected argument {arg:?}"));
+        }
+        match arg.split_once('=') {
+            Some((flag, value)) => {
+                self.pending_value = Some(value.to_owned());
+                Ok(Some(flag.to_owned()))
+            }
+            None => Ok(Some(arg)),
+        }
+    }
+
+    fn value(&mut self, flag: &str) -> Result<String, UsageError> {
+        match self.pending_value.take().or_else(|| self.args.next()) {
+            Some(value) => Ok(value),
+            None => usage_error(format!("{flag} needs a value")),
+        }
+    }
+
+    /// Reads the value of `--last`.
+    fn last_digits(&mut self) -> Result<NumberMatch, UsageError> {
+        let count = self.value("--last")?;
+        match count.parse() {
+            Ok(count) if count > 0 => Ok(NumberMatch::LastDigits(count)),
+            _ => usage_error(format!("--last takes a number of digits, not {count:?}")),
+        }
+    }
+
+    /// Reads detail flags until the arguments run out.
+    /// `--name` and `--clear` are only understood when modifying.
+    fn details(&mut self, modify: bool) -> Result<(Details, Option<String>), UsageError> {
+        let mut details = Details::default();
+        let mut preferred = None;
+        let mut new_name = None;
+
+        while let Some(flag) = self.next_flag()? {
+            match flag.as_str() {
+                "--phone" => {
+                    let value = self.value(&flag)?;
+                    let Some((label, number)) = value.split_once('=') else {
+                        return usage_error("--phone needs a value like home=0201234567");
+                    };
+                    let Ok(label) = label.parse::<NumberLabel>();
+                    details
+                        .numbers
+                        .get_or_insert_with(Vec::new)
+                        .push(PhoneNumber::new(label, number));
+                }
+                "--preferred" => preferred = Some(self.value(&flag)?),
+                "--email" => {
+                    let value = self.value(&flag)?;
+                    let (label, address) = split_label(&value);
+                    details
+                        .emails
+                        .get_or_insert_with(Vec::new)
+                        .push(EmailAddress {
+                            label,
+                            address: address.to_owned(),
+                        });
+                }
+                "--address" => {
+                    let value = self.value(&flag)?;
+                    details
+                        .addresses
+                        .get_or_insert_with(Vec::new)
+                        .push(parse_address(&value)?);
+                }
+                "--name" if modify => new_name = Some(self.value(&flag)?),
+                "--clear" if modify => match self.value(&flag)?.as_str() {
+                    "numbers" => details.numbers = Some(Vec::new()),
+                    "emails" => details.emails = Some(Vec::new()),
+                    "addresses" => details.addresses = Some(Vec::new()),
+                    kind => {
+                        return usage_error(format!(
+                            "--clear takes numbers, emails or addresses, not {kind:?}"
+                        ))
+                    }
+                },
+                _ => {
+                    // "--mobile", "--work" and the other predefined labels.
+                    let Some(label) = NumberLabel::PREDEFINED
+                        .into_iter()
+                        .find(|label| flag.strip_prefix("--") == Some(label.as_str()))
+                    else {
+                        return usage_error(format!("unknown option {flag:?}"));
+                    };
+                    let number = self.value(&flag)?;
+                    details
+                        .numbers
+                        .get_or_insert_with(Vec::new)
+                        .push(PhoneNumber::new(label, number));
+                }
+            }
+        }
+
+        match &mut details.numbers {
+            Some(numbers) => mark_preferred(numbers, preferred.as_deref())?,
+            None if preferred.is_some() => {
+                return usage_error("--preferred needs the numbers to be given too")
+            }
+            None => {}
+        }
+
+        Ok((details, new_name))
+    }
+}
+
+impl Args {
+    /// Reads the file to import or export and its format.
+    /// `--on-conflict` is only understood when importing.
+    fn file(&mut self, import: bool) -> Result<(PathBuf, Format, OnConflict), UsageError> {
+        let file_path = PathBuf::from(self.positional("FILE")?);
+        let mut format = None;
+        let mut csv_options = None;
+        let mut vcard_version = None;
+        let mut on_conflict = OnConflict::Skip;
+
+        while let Some(flag) = self.next_flag()? {
+            match flag.as_str() {
+                "--format" => format = Some(self.value(&flag)?),
+                "--delimiter" => {
+                    let delimiter = self.character(&flag)?;
+                    csv_options
+                        .get_or_insert_with(CsvOptions::default)
+                        .delimiter = delimiter;
+                }
+                "--quote" => {
+                    let quote = self.character(&flag)?;
+                    csv_options.get_or_insert_with(CsvOptions::default).quote = quote;
+                }
+                "--vcard-version" if !import => {
+                    vcard_version = match self.value(&flag)?.as_str() {
+                        "3" | "3.0" => Some(VCardVersion::V3),
+                        "4" | "4.0" => Some(VCardVersion::V4),
+                        version => {
+                            return usage_error(format!(
+                                "--vcard-version takes 3 or 4, not {version:?}"
+                            ))
+                        }
+                    }
+                }
+                "--on-conflict" if import => {
+                    on_conflict = match self.value(&flag)?.as_str() {
+                        "skip" => OnConflict::Skip,
+                        "overwrite" => OnConflict::Overwrite,
+                        "merge" => OnConflict::Merge,
+                        policy => {
+                            return usage_error(format!(
+                                "--on-conflict takes skip, overwrite or merge, not {policy:?}"
+                            ))
+                        }
+                    }
+                }
+                _ => return usage_error(format!("unknown option {flag:?}")),
+            }
+        }
+
+        let extension = file_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let format = match (format.as_deref(), extension.as_deref()) {
+            (Some(format), _) => format,
+            (None, _) if file_path.as_os_str() == "-" => "csv",
+            (None, Some("csv")) => "csv",
+            (None, Some("vcf" | "vcard")) => "vcard",
+            (None, Some("json")) => "json",
+            (None, Some("jsonl" | "ndjson")) => "jsonl",
+            (None, _) => "sqlite",
+        };
+        let format = match (format, csv_options, vcard_version) {
+            ("csv", csv_options, None) => Format::Csv(csv_options.unwrap_or_default()),
+            ("vcard", None, vcard_version) => {
+                Format::VCard(vcard_version.unwrap_or(VCardVersion::V3))
+            }
+            ("json", None, None) => Format::Json,
+            ("jsonl", None, None) => Format::JsonLines,
+            ("cisco" | "yealink" | "grandstream", _, _) if import => {
+                return usage_error("desk phone directories can only be exported")
+            }
+            ("cisco", None, None) => Format::DeskPhone(DeskPhone::Cisco),
+            ("yealink", None, None) => Format::DeskPhone(DeskPhone::Yealink),
+            ("grandstream", None, None) => Format::DeskPhone(DeskPhone::Grandstream),
+            ("sqlite", None, None) if file_path.as_os_str() != "-" => Format::Sqlite,
+            ("sqlite", None, None) => {
+                return usage_error("SQLite files cannot be standard input or output")
+            }
+            (
+                "csv" | "vcard" | "json" | "jsonl" | "cisco" | "yealink" | "grandstream" | "sqlite",
+                _,
+                _,
+            ) => {
+                return usage_error(
+                    "--delimiter and --quote only apply to CSV, and --vcard-version to vCard",
+                )
+            }
+            (format, _, _) => return usage_error(format!("unknown format {format:?}")),
+        };
+
+        Ok((file_path, format, on_conflict))
+    }
+
+    /// Reads a single ASCII character, where "tab" stands for a tab.
+    fn character(&mut self, flag: &str) -> Result<u8, UsageError> {
+        match self.value(flag)?.as_str() {
+            "tab" | "\\t" => Ok(b'\t'),
+            value => match value.as_bytes() {
+                [byte] if byte.is_ascii() => Ok(*byte),
+                _ => usage_error(format!("{flag} needs a single character, not {value:?}")),
+            },
+        }
+    }
+}
+
+/// Splits "work=someone@example.com" into its label and value. Without a label, "other" is used.
+fn split_label(value: &str) -> (AddressLabel, &str) {
+    match value.split_once('=') {
+        Some((label, value)) => {
+            let Ok(label) = label.parse();
+            (label, value)
+        }
+        None => (AddressLabel::Other, value),
+    }
+}
+
+/// Reads "[LABEL=]STREET;CITY;REGION;POSTCODE;COUNTRY". Trailing parts may be left out.
+fn parse_address(value: &str) -> Result<PostalAddress, UsageError> {
+    let (label, parts) = split_label(value);
+    match PostalAddress::from_parts(label, parts) {
+        Some(address) => Ok(address),
+        None => usage_error("--address has at most five parts separated by \";\""),
+    }
+}
+
+/// Marks the number equal to `preferred`, or the only number if there is just one.
+fn mark_preferred(numbers: &mut [PhoneNumber], preferred: Option<&str>) -> Result<(), UsageError> {
+    match preferred {
+        Some(preferred) => match numbers.iter_mut().find(|number| number.number == preferred) {
+            Some(number) => number.preferred = true,
+            None => return usage_error("--preferred must be one of the numbers given"),
+        },
+        None => {
+            if let [number] = numbers {
+                number.preferred = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command, committing what it changes to `history` if there is one.
+/// Failures are reported on stderr and through the exit code.
+pub fn run(
+    command: Command,
+    phone_book_db: &mut dyn PhoneBookStore,
+    history: Option<&GitHistory>,
+) -> ExitCode {
+    let result = match history {
+        // A revert commits all it changes at once.
+        Some(history) if !matches!(command, Command::Revert(_)) => {
+            execute(command, &mut history.record(phone_book_db), Some(history))
+        }
+        _ => execute(command, phone_book_db, history),
+    };
+    for warning in history.into_iter().flat_map(GitHistory::take_warnings) {
+        eprintln!("warning: {warning}");
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn execute(
+    command: Command,
+    phone_book_db: &mut dyn PhoneBookStore,
+    history: Option<&GitHistory>,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Help => println!("{HELP}"),
+        Command::Add { name, details } => {
+            let mut entry = PhoneEntry::default();
+            details.apply_to(&mut entry);
+            let id = phone_book_db.insert_contact(name.clone(), entry)?;
+            println!("Added {name} with ID {id}");
+        }
+        Command::Get(target) => {
+            let contacts = match target {
+                Target::Name(name) => {
+                    let contacts = phone_book_db.read_contacts_by_name(&name)?;
+                    if contacts.is_empty() {
+                        return Err(PhoneBookError::NotFound(name).into());
+                    }
+                    contacts
+                }
+                Target::Id(id) => vec![find_contact(phone_book_db, Target::Id(id))?],
+            };
+            show_phone_book(&contacts);
+        }
+        Command::List => show_phone_book(&phone_book_db.read_all_contacts()?),
+        Command::Remove(target) => {
+            let contact = find_contact(phone_book_db, target)?;
+            phone_book_db.remove_contact(contact.id)?;
+            println!("Removed {} with ID {}", contact.name, contact.id);
+        }
+        Command::Modify {
+            target,
+            details,
+            new_name,
+        } => {
+            let contact = find_contact(phone_book_db, target)?;
+            let mut entry = contact.entry.clone();
+            details.apply_to(&mut entry);
+            // Only what changes is written, and the details and the name change together.
+            let entry = (entry != contact.entry).then_some(entry);
+            let new_name = new_name.filter(|new_name| *new_name != contact.name);
+            phone_book_db.edit_contact(contact.id, entry, new_name)?;
+            println!("Modified the contact with ID {}", contact.id);
+        }
+        Command::Search { text, name_match } => {
+            let contacts = phone_book_db.search_contacts(&text, name_match)?;
+            if contacts.is_empty() {
+                return Err(PhoneBookError::NotFound(text).into());
+            }
+            show_phone_book(&contacts);
+        }
+        Command::Find { query, fuzzy } => {
+            if !fuzzy {
+                let hits = phone_book_db.search_full_text(&query, HIGHLIGHT_MARKS)?;
+                if !hits.is_empty() {
+                    show_search_hits(&hits);
+                    return Ok(());
+                }
+            }
+            // Without exact matches the query is probably a mistyped name.
+            // --fuzzy goes straight to names alike, for when the user knows it is.
+            let similar = phone_book_db.find_similar_names(&query)?;
+            if similar.is_empty() {
+                return Err(PhoneBookError::NotFound(query).into());
+            }
+            show_similar_contacts(&similar);
+        }
+        Command::Who {
+            number,
+            number_match,
+        } => {
+            let contacts = phone_book_db.find_contacts_by_number(&number, number_match)?;
+            if contacts.is_empty() {
+                return Err(PhoneBookError::NotFound(number).into());
+            }
+            show_phone_book(&contacts);
+        }
+        Command::Agi {
+            listen: None,
+            number_match,
+        } => {
+            agi::serve_session(
+                io::stdin().lock(),
+                io::stdout().lock(),
+                phone_book_db,
+                number_match,
+            )?;
+        }
+        Command::Agi {
+            listen: Some(address),
+            number_match,
+        } => agi::listen(&address, phone_book_db, number_match, |line| {
+            eprintln!("{line}")
+        })?,
+        Command::Import {
+            file_path,
+            format,
+            on_conflict,
+        } => {
+            let contacts = match format {
+                Format::Sqlite => PhoneBookDB::open_copy(&file_path)?
+                    .read_all_contacts()?
+                    .into_iter()
+                    .map(|contact| {
+                        (
+                            format!("contact {}", contact.id),
+                            contact.name,
+                            contact.entry,
+                        )
+                    })
+                    .collect(),
+                Format::Csv(options) => {
+                    let default_country = phone_book_db.default_country();
+                    if file_path.as_os_str() == "-" {
+                        csv_io::read_csv(io::stdin().lock(), &options, default_country)?
+                    } else {
+                        csv_io::read_csv(File::open(&file_path)?, &options, default_country)?
+                    }
+                }
+                Format::VCard(_) => {
+                    vcard::read_vcards(&read_text(&file_path)?, phone_book_db.default_country())?
+                }
+                Format::Json => {
+                    json_io::read_json(&read_text(&file_path)?, phone_book_db.default_country())?
+                }
+                Format::JsonLines => json_io::read_json_lines(
+                    &read_text(&file_path)?,
+                    phone_book_db.default_country(),
+                )?,
+                Format::DeskPhone(_) => unreachable!("rejected when parsing the arguments"),
+            };
+            let summary = phone_book_db.import_contacts(contacts, on_conflict)?;
+            println!(
+                "Imported {} new contacts, skipped {}, overwrote {} and merged {}",
+                summary.created, summary.skipped, summary.overwritten, summary.merged
+            );
+        }
+        Command::Export { file_path, format } => {
+            match format {
+                Format::Sqlite => phone_book_db.export_copy(&file_path)?,
+                Format::Csv(options) => {
+                    let contacts = phone_book_db.read_all_contacts()?;
+                    if file_path.as_os_str() == "-" {
+                        // Nothing else is printed, so the output can be piped on.
+                        return Ok(csv_io::write_csv(&contacts, io::stdout().lock(), &options)?);
+                    }
+                    csv_io::write_csv(&contacts, File::create(&file_path)?, &options)?;
+                }
+                Format::VCard(version) => {
+                    let contacts = phone_book_db.read_all_contacts()?;
+                    if file_path.as_os_str() == "-" {
+                        return Ok(vcard::write_vcards(
+                            &contacts,
+                            io::stdout().lock(),
+                            version,
+                        )?);
+                    }
+                    vcard::write_vcards(&contacts, File::create(&file_path)?, version)?;
+                }
+                Format::Json => {
+                    let contacts = phone_book_db.read_all_contacts()?;
+                    if file_path.as_os_str() == "-" {
+                        return Ok(json_io::write_json(&contacts, io::stdout().lock())?);
+                    }
+                    json_io::write_json(&contacts, File::create(&file_path)?)?;
+                }
+                Format::JsonLines => {
+                    let contacts = phone_book_db.read_all_contacts()?;
+                    if file_path.as_os_str() == "-" {
+                        return Ok(json_io::write_json_lines(&contacts, io::stdout().lock())?);
+                    }
+                    json_io::write_json_lines(&contacts, File::create(&file_path)?)?;
+                }
+                Format::DeskPhone(desk_phone) => {
+                    let contacts = phone_book_db.read_all_contacts()?;
+                    let left_out = if file_path.as_os_str() == "-" {
+                        desk_phone::write_directory(&contacts, io::stdout().lock(), desk_phone)?
+                    } else {
+                        desk_phone::write_directory(
+                            &contacts,
+                            File::create(&file_path)?,
+                            desk_phone,
+                        )?
+                    };
+                    if left_out > 0 {
+                        eprintln!("{left_out} numbers did not fit the directory and were left out");
+                    }
+                    if file_path.as_os_str() == "-" {
+                        return Ok(());
+                    }
+                }
+            }
+            println!("Exported to {}", file_path.display());
+        }
+        Command::Where => match phone_book_db.file_path() {
+            Some(file_path) => println!("{}", file_path.display()),
+            None => println!("The phone book is only kept in memory."),
+        },
+        Command::History(name) => {
+            let Some(records) = phone_book_db.read_audit_log_by_name(&name)? else {
+                return Err(PhoneBookError::Unsupported(
+                    "the audit log is only kept in databases, not with --vdir".to_owned(),
+                )
+                .into());
+            };
+            if records.is_empty() {
+                return Err(PhoneBookError::NotFound(name).into());
+            }
+            show_audit_log(&records);
+        }
+        Command::Log(target) => {
+            let history = history.ok_or_else(no_history)?;
+            let contact = match target {
+                Some(target) => Some(find_contact(phone_book_db, target)?),
+                None => None,
+            };
+            for change in history.log(contact.as_ref())? {
+                println!(
+                    "{}  {}  {}  {}",
+                    change.commit, change.date, change.author, change.message
+                );
+            }
+        }
+        Command::Revert(commit) => {
+            let history = history.ok_or_else(no_history)?;
+            let change = history.revert(phone_book_db, &commit)?;
+            println!("Reverted {} \"{}\"", change.commit, change.message);
+        }
+    }
+    Ok(())
+}
+
+fn no_history() -> PhoneBookError {
+    PhoneBookError::Config(format!(
+        "no history is kept, use --history <DIR> or set {HISTORY_VARIABLE}"
+    ))
+}
+
+/// Reads a whole file, or standard input for "-".
+fn read_text(file_path: &Path) -> io::Result<String> {
+    if file_path.as_os_str() == "-" {
+        io::read_to_string(io::stdin().lock())
+    } else {
+        fs::read_to_string(file_path)
+    }
+}
+
+/// Finds exactly one contact, failing when a name is shared by several contacts.
+fn find_contact(
+    phone_book_db: &dyn PhoneBookStore,
+    target: Target,
+) -> Result<Contact, Box<dyn Error>> {
+    match target {
+        Target::Id(id) => phone_book_db
+            .read_contact(id)?
+            .ok_or_else(|| PhoneBookError::NotFound(format!("ID {id}")).into()),
+        Target::Name(name) => {
+            let mut contacts = phone_book_db.read_contacts_by_name(&name)?;
+            match contacts.len() {
+                0 => Err(PhoneBookError::NotFound(name).into()),
+                1 => Ok(contacts.remove(0)),
+                _ => {
+                    let ids = contacts
+                        .iter()
+                        .map(|contact| contact.id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    Err(format!(
+                        "{name:?} is the name of several contacts, use --id with one of {ids}"
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, parse, Command, Details, Format, Invocation, Target};
+    use phone_book_with_data::{
+        csv_io::CsvOptions, desk_phone::DeskPhone, vcard::VCardVersion, AuditOperation, ContactId,
+        NameMatch, NumberLabel, NumberMatch, OnConflict, PhoneBookDB, PhoneEntry, PhoneNumber,
+    };
+
+    fn parse_args(args: &[&str]) -> Result<Option<Command>, super::UsageError> {
+        parse(args.iter().map(|arg| arg.to_string())).map(|invocation| invocation.command)
+    }
+
+    #[test]
+    fn no_arguments_means_interactive() {
+        assert_eq!(parse_args(&[]), Ok(None));
+        assert_eq!(parse_args(&["list", "--help"]), Ok(Some(Command::Help)));
+    }
+
+    #[test]
+    fn database_is_given_before_the_command() {
+        assert_eq!(
+            parse(["--db".to_owned(), "a.sqlite".to_owned()]),
+            Ok(Invocation {
+                database: Some("a.sqlite".into()),
+                vdir: None,
+                history: None,
+                command: None,
+            })
+        );
+        assert_eq!(
+            parse(["--db=a.sqlite".to_owned(), "where".to_owned()]),
+            Ok(Invocation {
+                database: Some("a.sqlite".into()),
+                vdir: None,
+                history: None,
+                command: Some(Command::Where),
+            })
+        );
+        assert!(parse_args(&["list", "--db", "a.sqlite"]).is_err());
+        assert!(parse_args(&["--db"]).is_err());
+        assert_eq!(
+            parse(["--vdir=contacts".to_owned(), "list".to_owned()]),
+            Ok(Invocation {
+                database: None,
+                vdir: Some("contacts".into()),
+                history: None,
+                command: Some(Command::List),
+            })
+        );
+        assert!(parse_args(&["--db", "a.sqlite", "--vdir", "contacts"]).is_err());
+    }
+
+    #[test]
+    fn reads_the_history() {
+        assert_eq!(
+            parse(["--history=history".to_owned(), "log".to_owned()]),
+            Ok(Invocation {
+                database: None,
+                vdir: None,
+                history: Some("history".into()),
+                command: Some(Command::Log(None)),
+            })
+        );
+        assert_eq!(
+            parse_args(&["log", "--id", "7"]),
+            Ok(Some(Command::Log(Some(Target::Id(ContactId(7))))))
+        );
+        assert_eq!(
+            parse_args(&["revert", "1a2b3c4"]),
+            Ok(Some(Command::Revert("1a2b3c4".to_owned())))
+        );
+        assert!(parse_args(&["revert"]).is_err());
+        assert_eq!(
+            parse_args(&["history", "Arnold Baker"]),
+            Ok(Some(Command::History("Arnold Baker".to_owned())))
+        );
+        assert!(parse_args(&["history"]).is_err());
+    }
+
+    #[test]
+    fn reads_numbers_and_the_preferred_one() {
+        let Ok(Some(Command::Add { name, details })) = parse_args(&[
+            "add",
+            "Arnold",
+            "--mobile",
+            "+44 7700 900123",
+            "--phone=boat=+44 20 7946 0958",
+            "--preferred=+44 20 7946 0958",
+        ]) else {
+            panic!("add was not understood");
+        };
+
+        assert_eq!(name, "Arnold");
+        let mobile = PhoneNumber::new(NumberLabel::Mobile, "+44 7700 900123");
+        let mut boat = PhoneNumber::new(NumberLabel::Custom("boat".into()), "+44 20 7946 0958");
+        boat.preferred = true;
+        assert_eq!(details.numbers, Some(vec![mobile, boat]));
+        assert_eq!(details.emails, None);
+    }
+
+    #[test]
+    fn targets_by_name_or_id() {
+        assert_eq!(
+            parse_args(&["get", "--id", "7"]),
+            Ok(Some(Command::Get(Target::Id(ContactId(7)))))
+        );
+        assert_eq!(
+            parse_args(&["remove", "--id=7"]),
+            Ok(Some(Command::Remove(Target::Id(ContactId(7)))))
+        );
+        assert_eq!(
+            parse_args(&["modify", "Arnold", "--clear", "emails"]),
+            Ok(Some(Command::Modify {
+                target: Target::Name("Arnold".into()),
+                details: Details {
+                    emails: Some(Vec::new()),
+                    ..Details::default()
+                },
+                new_name: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn modify_changes_nothing_when_the_new_name_is_taken() {
+        let mut phone_book = PhoneBookDB::new(None).unwrap();
+        let id = phone_book
+            .insert_contact("Arnold".to_owned(), PhoneEntry::default())
+            .unwrap();
+        phone_book
+            .insert_contact("Bruno".to_owned(), PhoneEntry::default())
+            .unwrap();
+
+        let command = parse_args(&["modify", "Arnold", "--mobile", "903795", "--name", "Bruno"])
+            .unwrap()
+            .unwrap();
+        assert!(execute(command, &mut phone_book, None).is_err());
+        let arnold = phone_book.read_contact(id).unwrap().unwrap();
+        assert_eq!(arnold.name, "Arnold");
+        assert!(arnold.entry.numbers.is_empty());
+
+        // Renaming alone leaves the details, and their audit log, alone.
+        let command = parse_args(&["modify", "Arnold", "--name", "Arnie"])
+            .unwrap()
+            .unwrap();
+        execute(command, &mut phone_book, None).unwrap();
+        let operations = phone_book
+            .read_audit_log_by_name("Arnie")
+            .unwrap()
+            .into_iter()
+            .map(|record| record.operation)
+            .collect::<Vec<_>>();
+        assert_eq!(operations, [AuditOperation::Insert, AuditOperation::Rename]);
+    }
+
+    #[test]
+    fn searches_anywhere_or_at_the_start() {
+        assert_eq!(
+            parse_args(&["search", "arn"]),
+            Ok(Some(Command::Search {
+                text: "arn".to_owned(),
+                name_match: NameMatch::Substring,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["search", "arn", "--prefix"]),
+            Ok(Some(Command::Search {
+                text: "arn".to_owned(),
+                name_match: NameMatch::Prefix,
+            }))
+        );
+    }
+
+    #[test]
+    fn finds_with_a_query() {
+        assert_eq!(
+            parse_args(&["find", "\"Baker Street\" lon*"]),
+            Ok(Some(Command::Find {
+                query: "\"Baker Street\" lon*".to_owned(),
+                fuzzy: false,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["find", "Arnlod", "--fuzzy"]),
+            Ok(Some(Command::Find {
+                query: "Arnlod".to_owned(),
+                fuzzy: true,
+            }))
+        );
+        assert!(parse_args(&["find"]).is_err());
+    }
+
+    #[test]
+    fn looks_up_numbers() {
+        assert_eq!(
+            parse_args(&["who", "020 7946 0958"]),
+            Ok(Some(Command::Who {
+                number: "020 7946 0958".to_owned(),
+                number_match: NumberMatch::Whole,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["who", "7946 0958", "--last=8"]),
+            Ok(Some(Command::Who {
+                number: "7946 0958".to_owned(),
+                number_match: NumberMatch::LastDigits(8),
+            }))
+        );
+        assert!(parse_args(&["who", "0958", "--last", "0"]).is_err());
+    }
+
+    #[test]
+    fn serves_agi() {
+        assert_eq!(
+            parse_args(&["agi"]),
+            Ok(Some(Command::Agi {
+                listen: None,
+                number_match: NumberMatch::Whole,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["agi", "--listen", "4573", "--last=9"]),
+            Ok(Some(Command::Agi {
+                listen: Some("127.0.0.1:4573".to_owned()),
+                number_match: NumberMatch::LastDigits(9),
+            }))
+        );
+        assert_eq!(
+            parse_args(&["agi", "--listen=0.0.0.0:4573"]),
+            Ok(Some(Command::Agi {
+                listen: Some("0.0.0.0:4573".to_owned()),
+                number_match: NumberMatch::Whole,
+            }))
+        );
+    }
+
+    #[test]
+    fn formats_follow_the_file_name() {
+        assert_eq!(
+            parse_args(&["export", "contacts.sqlite"]),
+            Ok(Some(Command::Export {
+                file_path: "contacts.sqlite".into(),
+                format: Format::Sqlite,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["import", "contacts.CSV", "--on-conflict=merge"]),
+            Ok(Some(Command::Import {
+                file_path: "contacts.CSV".into(),
+                format: Format::Csv(CsvOptions::default()),
+                on_conflict: OnConflict::Merge,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["export", "-", "--delimiter", "tab", "--quote", "'"]),
+            Ok(Some(Command::Export {
+                file_path: "-".into(),
+                format: Format::Csv(CsvOptions {
+                    delimiter: b'\t',
+                    quote: b'\'',
+                }),
+            }))
+        );
+    }
+
+    #[test]
+    fn vcards_are_recognized() {
+        assert_eq!(
+            parse_args(&["export", "contacts.vcf", "--vcard-version", "4.0"]),
+            Ok(Some(Command::Export {
+                file_path: "contacts.vcf".into(),
+                format: Format::VCard(VCardVersion::V4),
+            }))
+        );
+        assert_eq!(
+            parse_args(&["import", "-", "--format", "vcard"]),
+            Ok(Some(Command::Import {
+                file_path: "-".into(),
+                format: Format::VCard(VCardVersion::V3),
+                on_conflict: OnConflict::Skip,
+            }))
+        );
+    }
+
+    #[test]
+    fn json_is_recognized() {
+        assert_eq!(
+            parse_args(&["export", "dump.json"]),
+            Ok(Some(Command::Export {
+                file_path: "dump.json".into(),
+                format: Format::Json,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["export", "-", "--format=jsonl"]),
+            Ok(Some(Command::Export {
+                file_path: "-".into(),
+                format: Format::JsonLines,
+            }))
+        );
+        assert_eq!(
+            parse_args(&["import", "stream.ndjson"]),
+            Ok(Some(Command::Import {
+                file_path: "stream.ndjson".into(),
+                format: Format::JsonLines,
+                on_conflict: OnConflict::Skip,
+            }))
+        );
+    }
+
+    #[test]
+    fn desk_phone_directories_are_exported() {
+        assert_eq!(
+            parse_args(&["export", "directory.xml", "--format", "grandstream"]),
+            Ok(Some(Command::Export {
+                file_path: "directory.xml".into(),
+                format: Format::DeskPhone(DeskPhone::Grandstream),
+            }))
+        );
+        assert_eq!(
+            parse_args(&["export", "-", "--format=cisco"]),
+            Ok(Some(Command::Export {
+                file_path: "-".into(),
+                format: Format::DeskPhone(DeskPhone::Cisco),
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_usage() {
+        for args in [
+            &["frobnicate"][..],
+            &["get"],
+            &["get", "--id", "seven"],
+            &["add", "Arnold", "--name", "Arnie"],
+            &["add", "Arnold", "--mobile"],
+            &["add", "Arnold", "--mobile", "1", "--preferred", "2"],
+            &["modify", "Arnold"],
+            &["list", "extra"],
+            &["export", "a.sqlite", "--delimiter", ";"],
+            &["export", "a.csv", "--on-conflict", "skip"],
+            &["import", "a.csv", "--on-conflict", "ask"],
+            &["import", "a.csv", "--delimiter", "ab"],
+            &["import", "a", "--format", "xml"],
+            &["import", "-", "--format", "sqlite"],
+            &["import", "a.vcf", "--vcard-version", "4"],
+            &["export", "a.csv", "--vcard-version", "4"],
+            &["export", "a.vcf", "--delimiter", ";"],
+            &["export", "a.json", "--quote", "'"],
+            &["import", "phones.xml", "--format", "cisco"],
+            &[
+                "export",
+                "phones.xml",
+                "--format",
+                "yealink",
+                "--delimiter",
+                ";",
+            ],
+        ] {
+            assert!(parse_args(args).is_err(), "{args:?}");
+        }
+    }
+}
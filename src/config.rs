@@ -0,0 +1,322 @@
+//! Works out which database file to open.
+//!
+//! The first of these that is set wins: the `--db` flag, the `PHONEBOOK_DB` environment
+//! variable, the `database` setting in `$XDG_CONFIG_HOME/phonebook/config`, and finally
+//! `$XDG_DATA_HOME/phonebook/phonebook.sqlite`. Older versions always used `file.sqlite` in
+//! the current directory, so as long as there is nothing at the default location and that
+//! file exists, it is used instead.
+//!
+//! The config file holds one `key = value` setting per line. Empty lines and lines
+//! starting with `#` are ignored. A relative `database` path is relative to the config file.
+//!
+//! Changes are recorded in a git history only when asked for, with the `--history` flag or
+//! the `PHONEBOOK_HISTORY` environment variable.
+
+use crate::error::PhoneBookError;
+use std::{
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const DATABASE_VARIABLE: &str = "PHONEBOOK_DB";
+pub const HISTORY_VARIABLE: &str = "PHONEBOOK_HISTORY";
+const FILE_NAME: &str = "phonebook.sqlite";
+/// The database file older versions used, in the current directory.
+const LEGACY_FILE_NAME: &str = "file.sqlite";
+
+/// What decided on the database file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Source {
+    Flag,
+    Environment,
+    ConfigFile(PathBuf),
+    Default,
+    /// The file of older versions was found, and the default location it should be moved to.
+    Legacy(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag => f.write_str("the --db flag"),
+            Source::Environment => write!(f, "the {DATABASE_VARIABLE} environment variable"),
+            Source::ConfigFile(path) => write!(f, "the config file {}", path.display()),
+            Source::Default => f.write_str("the default location"),
+            Source::Legacy(_) => f.write_str("the fallback for older versions"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub source: Source,
+}
+
+impl Location {
+    /// Creates the directory of the default location, which usually does not exist yet.
+    /// Directories of paths the user chose are left alone, so that typos are noticed.
+    pub fn create_default_dir(&self) -> io::Result<()> {
+        match (&self.source, self.path.parent()) {
+            (Source::Default, Some(dir)) => fs::create_dir_all(dir),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Finds the database file, with `flag` being the value of `--db` if it was given.
+pub fn database_location(flag: Option<PathBuf>) -> Result<Location, PhoneBookError> {
+    resolve(
+        flag,
+        |name| std::env::var_os(name),
+        Path::new(LEGACY_FILE_NAME),
+    )
+}
+
+/// Finds the git repository to record changes in, with `flag` being the value of `--history`.
+pub fn history_location(flag: Option<PathBuf>) -> Option<PathBuf> {
+    flag.or_else(|| {
+        std::env::var_os(HISTORY_VARIABLE)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+fn resolve(
+    flag: Option<PathBuf>,
+    var: impl Fn(&str) -> Option<OsString>,
+    legacy_file: &Path,
+) -> Result<Location, PhoneBookError> {
+    if let Some(path) = flag {
+        return Ok(Location {
+            path,
+            source: Source::Flag,
+        });
+    }
+
+    let var = |name| var(name).filter(|value| !value.is_empty());
+    if let Some(path) = var(DATABASE_VARIABLE) {
+        return Ok(Location {
+            path: path.into(),
+            source: Source::Environment,
+        });
+    }
+
+    let home = var("HOME").map(PathBuf::from);
+    // The XDG base directory spec says relative paths in these variables are to be ignored.
+    let base_dir = |name, fallback| {
+        var(name)
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| Some(home.as_ref()?.join(fallback)))
+    };
+
+    if let Some(config_file) = base_dir("XDG_CONFIG_HOME", ".config") {
+        let config_file = config_file.join("phonebook").join("config");
+        if let Some(path) = read_config(&config_file, home.as_deref())? {
+            return Ok(Location {
+                path,
+                source: Source::ConfigFile(config_file),
+            });
+        }
+    }
+
+    match base_dir("XDG_DATA_HOME", ".local/share") {
+        Some(data_dir) => {
+            let path = data_dir.join("phonebook").join(FILE_NAME);
+            if !path.exists() && legacy_file.is_file() {
+                return Ok(Location {
+                    path: legacy_file.to_owned(),
+                    source: Source::Legacy(path),
+                });
+            }
+            Ok(Location {
+                path,
+                source: Source::Default,
+            })
+        }
+        None => Err(PhoneBookError::Config(format!(
+            "HOME is not set, so there is no default location; use --db or {DATABASE_VARIABLE}"
+        ))),
+    }
+}
+
+/// Returns the `database` setting of the config file, or None if the file or the setting is missing.
+fn read_config(config_file: &Path, home: Option<&Path>) -> Result<Option<PathBuf>, PhoneBookError> {
+    let contents = match fs::read_to_string(config_file) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut database = None;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message| {
+            PhoneBookError::Config(format!(
+                "{} line {}: {message}",
+                config_file.display(),
+                number + 1
+            ))
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid("expected key = value"));
+        };
+        match key.trim() {
+            "database" => database = Some(value.trim()),
+            key => return Err(invalid(&format!("unknown setting {key:?}"))),
+        }
+    }
+
+    Ok(database.map(|database| {
+        match (database.strip_prefix("~/"), home) {
+            (Some(rest), Some(home)) => home.join(rest),
+            // `join` keeps absolute paths as they are.
+            _ => config_file.parent().unwrap_or(Path::new("")).join(database),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Location, Source};
+    use crate::error::PhoneBookError;
+    use std::{
+        ffi::OsString,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    /// Most tests are about machines where older versions were never used.
+    fn no_legacy_file() -> &'static Path {
+        Path::new("")
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.into())
+        }
+    }
+
+    #[test]
+    fn flag_beats_environment_beats_default() {
+        let vars = [
+            ("HOME", "/home/arnold"),
+            ("PHONEBOOK_DB", "/tmp/env.sqlite"),
+        ];
+        assert_eq!(
+            resolve(Some("flag.sqlite".into()), env(&vars), no_legacy_file()).unwrap(),
+            Location {
+                path: "flag.sqlite".into(),
+                source: Source::Flag
+            }
+        );
+        assert_eq!(
+            resolve(None, env(&vars), no_legacy_file()).unwrap(),
+            Location {
+                path: "/tmp/env.sqlite".into(),
+                source: Source::Environment
+            }
+        );
+        assert_eq!(
+            resolve(None, env(&vars[..1]), no_legacy_file()).unwrap(),
+            Location {
+                path: "/home/arnold/.local/share/phonebook/phonebook.sqlite".into(),
+                source: Source::Default
+            }
+        );
+        assert_eq!(
+            resolve(
+                None,
+                env(&[("XDG_DATA_HOME", "/data"), ("HOME", "/h")]),
+                no_legacy_file()
+            )
+            .unwrap()
+            .path,
+            PathBuf::from("/data/phonebook/phonebook.sqlite")
+        );
+        assert!(matches!(
+            resolve(None, env(&[]), no_legacy_file()),
+            Err(PhoneBookError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn reads_the_config_file() {
+        let config_home =
+            std::env::temp_dir().join(format!("phone_book_config_{}", uuid::Uuid::new_v4()));
+        let config_file = config_home.join("phonebook").join("config");
+        fs::create_dir_all(config_file.parent().unwrap()).unwrap();
+        let config_home_str = config_home.to_str().unwrap();
+        let vars = [
+            ("HOME", "/home/arnold"),
+            ("XDG_CONFIG_HOME", config_home_str),
+        ];
+
+        fs::write(
+            &config_file,
+            "# where the contacts live\ndatabase = contacts.sqlite\n",
+        )
+        .unwrap();
+        let relative = resolve(None, env(&vars), no_legacy_file());
+        fs::write(&config_file, "database=~/phone.sqlite").unwrap();
+        let from_home = resolve(None, env(&vars), no_legacy_file());
+        fs::write(&config_file, "databse = /tmp/phone.sqlite").unwrap();
+        let typo = resolve(None, env(&vars), no_legacy_file());
+        fs::remove_dir_all(&config_home).unwrap();
+
+        assert_eq!(
+            relative.unwrap(),
+            Location {
+                path: config_home.join("phonebook").join("contacts.sqlite"),
+                source: Source::ConfigFile(config_file),
+            }
+        );
+        assert_eq!(
+            from_home.unwrap().path,
+            PathBuf::from("/home/arnold/phone.sqlite")
+        );
+        assert!(matches!(typo, Err(PhoneBookError::Config(_))));
+    }
+
+    #[test]
+    fn falls_back_to_the_file_of_older_versions() {
+        let dir = std::env::temp_dir().join(format!("phone_book_legacy_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let legacy_file = dir.join("file.sqlite");
+        let data_home = dir.join("data");
+        let data_home_str = data_home.to_str().unwrap();
+        let vars = [("HOME", "/home/arnold"), ("XDG_DATA_HOME", data_home_str)];
+        let default = data_home.join("phonebook").join("phonebook.sqlite");
+
+        let without = resolve(None, env(&vars), &legacy_file);
+        fs::write(&legacy_file, "").unwrap();
+        let with = resolve(None, env(&vars), &legacy_file);
+        fs::create_dir_all(default.parent().unwrap()).unwrap();
+        fs::write(&default, "").unwrap();
+        let moved = resolve(None, env(&vars), &legacy_file);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(without.unwrap().source, Source::Default);
+        assert_eq!(
+            with.unwrap(),
+            Location {
+                path: legacy_file,
+                source: Source::Legacy(default.clone()),
+            }
+        );
+        assert_eq!(
+            moved.unwrap(),
+            Location {
+                path: default,
+                source: Source::Default,
+            }
+        );
+    }
+}
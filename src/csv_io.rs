@@ -0,0 +1,313 @@
+//! Reading and writing contacts as CSV, one contact per row.
+//!
+//! The header row names the columns `name`, `numbers`, `preferred`, `emails` and `addresses`.
+//! Only `name` is needed when importing, and the columns may come in any order.
+//! A cell holds one number or address per line, written as `LABEL=VALUE`, where a missing
+//! label means "other". Postal addresses are written as `LABEL=STREET;CITY;REGION;POSTCODE;COUNTRY`.
+//! `preferred` repeats the preferred number, if there is one.
+
+use crate::{
+    entry::{Contact, EmailAddress, PhoneEntry, PhoneNumber, PostalAddress},
+    error::PhoneBookError,
+    normalize::Country,
+    validate,
+};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+const COLUMNS: [&str; 5] = ["name", "numbers", "preferred", "emails", "addresses"];
+
+/// The characters that separate and quote cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub quote: u8,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            quote: b'"',
+        }
+    }
+}
+
+/// Writes a header row and one row for every contact.
+pub fn write_csv(
+    contacts: &[Contact],
+    writer: impl Write,
+    options: &CsvOptions,
+) -> Result<(), PhoneBookError> {
+    let mut writer = WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .from_writer(writer);
+
+    writer.write_record(COLUMNS)?;
+    for contact in contacts {
+        let entry = &contact.entry;
+        let preferred = entry.numbers.iter().find(|number| number.preferred);
+        writer.write_record([
+            contact.name.as_str(),
+            &lines(
+                entry
+                    .numbers
+                    .iter()
+                    .map(|number| format!("{}={}", number.label, number.number)),
+            ),
+            preferred.map_or("", |number| &number.number),
+            &lines(
+                entry
+                    .emails
+                    .iter()
+                    .map(|email| format!("{}={}", email.label, email.address)),
+            ),
+            &lines(
+                entry
+                    .addresses
+                    .iter()
+                    .map(|address| format!("{}={}", address.label, address.parts())),
+            ),
+        ])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn lines(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join("\n")
+}
+
+/// Reads the contacts of every row, each together with the line it starts on.
+/// All rows are checked before anything is returned, so every mistake is reported at once.
+/// Numbers are validated with `default_country`, just like the database will.
+pub fn read_csv(
+    reader: impl Read,
+    options: &CsvOptions,
+    default_country: Option<&Country>,
+) -> Result<Vec<(String, String, PhoneEntry)>, PhoneBookError> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .from_reader(reader);
+
+    let mut columns = [None; COLUMNS.len()];
+    for (position, header) in reader.headers()?.iter().enumerate() {
+        let header = header.trim();
+        match COLUMNS
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(header))
+        {
+            Some(column) => columns[column] = Some(position),
+            None => {
+                return Err(PhoneBookError::Import(format!(
+                    "unknown column {header:?}, the columns are {}",
+                    COLUMNS.join(", ")
+                )))
+            }
+        }
+    }
+    if columns[0].is_none() {
+        return Err(PhoneBookError::Import(
+            "the header row has no name column".to_owned(),
+        ));
+    }
+
+    let mut contacts = Vec::new();
+    let mut errors = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        match read_row(&record, &columns, default_country) {
+            Ok((name, entry)) => contacts.push((format!("line {line}"), name, entry)),
+            Err(message) => errors.push(format!("line {line}: {message}")),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(PhoneBookError::Import(errors.join("\n")));
+    }
+    Ok(contacts)
+}
+
+fn read_row(
+    record: &StringRecord,
+    columns: &[Option<usize>; COLUMNS.len()],
+    default_country: Option<&Country>,
+) -> Result<(String, PhoneEntry), String> {
+    let [name, numbers, preferred, emails, addresses] =
+        columns.map(|column| column.and_then(|column| record.get(column)).unwrap_or(""));
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("the name is empty".to_owned());
+    }
+
+    let mut entry = PhoneEntry::default();
+    for item in items(numbers) {
+        let (label, number) = split_label(item);
+        entry.numbers.push(PhoneNumber::new(label, number));
+    }
+    validate::validate_entry(&entry, default_country).map_err(|err| err.to_string())?;
+
+    let preferred = preferred.trim();
+    if !preferred.is_empty() {
+        match entry
+            .numbers
+            .iter_mut()
+            .find(|number| number.number == preferred)
+        {
+            Some(number) => number.preferred = true,
+            None => {
+                return Err(format!(
+                    "the preferred number {preferred:?} is not one of the numbers"
+                ))
+            }
+        }
+    }
+
+    for item in items(emails) {
+        let (label, address) = split_label(item);
+        entry.emails.push(EmailAddress {
+            label,
+            address: address.to_owned(),
+        });
+    }
+
+    for item in items(addresses) {
+        let (label, parts) = split_label(item);
+        match PostalAddress::from_parts(label, parts) {
+            Some(address) => entry.addresses.push(address),
+            None => return Err(format!("the address {parts:?} has more than five parts")),
+        }
+    }
+
+    Ok((name.to_owned(), entry))
+}
+
+/// The non-empty lines of a cell.
+fn items(cell: &str) -> impl Iterator<Item = &str> {
+    cell.lines().map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// Splits "work=0201234567" into its label and value. Without a label, "other" is used.
+fn split_label<Label: FromStr<Err = Infallible>>(item: &str) -> (Label, &str) {
+    let (label, value) = item.split_once('=').unwrap_or(("other", item));
+    let Ok(label) = label.parse();
+    (label, value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_csv, write_csv, CsvOptions};
+    use crate::{
+        entry::{
+            AddressLabel, Contact, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+            PostalAddress,
+        },
+        error::PhoneBookError,
+        normalize::find_country,
+    };
+    use uuid::Uuid;
+
+    fn arnold() -> Contact {
+        let mut work = PhoneNumber::new(NumberLabel::Work, "+44 20 7946 0958");
+        work.preferred = true;
+        Contact {
+            id: ContactId(1),
+            uuid: Uuid::new_v4(),
+            name: "Arnold, Jr.".to_owned(),
+            entry: PhoneEntry {
+                numbers: vec![
+                    PhoneNumber::new(NumberLabel::Mobile, "07700 900123"),
+                    work,
+                    PhoneNumber::new(NumberLabel::Custom("boat".to_owned()), "+1 212 555 0100"),
+                ],
+                emails: vec![EmailAddress {
+                    label: AddressLabel::Work,
+                    address: "arnold@example.com".to_owned(),
+                }],
+                addresses: vec![PostalAddress {
+                    label: AddressLabel::Home,
+                    street: "1 \"The\" Road".to_owned(),
+                    city: "London".to_owned(),
+                    region: String::new(),
+                    postcode: "N1 1AA".to_owned(),
+                    country: "UK".to_owned(),
+                }],
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for options in [
+            CsvOptions::default(),
+            CsvOptions {
+                delimiter: b';',
+                quote: b'\'',
+            },
+        ] {
+            let mut csv = Vec::new();
+            write_csv(&[arnold()], &mut csv, &options).unwrap();
+
+            let contacts = read_csv(csv.as_slice(), &options, find_country("GB")).unwrap();
+
+            assert_eq!(
+                contacts,
+                vec![(
+                    "line 2".to_owned(),
+                    "Arnold, Jr.".to_owned(),
+                    arnold().entry
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn columns_may_be_missing_or_reordered() {
+        let csv = "Numbers,Name\n\"mobile=+44 7700 900123\n0201234567\",Jack\n";
+
+        let contacts = read_csv(csv.as_bytes(), &CsvOptions::default(), None).unwrap();
+
+        assert_eq!(contacts[0].1, "Jack");
+        assert_eq!(
+            contacts[0].2.numbers,
+            vec![
+                PhoneNumber::new(NumberLabel::Mobile, "+44 7700 900123"),
+                PhoneNumber::new(NumberLabel::Other, "0201234567"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_row() {
+        let csv = "name,numbers,preferred\n\
+                   Arnold,mobile=+44 7700 900123,\n\
+                   ,mobile=+44 7700 900123,\n\
+                   Jack,mobile=asdf,\n\
+                   Jill,mobile=+44 7700 900123,+44 20 7946 0958\n";
+
+        let err = read_csv(csv.as_bytes(), &CsvOptions::default(), None).unwrap_err();
+
+        let PhoneBookError::Import(message) = err else {
+            panic!("not an import error: {err}");
+        };
+        let lines = message.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("line 3: "));
+        assert!(lines[1].starts_with("line 4: invalid phone number"));
+        assert!(lines[2].starts_with("line 5: "));
+
+        assert!(matches!(
+            read_csv("name,phone\n".as_bytes(), &CsvOptions::default(), None),
+            Err(PhoneBookError::Import(_))
+        ));
+    }
+}
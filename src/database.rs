@@ -1,29 +1,201 @@
-use crate::entry::PhoneEntry;
-use rusqlite::{params, Connection, Result};
+use crate::{
+    entry::{
+        AddressLabel, Contact, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+        PostalAddress,
+    },
+    error::PhoneBookError,
+    fuzzy, migrations,
+    normalize::{self, Country},
+    search::{self, NameMatch},
+    validate,
+};
+use rusqlite::{
+    backup::Progress, types::Type, Connection, DatabaseName, OptionalExtension, Params, Row,
+    Transaction,
+};
 use std::{
     collections::BTreeMap,
+    fmt,
     path::{Path, PathBuf},
 };
+use uuid::Uuid;
+
+const SELECT_CONTACTS: &str = "SELECT id, uuid, name FROM phone_book";
+
+/// What `write_entry` did.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum WriteOutcome {
+    /// There was no contact with the name, so a new one was created.
+    Created(ContactId),
+    /// The existing contact was overwritten; `previous` holds what it contained before.
+    Replaced { id: ContactId, previous: PhoneEntry },
+}
+
+/// What an import does with a contact whose name is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Keep the existing contact and drop the imported one.
+    Skip,
+    /// Replace the details of the existing contact with the imported ones.
+    Overwrite,
+    /// Add the imported details the existing contact does not have yet.
+    Merge,
+}
+
+/// How much of a number has to be the same for a reverse lookup to find it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberMatch {
+    /// All digits.
+    Whole,
+    /// Only the last few digits, for numbers that might have been written with or
+    /// without the calling code or area code.
+    LastDigits(usize),
+}
+
+/// How many contacts an import created, and what happened to the ones whose name was taken.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub created: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+    pub merged: usize,
+}
+
+/// A contact found by a full-text search.
+#[derive(Debug, PartialEq)]
+pub struct SearchHit {
+    pub contact: Contact,
+    /// The name, with the matching words marked.
+    pub name: String,
+    /// Every number, email and address that matched, with the matching words marked.
+    pub details: Vec<String>,
+}
+
+/// A contact whose name is like the one searched for.
+#[derive(Debug, PartialEq)]
+pub struct SimilarContact {
+    pub contact: Contact,
+    /// From `fuzzy::MIN_SCORE` up to 1 for a name that only differs in case or accents.
+    pub score: f64,
+}
+
+/// One of several changes that `apply_changes` makes together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreChange {
+    Remove(ContactId),
+    /// Adds copies of contacts, keeping their UUIDs, like `copy_contacts`.
+    Copy(Vec<Contact>),
+    /// Changes the details, the name or both, like `edit_contact`.
+    Edit {
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    },
+}
+
+/// What a change in the audit log did to its contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    Insert,
+    Modify,
+    Rename,
+    Remove,
+}
+
+impl AuditOperation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOperation::Insert => "insert",
+            AuditOperation::Modify => "modify",
+            AuditOperation::Rename => "rename",
+            AuditOperation::Remove => "remove",
+        }
+    }
+
+    fn parse(operation: &str) -> Option<AuditOperation> {
+        [
+            AuditOperation::Insert,
+            AuditOperation::Modify,
+            AuditOperation::Rename,
+            AuditOperation::Remove,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str() == operation)
+    }
+}
+
+/// One change to a contact, as the audit log recorded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Counts up with every change, so later changes have higher sequence numbers.
+    pub sequence: i64,
+    /// When the change was made, in UTC, like "2024-05-01T09:30:00Z".
+    pub timestamp: String,
+    /// The operating system user who made the change.
+    pub os_user: String,
+    pub operation: AuditOperation,
+    pub contact_id: ContactId,
+    pub contact_uuid: Uuid,
+    /// The contact before the change, None when it was inserted.
+    pub old: Option<Contact>,
+    /// The contact after the change, None when it was removed.
+    pub new: Option<Contact>,
+}
 
 pub struct PhoneBookDB {
     database_file_path: Option<PathBuf>,
     conn: Connection,
+    /// Used to understand numbers written without a calling code.
+    default_country: Option<&'static Country>,
 }
 
 impl PhoneBookDB {
-    pub fn new(
-        file_path: Option<std::path::PathBuf>,
-    ) -> Result<PhoneBookDB, Box<dyn std::error::Error>> {
+    /// Opens the database at `file_path`, or an in-memory database if it is None,
+    /// and migrates it to the latest schema version.
+    pub fn new(file_path: Option<std::path::PathBuf>) -> Result<PhoneBookDB, PhoneBookError> {
+        let conn = match &file_path {
+            Some(file_path) => Connection::open(file_path)?,
+            None => Connection::open_in_memory()?,
+        };
+        PhoneBookDB::from_connection(conn, file_path)
+    }
+
+    /// Opens an in-memory copy of the phone book in `file_path`.
+    /// The file itself is never written to, not even to migrate it.
+    pub fn open_copy(file_path: &Path) -> Result<PhoneBookDB, PhoneBookError> {
+        // Opening a missing file would silently create an empty one.
+        std::fs::metadata(file_path)?;
+
+        let mut conn = Connection::open_in_memory()?;
+        conn.restore(DatabaseName::Main, file_path, None::<fn(Progress)>)?;
+        PhoneBookDB::from_connection(conn, None)
+    }
+
+    fn from_connection(
+        mut conn: Connection,
+        file_path: Option<PathBuf>,
+    ) -> Result<PhoneBookDB, PhoneBookError> {
+        migrations::migrate(&mut conn)?;
+        // Only enabled after migrating, so that rebuilding a parent table
+        // cannot cascade into its children.
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let default_country = conn
+            .query_row(
+                "SELECT value FROM setting WHERE key = 'default_country'",
+                (),
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .and_then(|code| normalize::find_country(&code));
+
         Ok(PhoneBookDB {
-            database_file_path: file_path.clone(),
-            conn: match file_path {
-                Some(file_path) => Connection::open(file_path)?,
-                None => Connection::open_in_memory()?,
-            },
+            database_file_path: file_path,
+            conn,
+            default_country,
         })
     }
 
-    #[allow(dead_code)]
     pub fn file_path(&self) -> Option<&Path> {
         match &self.database_file_path {
             Some(file_path) => Some(file_path),
@@ -31,66 +203,466 @@ impl PhoneBookDB {
         }
     }
 
-    fn create_table_if_not_exists(&self) -> Result<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS
-         phone_book (name TEXT NOT NULL, phone_number TEXT NOT NULL, work_number TEXT NOT NULL)",
-            (),
-        )?;
+    /// Writes a copy of the whole phone book to a new database file.
+    pub fn export_copy(&self, file_path: &Path) -> Result<(), PhoneBookError> {
+        self.conn
+            .execute("VACUUM INTO ?1", [file_path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Adds all contacts in a single transaction, so that either all of them are imported or none.
+    /// Every contact comes with where it was read from, like "line 3", to point out the one that failed.
+    /// A name counts as taken when the phone book or an earlier imported contact has it.
+    pub fn import_contacts<Origin: fmt::Display>(
+        &self,
+        contacts: Vec<(Origin, String, PhoneEntry)>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, PhoneBookError> {
+        self.in_transaction(|tx| {
+            let mut summary = ImportSummary::default();
+            for (origin, name, entry) in contacts {
+                import_contact(
+                    tx,
+                    &name,
+                    entry,
+                    on_conflict,
+                    self.default_country,
+                    &mut summary,
+                )
+                .map_err(|err| PhoneBookError::Import(format!("{origin}: {err}")))?;
+            }
+            Ok(summary)
+        })
+    }
+
+    pub fn default_country(&self) -> Option<&'static Country> {
+        self.default_country
+    }
+
+    /// Remembers the country used for numbers written without a calling code,
+    /// and normalizes every stored number again with it.
+    pub fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        let country = match code {
+            Some(code) => Some(
+                normalize::find_country(code)
+                    .ok_or_else(|| PhoneBookError::UnknownCountry(code.to_owned()))?,
+            ),
+            None => None,
+        };
+
+        self.in_transaction(|tx| {
+            match country {
+                Some(country) => tx.execute(
+                    "INSERT OR REPLACE INTO setting (key, value) VALUES ('default_country', ?1)",
+                    [country.code],
+                )?,
+                None => tx.execute("DELETE FROM setting WHERE key = 'default_country'", ())?,
+            };
+
+            let numbers = tx
+                .prepare("SELECT id, number FROM phone_number")?
+                .query_map((), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut update = tx
+                .prepare("UPDATE phone_number SET e164 = ?2, reversed_digits = ?3 WHERE id = ?1")?;
+            for (id, number) in numbers {
+                update.execute((
+                    id,
+                    normalize::to_e164(&number, country),
+                    reversed_digits(&number, country),
+                ))?;
+            }
+            Ok(())
+        })?;
 
+        self.default_country = country;
         Ok(())
     }
 
-    pub fn modify_entry(
+    /// Runs `f` in a transaction that is committed only if `f` succeeds.
+    fn in_transaction<T>(
+        &self,
+        f: impl FnOnce(&Transaction) -> Result<T, PhoneBookError>,
+    ) -> Result<T, PhoneBookError> {
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Adds a new contact, even if another contact already has the same name.
+    pub fn insert_contact(
         &self,
         name: String,
         entry: PhoneEntry,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.create_table_if_not_exists()?;
-        self.conn.execute(
-            "UPDATE phone_book SET phone_number = ?2, work_number = ?3 WHERE name = ?1",
-            [&name, &entry.mobile, &entry.work],
+    ) -> Result<ContactId, PhoneBookError> {
+        self.in_transaction(|tx| {
+            insert_contact(tx, Uuid::new_v4(), &name, &entry, self.default_country)
+        })
+    }
+
+    /// Adds copies of contacts from another phone book in one transaction, keeping their UUIDs
+    /// so that they are still recognized as the same contacts. Returns the IDs they were given.
+    pub fn copy_contacts(&self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        self.in_transaction(|tx| {
+            contacts
+                .iter()
+                .map(|contact| {
+                    insert_contact(
+                        tx,
+                        contact.uuid,
+                        &contact.name,
+                        &contact.entry,
+                        self.default_country,
+                    )
+                })
+                .collect()
+        })
+    }
+
+    pub fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        read_contact(&self.conn, id)
+    }
+
+    /// Returns every contact with exactly this name, oldest first.
+    pub fn read_contacts_by_name(&self, name: &str) -> Result<Vec<Contact>, PhoneBookError> {
+        self.query_contacts("WHERE name = ?1", [name])
+    }
+
+    /// Returns every contact whose name matches `text`, ignoring case and accents, oldest first.
+    /// Empty text matches every contact.
+    pub fn search_contacts(
+        &self,
+        text: &str,
+        name_match: NameMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        self.query_contacts(
+            "WHERE search_name LIKE ?1 ESCAPE '\\'",
+            [search::like_pattern(text, name_match)],
+        )
+    }
+
+    /// Searches the names, numbers, emails and addresses of all contacts, best matches first.
+    /// See `search::full_text_query` for what `query` may contain.
+    /// Matching words are put between the `marks`.
+    pub fn search_full_text(
+        &self,
+        query: &str,
+        marks: (&str, &str),
+    ) -> Result<Vec<SearchHit>, PhoneBookError> {
+        let Some(query) = search::full_text_query(query) else {
+            return Ok(Vec::new());
+        };
+        // Name matches count for more than matches in the other columns.
+        let mut stmt = self.conn.prepare(
+            "SELECT rowid, name, numbers, emails, addresses,
+                highlight(contact_search, 0, ?2, ?3), highlight(contact_search, 1, ?2, ?3),
+                highlight(contact_search, 2, ?2, ?3), highlight(contact_search, 3, ?2, ?3)
+            FROM contact_search WHERE contact_search MATCH ?1
+            ORDER BY bm25(contact_search, 10.0, 1.0, 1.0, 1.0), rowid",
         )?;
+        let rows = stmt
+            .query_map((query, marks.0, marks.1), |row| {
+                let mut columns = Vec::new();
+                for column in 1..9 {
+                    columns.push(row.get::<_, Option<String>>(column)?.unwrap_or_default());
+                }
+                Ok((ContactId(row.get(0)?), columns))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(())
+        let mut hits = Vec::new();
+        for (id, mut columns) in rows {
+            let highlighted = columns.split_off(4);
+            let details = columns[1..]
+                .iter()
+                .zip(&highlighted[1..])
+                .flat_map(|(plain, highlighted)| plain.lines().zip(highlighted.lines()))
+                .filter(|(plain, highlighted)| plain != highlighted)
+                .map(|(_, highlighted)| highlighted.to_owned())
+                .collect();
+            hits.push(SearchHit {
+                contact: existing_contact(&self.conn, id)?,
+                name: highlighted[0].clone(),
+                details,
+            });
+        }
+
+        Ok(hits)
     }
 
-    pub fn remove_entry(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        self.create_table_if_not_exists()?;
-        self.conn
-            .execute("DELETE FROM phone_book WHERE name = ?1", [name])?;
-        Ok(())
+    /// Returns the contacts whose name is like `name`, allowing for typos, the most alike first.
+    pub fn find_similar_names(&self, name: &str) -> Result<Vec<SimilarContact>, PhoneBookError> {
+        let candidates = self
+            .conn
+            .prepare(&format!("{SELECT_CONTACTS} ORDER BY id"))?
+            .query_map((), contact_from_row)?
+            .map(|contact| contact.map(|contact| (contact.id, contact.name)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut similar = Vec::new();
+        for (id, score) in fuzzy::rank(name, candidates) {
+            similar.push(SimilarContact {
+                contact: existing_contact(&self.conn, id)?,
+                score,
+            });
+        }
+        Ok(similar)
+    }
+
+    /// Returns every contact, oldest first.
+    pub fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        self.query_contacts("", [])
+    }
+
+    /// Returns every change made to the contact, oldest first, even after it was removed.
+    pub fn read_audit_log(&self, id: ContactId) -> Result<Vec<AuditRecord>, PhoneBookError> {
+        self.query_audit_log("WHERE contact_id = ?1", [id.0])
     }
-    pub fn read_all_entries(
+
+    /// Returns every change made to the contacts that had `name` before or after one of their
+    /// changes, so that contacts which were renamed or removed since are found too.
+    pub fn read_audit_log_by_name(&self, name: &str) -> Result<Vec<AuditRecord>, PhoneBookError> {
+        self.query_audit_log(
+            "WHERE contact_id IN (SELECT contact_id FROM audit_log
+                WHERE old_value ->> '$.name' = ?1 OR new_value ->> '$.name' = ?1)",
+            [name],
+        )
+    }
+
+    fn query_audit_log(
         &self,
-    ) -> Result<BTreeMap<String, PhoneEntry>, Box<dyn std::error::Error>> {
-        let data = self.read_all_entries_as_vec(None)?;
+        filter: &str,
+        params: impl Params,
+    ) -> Result<Vec<AuditRecord>, PhoneBookError> {
+        let contact = |row: &Row, column| -> rusqlite::Result<Option<Contact>> {
+            let Some(value) = row.get::<_, Option<String>>(column)? else {
+                return Ok(None);
+            };
+            serde_json::from_str(&value).map(Some).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(err))
+            })
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, timestamp, os_user, operation, contact_id, contact_uuid, old_value, new_value
+             FROM audit_log {filter} ORDER BY id"
+        ))?;
+        let records = stmt
+            .query_map(params, |row| {
+                let operation = row.get::<_, String>(3)?;
+                let uuid = row.get::<_, String>(5)?;
+                Ok(AuditRecord {
+                    sequence: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    os_user: row.get(2)?,
+                    operation: AuditOperation::parse(&operation).ok_or_else(|| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            3,
+                            Type::Text,
+                            format!("unknown operation {operation:?}").into(),
+                        )
+                    })?,
+                    contact_id: ContactId(row.get(4)?),
+                    contact_uuid: Uuid::parse_str(&uuid).map_err(|err| {
+                        rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(err))
+                    })?,
+                    old: contact(row, 6)?,
+                    new: contact(row, 7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    }
 
-        let phone_book = data.into_iter().collect();
-        Ok(phone_book)
+    /// Replaces the numbers of the contact and returns the ones it had before.
+    pub fn modify_contact(
+        &self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        self.in_transaction(|tx| modify_contact(tx, id, &entry, self.default_country))
     }
 
-    pub fn write_entry(
+    /// Changes the details, the name or both in one transaction, so that either all of it
+    /// happens or nothing does. What is None is left as it is.
+    pub fn edit_contact(
+        &self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        self.in_transaction(|tx| {
+            if let Some(entry) = &entry {
+                modify_contact(tx, id, entry, self.default_country)?;
+            }
+            if let Some(new_name) = new_name {
+                rename_contact(tx, id, new_name)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes the contact and returns it as it was just before.
+    /// Makes the changes in order in one transaction, so that either all of them happen or none.
+    pub fn apply_changes(&self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        self.in_transaction(|tx| {
+            for change in changes {
+                match change {
+                    StoreChange::Remove(id) => {
+                        remove_contact(tx, id)?;
+                    }
+                    StoreChange::Copy(contacts) => {
+                        for contact in &contacts {
+                            insert_contact(
+                                tx,
+                                contact.uuid,
+                                &contact.name,
+                                &contact.entry,
+                                self.default_country,
+                            )?;
+                        }
+                    }
+                    StoreChange::Edit {
+                        id,
+                        entry,
+                        new_name,
+                    } => {
+                        if let Some(entry) = &entry {
+                            modify_contact(tx, id, entry, self.default_country)?;
+                        }
+                        if let Some(new_name) = new_name {
+                            rename_contact(tx, id, new_name)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove_contact(&self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        self.in_transaction(|tx| remove_contact(tx, id))
+    }
+
+    /// Gives the contact a new name, keeping everything else about it.
+    /// Fails with `AlreadyExists` if another contact already has that name.
+    pub fn rename_contact(&self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        self.in_transaction(|tx| rename_contact(tx, id, new_name))
+    }
+
+    /// Returns every contact that has `number`, however it was written.
+    /// Numbers are compared by their digits in E.164 form, or as typed when that cannot be
+    /// worked out, so formatting and extensions make no difference.
+    pub fn find_contacts_by_number(
+        &self,
+        number: &str,
+        number_match: NumberMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        let mut reversed_digits = reversed_digits(number, self.default_country);
+        if reversed_digits.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Digits need no escaping in a GLOB pattern, which unlike LIKE can use the index.
+        let pattern = match number_match {
+            NumberMatch::Whole => reversed_digits,
+            NumberMatch::LastDigits(count) => {
+                reversed_digits.truncate(count);
+                reversed_digits + "*"
+            }
+        };
+        let ids = self
+            .conn
+            .prepare(
+                "SELECT DISTINCT contact_id FROM phone_number
+                 WHERE reversed_digits GLOB ?1 ORDER BY contact_id",
+            )?
+            .query_map([pattern], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+
+        let mut contacts = Vec::new();
+        for id in ids {
+            contacts.extend(read_contact(&self.conn, ContactId(id))?);
+        }
+        Ok(contacts)
+    }
+
+    /// Reads the contacts that `filter`, a `WHERE` clause or nothing, lets through.
+    fn query_contacts(
+        &self,
+        filter: &str,
+        params: impl Params,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{SELECT_CONTACTS} {filter} ORDER BY id"))?;
+
+        let mut contacts = stmt
+            .query_map(params, contact_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        for contact in &mut contacts {
+            contact.entry = read_entry(&self.conn, contact.id)?;
+        }
+
+        Ok(contacts)
+    }
+}
+
+/// Name based access for callers that treat names as unique.
+/// When several contacts share a name, these methods act on the oldest of them.
+impl PhoneBookDB {
+    /// Replaces the numbers of the contact and returns the ones it had before.
+    pub fn modify_entry(
         &self,
         name: String,
         entry: PhoneEntry,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.create_table_if_not_exists()?;
-        self.conn
-            .execute("DELETE FROM phone_book WHERE name = ?1", [&name])?;
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        self.in_transaction(|tx| {
+            modify_contact(tx, existing_id(tx, &name)?, &entry, self.default_country)
+        })
+    }
 
-        self.conn.execute(
-            "INSERT INTO phone_book (name, phone_number, work_number) VALUES(?1, ?2, ?3)",
-            (name, entry.mobile, entry.work),
-        )?;
+    pub fn rename_entry(&self, name: &str, new_name: String) -> Result<(), PhoneBookError> {
+        self.in_transaction(|tx| rename_contact(tx, existing_id(tx, name)?, new_name))
+    }
 
-        Ok(())
+    /// Removes the contact and returns the numbers it had.
+    pub fn remove_entry(&self, name: &str) -> Result<PhoneEntry, PhoneBookError> {
+        self.in_transaction(|tx| Ok(remove_contact(tx, existing_id(tx, name)?)?.entry))
+    }
+
+    /// Contacts sharing a name are collapsed into one key, so prefer `read_all_contacts`
+    /// when duplicates matter.
+    pub fn read_all_entries(&self) -> Result<BTreeMap<String, PhoneEntry>, PhoneBookError> {
+        let data = self.read_all_entries_as_vec(None)?;
+
+        let phone_book = data.into_iter().collect();
+        Ok(phone_book)
     }
 
-    pub fn read_entry(
+    /// Updates the contact with this name in place, keeping its ID, or creates it if there is none.
+    pub fn write_entry(
         &self,
         name: String,
-    ) -> Result<Option<PhoneEntry>, Box<dyn std::error::Error>> {
+        entry: PhoneEntry,
+    ) -> Result<WriteOutcome, PhoneBookError> {
+        self.in_transaction(|tx| match find_id_by_name(tx, &name)? {
+            Some(id) => Ok(WriteOutcome::Replaced {
+                id,
+                previous: modify_contact(tx, id, &entry, self.default_country)?,
+            }),
+            None => Ok(WriteOutcome::Created(insert_contact(
+                tx,
+                Uuid::new_v4(),
+                &name,
+                &entry,
+                self.default_country,
+            )?)),
+        })
+    }
+
+    pub fn read_entry(&self, name: String) -> Result<Option<PhoneEntry>, PhoneBookError> {
         let data = self.read_all_entries_as_vec(Some(name))?;
         if data.is_empty() {
             Ok(None)
@@ -106,103 +678,393 @@ impl PhoneBookDB {
     fn read_all_entries_as_vec(
         &self,
         name: Option<String>,
-    ) -> Result<Vec<(String, PhoneEntry)>, Box<dyn std::error::Error>> {
-        self.create_table_if_not_exists()?;
-        let mut stmt = match &name {
-            Some(_name) => self.conn.prepare(
-                "SELECT name, phone_number, work_number FROM phone_book WHERE name = ?1",
-            )?,
-            None => self
-                .conn
-                .prepare("SELECT name, phone_number, work_number FROM phone_book")?,
+    ) -> Result<Vec<(String, PhoneEntry)>, PhoneBookError> {
+        let contacts = match name {
+            Some(name) => self.read_contacts_by_name(&name)?,
+            None => self.read_all_contacts()?,
         };
 
-        let params = match &name {
-            Some(name) => params![name.clone()],
-            None => params![],
-        };
+        Ok(contacts
+            .into_iter()
+            .map(|contact| (contact.name, contact.entry))
+            .collect())
+    }
+}
 
-        let phone_book_iter = stmt.query_map(params, |row| {
-            let name: String = row.get("name")?;
-            let phone_number: String = row.get("phone_number")?;
-            let work_number: String = row.get("work_number")?;
+fn insert_contact(
+    conn: &Connection,
+    uuid: Uuid,
+    name: &str,
+    entry: &PhoneEntry,
+    country: Option<&Country>,
+) -> Result<ContactId, PhoneBookError> {
+    validate::validate_entry(entry, country)?;
+    conn.execute(
+        "INSERT INTO phone_book (uuid, name, search_name) VALUES(?1, ?2, ?3)",
+        (uuid.to_string(), name, search::fold(name)),
+    )?;
+    let id = ContactId(conn.last_insert_rowid());
+    write_entry(conn, id, entry, country)?;
+    log_change(
+        conn,
+        AuditOperation::Insert,
+        None,
+        Some(&existing_contact(conn, id)?),
+    )?;
 
-            Ok((name, phone_number, work_number))
-        })?;
-        let mut phone_book = Vec::new();
+    Ok(id)
+}
 
-        for phone_book_entry in phone_book_iter {
-            let phone_book_entry = phone_book_entry.unwrap();
-            phone_book.push((
-                phone_book_entry.0,
-                PhoneEntry {
-                    mobile: phone_book_entry.1,
-                    work: phone_book_entry.2,
-                },
-            ));
+fn import_contact(
+    conn: &Connection,
+    name: &str,
+    entry: PhoneEntry,
+    on_conflict: OnConflict,
+    country: Option<&Country>,
+    summary: &mut ImportSummary,
+) -> Result<(), PhoneBookError> {
+    let Some(id) = find_id_by_name(conn, name)? else {
+        insert_contact(conn, Uuid::new_v4(), name, &entry, country)?;
+        summary.created += 1;
+        return Ok(());
+    };
+
+    match on_conflict {
+        OnConflict::Skip => summary.skipped += 1,
+        OnConflict::Overwrite => {
+            modify_contact(conn, id, &entry, country)?;
+            summary.overwritten += 1;
+        }
+        OnConflict::Merge => {
+            let mut merged = read_entry(conn, id)?;
+            merged.merge(entry, country);
+            modify_contact(conn, id, &merged, country)?;
+            summary.merged += 1;
+        }
+    }
+    Ok(())
+}
+
+fn read_contact(conn: &Connection, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+    let contact = conn
+        .query_row(
+            &format!("{SELECT_CONTACTS} WHERE id = ?1"),
+            [id.0],
+            contact_from_row,
+        )
+        .optional()?;
+
+    match contact {
+        Some(mut contact) => {
+            contact.entry = read_entry(conn, id)?;
+            Ok(Some(contact))
         }
+        None => Ok(None),
+    }
+}
 
-        Ok(phone_book)
+fn existing_contact(conn: &Connection, id: ContactId) -> Result<Contact, PhoneBookError> {
+    read_contact(conn, id)?.ok_or_else(|| PhoneBookError::NotFound(format!("ID {id}")))
+}
+
+fn find_id_by_name(conn: &Connection, name: &str) -> Result<Option<ContactId>, PhoneBookError> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM phone_book WHERE name = ?1 ORDER BY id LIMIT 1",
+            [name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(id.map(ContactId))
+}
+
+fn existing_id(conn: &Connection, name: &str) -> Result<ContactId, PhoneBookError> {
+    find_id_by_name(conn, name)?.ok_or_else(|| PhoneBookError::NotFound(name.to_owned()))
+}
+
+fn modify_contact(
+    conn: &Connection,
+    id: ContactId,
+    entry: &PhoneEntry,
+    country: Option<&Country>,
+) -> Result<PhoneEntry, PhoneBookError> {
+    let previous = existing_contact(conn, id)?;
+    validate::validate_new_numbers(entry, &previous.entry, country)?;
+    write_entry(conn, id, entry, country)?;
+    log_change(
+        conn,
+        AuditOperation::Modify,
+        Some(&previous),
+        Some(&existing_contact(conn, id)?),
+    )?;
+
+    Ok(previous.entry)
+}
+
+fn remove_contact(conn: &Connection, id: ContactId) -> Result<Contact, PhoneBookError> {
+    let previous = existing_contact(conn, id)?;
+    conn.execute("DELETE FROM phone_book WHERE id = ?1", [id.0])?;
+    log_change(conn, AuditOperation::Remove, Some(&previous), None)?;
+
+    Ok(previous)
+}
+
+fn rename_contact(
+    conn: &Connection,
+    id: ContactId,
+    new_name: String,
+) -> Result<(), PhoneBookError> {
+    let taken = conn
+        .query_row(
+            "SELECT 1 FROM phone_book WHERE name = ?1 AND id != ?2",
+            (&new_name, id.0),
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if taken {
+        return Err(PhoneBookError::AlreadyExists(new_name));
+    }
+
+    let previous = existing_contact(conn, id)?;
+    conn.execute(
+        "UPDATE phone_book SET name = ?2, search_name = ?3 WHERE id = ?1",
+        (id.0, &new_name, search::fold(&new_name)),
+    )?;
+    log_change(
+        conn,
+        AuditOperation::Rename,
+        Some(&previous),
+        Some(&existing_contact(conn, id)?),
+    )?;
+
+    Ok(())
+}
+
+/// Appends a change to the audit log. It has to be written in the transaction that makes the
+/// change, so that the log holds every change that was committed and nothing else.
+fn log_change(
+    conn: &Connection,
+    operation: AuditOperation,
+    old: Option<&Contact>,
+    new: Option<&Contact>,
+) -> Result<(), PhoneBookError> {
+    let Some(contact) = new.or(old) else {
+        return Ok(());
+    };
+    let value = |contact: Option<&Contact>| contact.map(serde_json::to_string).transpose();
+    conn.prepare_cached(
+        "INSERT INTO audit_log
+            (timestamp, os_user, operation, contact_id, contact_uuid, old_value, new_value)
+         VALUES (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'), ?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute((
+        os_user(),
+        operation.as_str(),
+        contact.id.0,
+        contact.uuid.to_string(),
+        value(old)?,
+        value(new)?,
+    ))?;
+    Ok(())
+}
+
+/// The name of the user running the program, for the audit log.
+fn os_user() -> String {
+    ["USER", "LOGNAME", "USERNAME"]
+        .into_iter()
+        .find_map(|name| std::env::var(name).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Reads the numbers and addresses of a contact in the order they were written.
+fn read_entry(conn: &Connection, id: ContactId) -> Result<PhoneEntry, PhoneBookError> {
+    let numbers = conn
+        .prepare_cached(
+            "SELECT label, number, preferred FROM phone_number WHERE contact_id = ?1 ORDER BY position",
+        )?
+        .query_map([id.0], |row| {
+            let Ok(label) = row.get::<_, String>("label")?.parse::<NumberLabel>();
+            Ok(PhoneNumber {
+                label,
+                number: row.get("number")?,
+                preferred: row.get("preferred")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let emails = conn
+        .prepare_cached(
+            "SELECT label, address FROM email_address WHERE contact_id = ?1 ORDER BY position",
+        )?
+        .query_map([id.0], |row| {
+            let Ok(label) = row.get::<_, String>("label")?.parse::<AddressLabel>();
+            Ok(EmailAddress {
+                label,
+                address: row.get("address")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let addresses = conn
+        .prepare_cached(
+            "SELECT label, street, city, region, postcode, country FROM postal_address
+             WHERE contact_id = ?1 ORDER BY position",
+        )?
+        .query_map([id.0], |row| {
+            let Ok(label) = row.get::<_, String>("label")?.parse::<AddressLabel>();
+            Ok(PostalAddress {
+                label,
+                street: row.get("street")?,
+                city: row.get("city")?,
+                region: row.get("region")?,
+                postcode: row.get("postcode")?,
+                country: row.get("country")?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(PhoneEntry {
+        numbers,
+        emails,
+        addresses,
+    })
+}
+
+/// Replaces all numbers and addresses of a contact with the ones in `entry`.
+/// Numbers are stored together with their E.164 form when `country` allows working it out;
+/// they have to be validated before.
+fn write_entry(
+    conn: &Connection,
+    id: ContactId,
+    entry: &PhoneEntry,
+    country: Option<&Country>,
+) -> Result<(), PhoneBookError> {
+    for table in ["phone_number", "email_address", "postal_address"] {
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE contact_id = ?1"),
+            [id.0],
+        )?;
+    }
+
+    let mut insert = conn.prepare_cached(
+        "INSERT INTO phone_number
+            (contact_id, position, label, number, preferred, e164, reversed_digits)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (position, number) in entry.numbers.iter().enumerate() {
+        insert.execute((
+            id.0,
+            position,
+            number.label.as_str(),
+            &number.number,
+            number.preferred,
+            normalize::to_e164(&number.number, country),
+            reversed_digits(&number.number, country),
+        ))?;
     }
+
+    let mut insert = conn.prepare_cached(
+        "INSERT INTO email_address (contact_id, position, label, address) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (position, email) in entry.emails.iter().enumerate() {
+        insert.execute((id.0, position, email.label.as_str(), &email.address))?;
+    }
+
+    let mut insert = conn.prepare_cached(
+        "INSERT INTO postal_address (contact_id, position, label, street, city, region, postcode, country)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for (position, address) in entry.addresses.iter().enumerate() {
+        insert.execute((
+            id.0,
+            position,
+            address.label.as_str(),
+            &address.street,
+            &address.city,
+            &address.region,
+            &address.postcode,
+            &address.country,
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// What `phone_number.reversed_digits` holds for `number`.
+fn reversed_digits(number: &str, country: Option<&Country>) -> String {
+    normalize::lookup_digits(number, country)
+        .chars()
+        .rev()
+        .collect()
+}
+
+fn contact_from_row(row: &Row) -> rusqlite::Result<Contact> {
+    let uuid: String = row.get("uuid")?;
+    let uuid = Uuid::parse_str(&uuid).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(err))
+    })?;
+
+    Ok(Contact {
+        id: ContactId(row.get("id")?),
+        uuid,
+        name: row.get("name")?,
+        entry: PhoneEntry::default(),
+    })
 }
+
 #[cfg(test)]
 mod tests {
-    use crate::{database::PhoneBookDB, entry::PhoneEntry};
+    use crate::{
+        database::{
+            AuditOperation, ImportSummary, NumberMatch, OnConflict, PhoneBookDB, WriteOutcome,
+        },
+        entry::{
+            AddressLabel, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+            PostalAddress,
+        },
+        error::PhoneBookError,
+        search::NameMatch,
+        validate::Invalid,
+    };
+
+    fn entry(mobile: &str, work: &str) -> PhoneEntry {
+        PhoneEntry {
+            numbers: vec![
+                PhoneNumber::new(NumberLabel::Mobile, mobile),
+                PhoneNumber::new(NumberLabel::Work, work),
+            ],
+            ..PhoneEntry::default()
+        }
+    }
 
     #[test]
     fn read_in_file() {
         let phone_book = PhoneBookDB::new(None).unwrap();
         let data = phone_book.read_all_entries().unwrap();
-        assert_eq!(data.is_empty(), true)
+        assert!(data.is_empty())
     }
 
     #[test]
     fn single_writes() {
         let phone_book_db = PhoneBookDB::new(None).unwrap();
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "9027590".to_owned(),
-                    work: "3795780357".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("9027590", "3795780357"))
             .unwrap();
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "02875902".to_owned(),
-                    work: "98270987".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("02875902", "98270987"))
             .unwrap();
 
         phone_book_db
-            .write_entry(
-                "Mark".to_owned(),
-                PhoneEntry {
-                    mobile: "375946".to_owned(),
-                    work: "738749".to_owned(),
-                },
-            )
+            .write_entry("Mark".to_owned(), entry("375946", "738749"))
             .unwrap();
 
         let read_phone_book_db = phone_book_db.read_all_entries_as_vec(None).unwrap();
-        assert!(read_phone_book_db.contains(&(
-            "Arnold".to_owned(),
-            PhoneEntry {
-                mobile: "9027590".to_owned(),
-                work: "3795780357".to_owned(),
-            },
-        )));
-        assert!(read_phone_book_db.contains(&(
-            "Jack".to_owned(),
-            PhoneEntry {
-                mobile: "02875902".to_owned(),
-                work: "98270987".to_owned(),
-            },
-        )));
+        assert!(
+            read_phone_book_db.contains(&("Arnold".to_owned(), entry("9027590", "3795780357"),))
+        );
+        assert!(read_phone_book_db.contains(&("Jack".to_owned(), entry("02875902", "98270987"),)));
     }
 
     #[test]
@@ -212,66 +1074,30 @@ mod tests {
         assert_eq!(phone_book_db.read_all_entries_as_vec(None).unwrap(), vec![]);
 
         phone_book_db
-            .write_entry(
-                "arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "345345".to_owned(),
-                    work: "3535345".to_owned(),
-                },
-            )
+            .write_entry("arnold".to_owned(), entry("345345", "3535345"))
             .unwrap();
 
         assert_eq!(
             phone_book_db.read_all_entries_as_vec(None).unwrap(),
-            vec![(
-                "arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "345345".to_owned(),
-                    work: "3535345".to_owned(),
-                },
-            )]
+            vec![("arnold".to_owned(), entry("345345", "3535345"),)]
         );
 
         assert_eq!(
             phone_book_db
                 .read_all_entries_as_vec(Some(String::from("arnold")))
                 .unwrap(),
-            vec![(
-                "arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "345345".to_owned(),
-                    work: "3535345".to_owned(),
-                },
-            )]
+            vec![("arnold".to_owned(), entry("345345", "3535345"),)]
         );
 
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "9870982".to_owned(),
-                    work: "279573".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("9870982", "279573"))
             .unwrap();
 
         assert_eq!(
             phone_book_db.read_all_entries_as_vec(None).unwrap(),
             vec![
-                (
-                    "arnold".to_owned(),
-                    PhoneEntry {
-                        mobile: "345345".to_owned(),
-                        work: "3535345".to_owned(),
-                    },
-                ),
-                (
-                    "Jack".to_owned(),
-                    PhoneEntry {
-                        mobile: "9870982".to_owned(),
-                        work: "279573".to_owned(),
-                    },
-                )
+                ("arnold".to_owned(), entry("345345", "3535345"),),
+                ("Jack".to_owned(), entry("9870982", "279573"),)
             ]
         );
 
@@ -279,26 +1105,14 @@ mod tests {
             phone_book_db
                 .read_all_entries_as_vec(Some(String::from("arnold")))
                 .unwrap(),
-            vec![(
-                "arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "345345".to_owned(),
-                    work: "3535345".to_owned(),
-                },
-            )]
+            vec![("arnold".to_owned(), entry("345345", "3535345"),)]
         );
 
         assert_eq!(
             phone_book_db
                 .read_all_entries_as_vec(Some(String::from("Jack")))
                 .unwrap(),
-            vec![(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "9870982".to_owned(),
-                    work: "279573".to_owned(),
-                },
-            )]
+            vec![("Jack".to_owned(), entry("9870982", "279573"),)]
         )
     }
 
@@ -307,34 +1121,16 @@ mod tests {
         let phone_book_db = PhoneBookDB::new(None).unwrap();
 
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "397097345".to_owned(),
-                    work: "789346535".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("397097345", "789346535"))
             .unwrap();
 
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "983534354".to_owned(),
-                    work: "34759384793".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("983534354", "34759384793"))
             .unwrap();
 
         assert_eq!(
             phone_book_db.read_all_entries_as_vec(None).unwrap(),
-            vec![(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "983534354".to_owned(),
-                    work: "34759384793".to_owned()
-                }
-            )]
+            vec![("Arnold".to_owned(), entry("983534354", "34759384793"))]
         )
     }
 
@@ -343,70 +1139,38 @@ mod tests {
         let phone_book_db = PhoneBookDB::new(None).unwrap();
 
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "83749876389".to_owned(),
-                    work: "3758937498".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("83749876389", "3758937498"))
             .unwrap();
 
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "938759834".to_owned(),
-                    work: "73598739074".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("938759834", "73598739074"))
             .unwrap();
 
         phone_book_db
-            .modify_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "938759834".to_owned(),
-                    work: "73598739074".to_owned(),
-                },
-            )
+            .modify_entry("Arnold".to_owned(), entry("938759834", "73598739074"))
             .unwrap();
 
         assert_eq!(
             phone_book_db
                 .read_all_entries_as_vec(Some(String::from("Arnold")))
                 .unwrap(),
-            vec![(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "938759834".to_owned(),
-                    work: "73598739074".to_owned(),
-                },
-            )]
+            vec![("Arnold".to_owned(), entry("938759834", "73598739074"),)]
         );
     }
     #[test]
     fn modify_entries_not_exist() {
         let phone_book_db = PhoneBookDB::new(None).unwrap();
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "938759834".to_owned(),
-                    work: "73598739074".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("938759834", "73598739074"))
             .unwrap();
 
-        phone_book_db
-            .modify_entry(
+        assert!(matches!(
+            phone_book_db.modify_entry(
                 "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "938759834".to_owned(),
-                    work: "73598739074".to_owned(),
-                },
-            )
-            .unwrap();
+                entry("938759834", "73598739074"),
+            ),
+            Err(PhoneBookError::NotFound(name)) if name == "Arnold"
+        ));
 
         assert_eq!(
             phone_book_db
@@ -422,7 +1186,10 @@ mod tests {
 
         assert_eq!(phone_book_db.read_all_entries_as_vec(None).unwrap(), vec![]);
 
-        phone_book_db.remove_entry("Arnold").unwrap();
+        assert!(matches!(
+            phone_book_db.remove_entry("Arnold"),
+            Err(PhoneBookError::NotFound(_))
+        ));
         assert_eq!(phone_book_db.read_all_entries_as_vec(None).unwrap(), vec![]);
 
         assert_eq!(
@@ -432,42 +1199,18 @@ mod tests {
             vec![]
         );
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "83750893475".to_owned(),
-                    work: "738765987364".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("83750893475", "738765987364"))
             .unwrap();
 
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "3535345345".to_owned(),
-                    work: "3453534562".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("3535345345", "3453534562"))
             .unwrap();
 
         assert_eq!(
             phone_book_db.read_all_entries_as_vec(None).unwrap(),
             vec![
-                (
-                    "Arnold".to_owned(),
-                    PhoneEntry {
-                        mobile: "83750893475".to_owned(),
-                        work: "738765987364".to_owned(),
-                    },
-                ),
-                (
-                    "Jack".to_owned(),
-                    PhoneEntry {
-                        mobile: "3535345345".to_owned(),
-                        work: "3453534562".to_owned(),
-                    },
-                )
+                ("Arnold".to_owned(), entry("83750893475", "738765987364"),),
+                ("Jack".to_owned(), entry("3535345345", "3453534562"),)
             ]
         );
 
@@ -475,13 +1218,7 @@ mod tests {
 
         assert_eq!(
             phone_book_db.read_all_entries_as_vec(None).unwrap(),
-            vec![(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "3535345345".to_owned(),
-                    work: "3453534562".to_owned(),
-                }
-            )]
+            vec![("Jack".to_owned(), entry("3535345345", "3453534562"))]
         );
 
         assert_eq!(
@@ -514,23 +1251,11 @@ mod tests {
         let phone_book_db = PhoneBookDB::new(None).unwrap();
 
         phone_book_db
-            .write_entry(
-                "Arnold".to_owned(),
-                PhoneEntry {
-                    mobile: "903795".to_owned(),
-                    work: "89347509".to_owned(),
-                },
-            )
+            .write_entry("Arnold".to_owned(), entry("903795", "89347509"))
             .unwrap();
 
         phone_book_db
-            .write_entry(
-                "Jack".to_owned(),
-                PhoneEntry {
-                    mobile: "37597343".to_owned(),
-                    work: "398745".to_owned(),
-                },
-            )
+            .write_entry("Jack".to_owned(), entry("37597343", "398745"))
             .unwrap();
 
         assert_eq!(
@@ -538,10 +1263,7 @@ mod tests {
                 .read_entry("Arnold".to_owned())
                 .unwrap()
                 .unwrap(),
-            PhoneEntry {
-                mobile: "903795".to_owned(),
-                work: "89347509".to_owned(),
-            },
+            entry("903795", "89347509"),
         );
 
         assert_eq!(
@@ -549,10 +1271,7 @@ mod tests {
                 .read_entry("Jack".to_owned())
                 .unwrap()
                 .unwrap(),
-            PhoneEntry {
-                mobile: "37597343".to_owned(),
-                work: "398745".to_owned(),
-            }
+            entry("37597343", "398745")
         );
 
         assert!(phone_book_db
@@ -560,4 +1279,735 @@ mod tests {
             .unwrap()
             .is_none());
     }
+
+    #[test]
+    fn duplicate_names_get_their_own_ids() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+
+        let first = phone_book_db
+            .insert_contact("John Smith".to_owned(), entry("111", "222"))
+            .unwrap();
+        let second = phone_book_db
+            .insert_contact("John Smith".to_owned(), entry("333", "444"))
+            .unwrap();
+        assert_ne!(first, second);
+
+        let contacts = phone_book_db.read_contacts_by_name("John Smith").unwrap();
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].id, first);
+        assert_eq!(contacts[1].id, second);
+        assert_ne!(contacts[0].uuid, contacts[1].uuid);
+
+        phone_book_db
+            .modify_contact(second, entry("555", "666"))
+            .unwrap();
+        phone_book_db.remove_contact(first).unwrap();
+
+        assert!(phone_book_db.read_contact(first).unwrap().is_none());
+        let remaining = phone_book_db.read_contact(second).unwrap().unwrap();
+        assert_eq!(remaining.name, "John Smith");
+        assert_eq!(remaining.entry, entry("555", "666"));
+    }
+
+    #[test]
+    fn write_entry_keeps_ids_stable() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+
+        phone_book_db
+            .write_entry("Arnold".to_owned(), entry("903795", "89347509"))
+            .unwrap();
+        let contact = phone_book_db.read_contacts_by_name("Arnold").unwrap()[0].clone();
+
+        phone_book_db
+            .write_entry("Arnold".to_owned(), entry("37597343", "398745"))
+            .unwrap();
+        let rewritten = phone_book_db.read_contact(contact.id).unwrap().unwrap();
+
+        assert_eq!(rewritten.uuid, contact.uuid);
+        assert_eq!(rewritten.entry, entry("37597343", "398745"));
+
+        phone_book_db.remove_contact(contact.id).unwrap();
+        let new_id = phone_book_db
+            .insert_contact("Arnold".to_owned(), entry("2398745", "4987345"))
+            .unwrap();
+        assert!(new_id > contact.id);
+        assert!(phone_book_db.read_contact(ContactId(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn renames_keep_ids_and_numbers() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let numbers = entry("903795", "89347509");
+        let id = phone_book_db
+            .insert_contact("Arnld".to_owned(), numbers.clone())
+            .unwrap();
+
+        phone_book_db
+            .rename_entry("Arnld", "Arnold".to_owned())
+            .unwrap();
+
+        let contact = phone_book_db.read_contact(id).unwrap().unwrap();
+        assert_eq!(contact.name, "Arnold");
+        assert_eq!(contact.entry, numbers);
+        assert!(phone_book_db
+            .read_entry("Arnld".to_owned())
+            .unwrap()
+            .is_none());
+
+        // Renaming to the current name is allowed.
+        phone_book_db
+            .rename_contact(id, "Arnold".to_owned())
+            .unwrap();
+    }
+
+    #[test]
+    fn searches_ignore_case_and_accents() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let add = |name: &str| {
+            phone_book_db
+                .insert_contact(name.to_owned(), PhoneEntry::default())
+                .unwrap()
+        };
+        let zoe = add("Zoë Arnold");
+        let arnold = add("ARNOLD");
+        let percent = add("100% Jack");
+        let renamed = add("Jill");
+        phone_book_db
+            .rename_contact(renamed, "Renée".to_owned())
+            .unwrap();
+
+        let ids = |text: &str, name_match| {
+            phone_book_db
+                .search_contacts(text, name_match)
+                .unwrap()
+                .into_iter()
+                .map(|contact| contact.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("arnold", NameMatch::Substring), vec![zoe, arnold]);
+        assert_eq!(ids(" Arn ", NameMatch::Prefix), vec![arnold]);
+        assert_eq!(ids("ZOE", NameMatch::Prefix), vec![zoe]);
+        assert_eq!(ids("renee", NameMatch::Prefix), vec![renamed]);
+        assert_eq!(ids("jill", NameMatch::Substring), vec![]);
+        assert_eq!(ids("0%", NameMatch::Substring), vec![percent]);
+        assert_eq!(ids("_", NameMatch::Substring), vec![]);
+        assert_eq!(ids("", NameMatch::Prefix).len(), 4);
+    }
+
+    #[test]
+    fn full_text_search_follows_every_change() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let arnold = phone_book_db
+            .insert_contact(
+                "Arnold Baker".to_owned(),
+                PhoneEntry {
+                    emails: vec![EmailAddress {
+                        label: AddressLabel::Work,
+                        address: "arnold@example.com".to_owned(),
+                    }],
+                    ..PhoneEntry::default()
+                },
+            )
+            .unwrap();
+        let jack = phone_book_db
+            .insert_contact(
+                "Jack".to_owned(),
+                PhoneEntry {
+                    numbers: vec![PhoneNumber::new(NumberLabel::Home, "+44 20 7946 0958")],
+                    addresses: vec![PostalAddress::from_parts(
+                        AddressLabel::Home,
+                        "221B Baker Street;London;;NW1 6XE;UK",
+                    )
+                    .unwrap()],
+                    ..PhoneEntry::default()
+                },
+            )
+            .unwrap();
+        let find = |query: &str| phone_book_db.search_full_text(query, ("[", "]")).unwrap();
+
+        // A match in the name ranks above one in an address.
+        let hits = find("baker");
+        assert_eq!(
+            hits.iter().map(|hit| hit.contact.id).collect::<Vec<_>>(),
+            vec![arnold, jack]
+        );
+        assert_eq!(hits[0].name, "Arnold [Baker]");
+        assert!(hits[0].details.is_empty());
+        assert_eq!(hits[1].name, "Jack");
+        assert_eq!(
+            hits[1].details,
+            vec!["221B [Baker] Street, London, NW1 6XE, UK"]
+        );
+        assert_eq!(find("\"baker street\" lond*").len(), 1);
+        assert_eq!(find("\"street baker\"").len(), 0);
+        assert_eq!(
+            find("7946")[0].details,
+            vec!["+44 20 [7946] 0958 +442079460958"]
+        );
+        assert_eq!(find("example")[0].contact.id, arnold);
+
+        phone_book_db
+            .modify_contact(jack, PhoneEntry::default())
+            .unwrap();
+        assert!(find("7946").is_empty());
+        phone_book_db
+            .rename_contact(jack, "Jacques".to_owned())
+            .unwrap();
+        assert!(find("jack").is_empty());
+        assert_eq!(find("jacq*")[0].name, "[Jacques]");
+        phone_book_db.remove_contact(arnold).unwrap();
+        assert!(find("arnold").is_empty());
+        assert!(find("\"\"").is_empty());
+    }
+
+    #[test]
+    fn indexed_details_keep_their_order() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let id = phone_book_db
+            .insert_contact(
+                "Arnold".to_owned(),
+                PhoneEntry {
+                    addresses: vec![
+                        PostalAddress::from_parts(AddressLabel::Home, ";London").unwrap()
+                    ],
+                    ..entry("903795", "89347509")
+                },
+            )
+            .unwrap();
+        // Rows are not necessarily stored in the order of their positions.
+        phone_book_db
+            .conn
+            .execute(
+                "UPDATE phone_number SET position = 2 WHERE contact_id = ?1 AND position = 0",
+                [id.0],
+            )
+            .unwrap();
+
+        let (numbers, addresses): (String, String) = phone_book_db
+            .conn
+            .query_row(
+                "SELECT numbers, addresses FROM contact_search WHERE rowid = ?1",
+                [id.0],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(numbers, "89347509 \n903795 ");
+        assert_eq!(addresses, "London");
+    }
+
+    #[test]
+    fn similar_names_are_ranked() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        for name in ["Arnie", "Arnold Baker", "Jack", "Arnold"] {
+            phone_book_db
+                .insert_contact(name.to_owned(), entry("903795", "89347509"))
+                .unwrap();
+        }
+
+        let similar = phone_book_db.find_similar_names("Arnlod").unwrap();
+
+        assert_eq!(
+            similar
+                .iter()
+                .map(|similar| similar.contact.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Arnold Baker", "Arnold"]
+        );
+        assert_eq!(similar[0].score, similar[1].score);
+        assert_eq!(similar[1].contact.entry, entry("903795", "89347509"));
+        assert!(phone_book_db.find_similar_names("Zed").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rename_fails_cleanly() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let numbers = entry("903795", "89347509");
+        phone_book_db
+            .write_entry("Arnold".to_owned(), numbers.clone())
+            .unwrap();
+        phone_book_db
+            .write_entry("Jack".to_owned(), numbers.clone())
+            .unwrap();
+
+        assert!(matches!(
+            phone_book_db.rename_entry("Jack", "Arnold".to_owned()),
+            Err(PhoneBookError::AlreadyExists(name)) if name == "Arnold"
+        ));
+        assert!(matches!(
+            phone_book_db.rename_entry("Mark", "Marco".to_owned()),
+            Err(PhoneBookError::NotFound(_))
+        ));
+        assert!(matches!(
+            phone_book_db.rename_contact(ContactId(42), "Marco".to_owned()),
+            Err(PhoneBookError::NotFound(_))
+        ));
+
+        assert_eq!(
+            phone_book_db.read_all_entries().unwrap().len(),
+            2,
+            "failed renames must not change anything"
+        );
+        assert_eq!(
+            phone_book_db.read_entry("Jack".to_owned()).unwrap(),
+            Some(numbers)
+        );
+    }
+
+    #[test]
+    fn mutations_report_outcomes() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let first = entry("903795", "89347509");
+        let second = entry("37597343", "398745");
+
+        let WriteOutcome::Created(id) = phone_book_db
+            .write_entry("Arnold".to_owned(), first.clone())
+            .unwrap()
+        else {
+            panic!("the first write must create the contact");
+        };
+        assert_eq!(
+            phone_book_db
+                .write_entry("Arnold".to_owned(), second.clone())
+                .unwrap(),
+            WriteOutcome::Replaced {
+                id,
+                previous: first.clone(),
+            }
+        );
+
+        assert_eq!(
+            phone_book_db
+                .modify_entry("Arnold".to_owned(), first.clone())
+                .unwrap(),
+            second
+        );
+        assert_eq!(
+            phone_book_db.modify_contact(id, second.clone()).unwrap(),
+            first
+        );
+
+        let removed = phone_book_db.remove_contact(id).unwrap();
+        assert_eq!(removed.name, "Arnold");
+        assert_eq!(removed.entry, second);
+        assert!(matches!(
+            phone_book_db.remove_contact(id),
+            Err(PhoneBookError::NotFound(_))
+        ));
+        assert!(matches!(
+            phone_book_db.modify_contact(id, first),
+            Err(PhoneBookError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn numbers_keep_labels_order_and_preference() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let mut numbers = PhoneEntry {
+            numbers: vec![
+                PhoneNumber::new(NumberLabel::Home, "0201234567"),
+                PhoneNumber::new(
+                    NumberLabel::Custom("second mobile".to_owned()),
+                    "07700900123",
+                ),
+                PhoneNumber::new(NumberLabel::Fax, "0201234568"),
+                PhoneNumber::new(NumberLabel::Main, "0201234500"),
+            ],
+            ..PhoneEntry::default()
+        };
+        numbers.numbers[1].preferred = true;
+
+        let id = phone_book_db
+            .insert_contact("Arnold".to_owned(), numbers.clone())
+            .unwrap();
+        assert_eq!(
+            phone_book_db.read_contact(id).unwrap().unwrap().entry,
+            numbers
+        );
+
+        let no_numbers = PhoneEntry::default();
+        assert_eq!(
+            phone_book_db
+                .modify_contact(id, no_numbers.clone())
+                .unwrap(),
+            numbers
+        );
+        assert_eq!(
+            phone_book_db.read_contact(id).unwrap().unwrap().entry,
+            no_numbers
+        );
+    }
+
+    #[test]
+    fn removing_a_contact_removes_its_details() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let id = phone_book_db
+            .insert_contact(
+                "Arnold".to_owned(),
+                with_addresses(entry("903795", "89347509")),
+            )
+            .unwrap();
+
+        phone_book_db.remove_contact(id).unwrap();
+
+        for table in ["phone_number", "email_address", "postal_address"] {
+            let count: i64 = phone_book_db
+                .conn
+                .query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 0, "{table} still has rows");
+        }
+    }
+
+    fn with_addresses(mut entry: PhoneEntry) -> PhoneEntry {
+        entry.emails = vec![
+            EmailAddress {
+                label: AddressLabel::Work,
+                address: "arnold@example.com".to_owned(),
+            },
+            EmailAddress {
+                label: AddressLabel::Custom("old".to_owned()),
+                address: "arnie@example.org".to_owned(),
+            },
+        ];
+        entry.addresses = vec![PostalAddress {
+            label: AddressLabel::Home,
+            street: "12 Rue de la Paix".to_owned(),
+            city: "Paris".to_owned(),
+            region: String::new(),
+            postcode: "75002".to_owned(),
+            country: "France".to_owned(),
+        }];
+        entry
+    }
+
+    #[test]
+    fn emails_and_addresses_are_stored() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        let details = with_addresses(entry("903795", "89347509"));
+
+        let id = phone_book_db
+            .insert_contact("Arnold".to_owned(), details.clone())
+            .unwrap();
+        assert_eq!(
+            phone_book_db.read_contact(id).unwrap().unwrap().entry,
+            details
+        );
+
+        let previous = phone_book_db
+            .modify_contact(id, entry("903795", "89347509"))
+            .unwrap();
+        assert_eq!(previous, details);
+        let contact = phone_book_db.read_contact(id).unwrap().unwrap();
+        assert!(contact.entry.emails.is_empty());
+        assert!(contact.entry.addresses.is_empty());
+    }
+
+    #[test]
+    fn numbers_are_found_however_they_are_written() {
+        let mut phone_book_db = PhoneBookDB::new(None).unwrap();
+        let arnold = phone_book_db
+            .insert_contact(
+                "Arnold".to_owned(),
+                entry("020 7946 0958", "+1 212 555 0100"),
+            )
+            .unwrap();
+
+        // Without a default country only international numbers can be compared.
+        assert_eq!(
+            phone_book_db
+                .find_contacts_by_number("+1 (212) 555-0100 ext. 7", NumberMatch::Whole)
+                .unwrap()[0]
+                .id,
+            arnold
+        );
+        assert!(phone_book_db
+            .find_contacts_by_number("+44 20 7946 0958", NumberMatch::Whole)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            phone_book_db
+                .find_contacts_by_number("020-7946-0958", NumberMatch::Whole)
+                .unwrap()[0]
+                .id,
+            arnold
+        );
+        assert_eq!(
+            phone_book_db
+                .find_contacts_by_number("+44 20 7946 0958", NumberMatch::LastDigits(10))
+                .unwrap()[0]
+                .id,
+            arnold
+        );
+
+        phone_book_db.set_default_country(Some("gb")).unwrap();
+        assert_eq!(phone_book_db.default_country().unwrap().code, "GB");
+        for number in ["+44 20 7946 0958", "020 7946 0958", "00442079460958"] {
+            let contacts = phone_book_db
+                .find_contacts_by_number(number, NumberMatch::Whole)
+                .unwrap();
+            assert_eq!(contacts.len(), 1, "{number}");
+            assert_eq!(contacts[0].id, arnold);
+            // The number is still shown the way it was typed.
+            assert_eq!(contacts[0].entry.numbers[0].number, "020 7946 0958");
+        }
+
+        let jack = phone_book_db
+            .insert_contact("Jack".to_owned(), entry("07700 900958", "01632 960123"))
+            .unwrap();
+        let ids = |number, number_match| {
+            phone_book_db
+                .find_contacts_by_number(number, number_match)
+                .unwrap()
+                .into_iter()
+                .map(|contact| contact.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("958", NumberMatch::LastDigits(3)), vec![arnold, jack]);
+        assert_eq!(
+            ids("+44 1632 960958", NumberMatch::LastDigits(4)),
+            vec![arnold, jack]
+        );
+        assert_eq!(ids("900958", NumberMatch::LastDigits(6)), vec![jack]);
+        assert_eq!(ids("0958", NumberMatch::Whole), vec![]);
+        assert_eq!(ids("no digits", NumberMatch::LastDigits(4)), vec![]);
+
+        assert!(matches!(
+            phone_book_db.set_default_country(Some("XX")),
+            Err(PhoneBookError::UnknownCountry(_))
+        ));
+        assert_eq!(phone_book_db.default_country().unwrap().code, "GB");
+    }
+
+    #[test]
+    fn invalid_numbers_are_rejected() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+
+        assert!(matches!(
+            phone_book_db.write_entry("Arnold".to_owned(), entry("asdf", "89347509")),
+            Err(PhoneBookError::InvalidNumber { number, reason: Invalid::Character('a') })
+                if number == "asdf"
+        ));
+        assert!(phone_book_db.read_all_contacts().unwrap().is_empty());
+        assert!(phone_book_db
+            .insert_contact("Arnold".to_owned(), entry("asdf", "89347509"))
+            .is_err());
+        assert!(
+            phone_book_db.read_all_contacts().unwrap().is_empty(),
+            "a rejected contact must not be added without its numbers"
+        );
+
+        let id = phone_book_db
+            .insert_contact("Arnold".to_owned(), entry("903795", "89347509"))
+            .unwrap();
+        assert!(matches!(
+            phone_book_db.modify_contact(id, entry("903795", "")),
+            Err(PhoneBookError::InvalidNumber {
+                reason: Invalid::Empty,
+                ..
+            })
+        ));
+        assert_eq!(
+            phone_book_db.read_contact(id).unwrap().unwrap().entry,
+            entry("903795", "89347509"),
+            "a rejected modification must leave the contact alone"
+        );
+    }
+
+    #[test]
+    fn stored_numbers_are_not_checked_again() {
+        let mut phone_book_db = PhoneBookDB::new(None).unwrap();
+        phone_book_db.set_default_country(Some("GB")).unwrap();
+        let id = phone_book_db
+            .insert_contact("Arnold".to_owned(), entry("020 7946 0958", "020 7946 0000"))
+            .unwrap();
+
+        // Too long for France, but already stored, so changing the other number still works.
+        phone_book_db.set_default_country(Some("FR")).unwrap();
+        phone_book_db
+            .modify_contact(id, entry("020 7946 0958", "01 23 45 67 89"))
+            .unwrap();
+        assert!(matches!(
+            phone_book_db.modify_contact(id, entry("020 7946 1111", "01 23 45 67 89")),
+            Err(PhoneBookError::InvalidNumber { number, .. }) if number == "020 7946 1111"
+        ));
+    }
+
+    #[test]
+    fn copies_in_and_out() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+        phone_book_db
+            .write_entry("Arnold".to_owned(), entry("903795", "89347509"))
+            .unwrap();
+        let file_path =
+            std::env::temp_dir().join(format!("phone_book_copy_{}.sqlite", uuid::Uuid::new_v4()));
+
+        phone_book_db.export_copy(&file_path).unwrap();
+        let copy = PhoneBookDB::open_copy(&file_path);
+        std::fs::remove_file(&file_path).unwrap();
+
+        let copy = copy.unwrap();
+        let contacts = copy.read_all_contacts().unwrap();
+        assert_eq!(contacts, phone_book_db.read_all_contacts().unwrap());
+
+        assert!(matches!(
+            PhoneBookDB::open_copy(&file_path),
+            Err(PhoneBookError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn imports_are_all_or_nothing() {
+        let phone_book_db = PhoneBookDB::new(None).unwrap();
+
+        let result = phone_book_db.import_contacts(
+            vec![
+                ("line 2", "Arnold".to_owned(), entry("903795", "89347509")),
+                ("line 3", "Jack".to_owned(), entry("37597343", "asdf")),
+            ],
+            OnConflict::Skip,
+        );
+
+        assert!(
+            matches!(result, Err(PhoneBookError::Import(message)) if message.starts_with("line 3: "))
+        );
+        assert!(phone_book_db.read_all_contacts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn imports_handle_taken_names() {
+        let numbers = |numbers: &[(NumberLabel, &str)]| PhoneEntry {
+            numbers: numbers
+                .iter()
+                .map(|(label, number)| PhoneNumber::new(label.clone(), *number))
+                .collect(),
+            ..PhoneEntry::default()
+        };
+        let existing = with_addresses(numbers(&[(NumberLabel::Mobile, "903795")]));
+        let import = |on_conflict, imported: PhoneEntry| {
+            let phone_book_db = PhoneBookDB::new(None).unwrap();
+            phone_book_db
+                .insert_contact("Arnold".to_owned(), existing.clone())
+                .unwrap();
+            let summary = phone_book_db
+                .import_contacts(
+                    vec![
+                        (1, "Arnold".to_owned(), imported),
+                        (2, "Jack".to_owned(), entry("37597343", "89347509")),
+                    ],
+                    on_conflict,
+                )
+                .unwrap();
+            let arnold = phone_book_db.read_contacts_by_name("Arnold").unwrap();
+            assert_eq!(arnold.len(), 1);
+            (summary, arnold[0].entry.clone())
+        };
+
+        let (summary, arnold) = import(
+            OnConflict::Skip,
+            numbers(&[(NumberLabel::Work, "89347509")]),
+        );
+        assert_eq!(
+            summary,
+            ImportSummary {
+                created: 1,
+                skipped: 1,
+                ..ImportSummary::default()
+            }
+        );
+        assert_eq!(arnold, existing);
+
+        let (summary, arnold) = import(
+            OnConflict::Overwrite,
+            numbers(&[(NumberLabel::Work, "89347509")]),
+        );
+        assert_eq!(summary.overwritten, 1);
+        assert_eq!(arnold, numbers(&[(NumberLabel::Work, "89347509")]));
+
+        let mut imported = with_addresses(entry("903795", "89347509"));
+        imported.numbers[1].preferred = true;
+        let (summary, arnold) = import(OnConflict::Merge, imported);
+        assert_eq!(summary.merged, 1);
+        let mut expected = existing.clone();
+        expected
+            .numbers
+            .push(PhoneNumber::new(NumberLabel::Work, "89347509"));
+        expected.numbers[1].preferred = true;
+        assert_eq!(arnold, expected);
+    }
+
+    #[test]
+    fn audit_log_keeps_every_change() {
+        let phone_book = PhoneBookDB::new(None).unwrap();
+        let arnold = phone_book
+            .insert_contact("Arnold".to_owned(), entry("903795", "4235"))
+            .unwrap();
+        phone_book
+            .modify_entry("Arnold".to_owned(), entry("903795", "89347509"))
+            .unwrap();
+        assert!(phone_book
+            .modify_contact(arnold, entry("12", "4235"))
+            .is_err());
+        phone_book
+            .rename_contact(arnold, "Arnold Baker".to_owned())
+            .unwrap();
+        phone_book.remove_contact(arnold).unwrap();
+        phone_book
+            .insert_contact("Arnold".to_owned(), PhoneEntry::default())
+            .unwrap();
+
+        let log = phone_book.read_audit_log(arnold).unwrap();
+        let operations = log
+            .iter()
+            .map(|record| record.operation)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            operations,
+            [
+                AuditOperation::Insert,
+                AuditOperation::Modify,
+                AuditOperation::Rename,
+                AuditOperation::Remove,
+            ],
+            "failed changes are not logged"
+        );
+        assert!(log
+            .windows(2)
+            .all(|pair| pair[0].sequence < pair[1].sequence));
+        assert_eq!(log[0].old, None);
+        assert_eq!(log[1].old.as_ref().unwrap().entry, entry("903795", "4235"));
+        assert_eq!(
+            log[1].new.as_ref().unwrap().entry,
+            entry("903795", "89347509")
+        );
+        assert_eq!(log[2].new.as_ref().unwrap().name, "Arnold Baker");
+        assert_eq!(log[3].old.as_ref().unwrap().name, "Arnold Baker");
+        assert_eq!(log[3].new, None);
+        assert!(log.iter().all(|record| record.contact_id == arnold
+            && record.timestamp.ends_with('Z')
+            && !record.os_user.is_empty()));
+
+        assert_eq!(
+            phone_book.read_audit_log_by_name("Arnold").unwrap().len(),
+            5,
+            "the removed contact and the new one had the name"
+        );
+        assert_eq!(
+            phone_book
+                .read_audit_log_by_name("Arnold Baker")
+                .unwrap()
+                .len(),
+            4
+        );
+
+        assert!(phone_book
+            .conn
+            .execute("DELETE FROM audit_log", ())
+            .is_err());
+        assert!(phone_book
+            .conn
+            .execute("UPDATE audit_log SET os_user = 'nobody'", ())
+            .is_err());
+    }
 }
@@ -0,0 +1,334 @@
+//! Directories for IP desk phones, which load a remote phone book from an XML file.
+//!
+//! Each vendor has its own format and limits:
+//! - Cisco `CiscoIPPhoneDirectory` has no number types, so every number is an entry of its own,
+//!   named after the contact and the label. A directory holds at most 32 entries, and names
+//!   and numbers at most 32 characters each.
+//! - Yealink `YealinkIPPhoneDirectory` entries have an office, a mobile and an other number.
+//! - Grandstream `AddressBook` contacts have any number of work, home and cell numbers.
+//!
+//! Numbers are written the way they were typed, without formatting or extension,
+//! because phones dial them as they are. Whatever does not fit is left out and counted.
+
+use crate::{
+    entry::{Contact, NumberLabel, PhoneNumber},
+    error::PhoneBookError,
+    normalize,
+};
+use std::io::Write;
+
+/// The directory format of a desk phone vendor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeskPhone {
+    Cisco,
+    Yealink,
+    Grandstream,
+}
+
+const CISCO_MAX_ENTRIES: usize = 32;
+const CISCO_MAX_LENGTH: usize = 32;
+const YEALINK_MAX_ENTRIES: usize = 1000;
+const GRANDSTREAM_MAX_CONTACTS: usize = 2000;
+
+/// Writes the directory of all contacts and returns how many numbers had to be left out.
+pub fn write_directory(
+    contacts: &[Contact],
+    mut writer: impl Write,
+    desk_phone: DeskPhone,
+) -> Result<usize, PhoneBookError> {
+    // Contacts without numbers cannot be dialled, so they are not worth a line on a phone.
+    let contacts = contacts
+        .iter()
+        .filter(|contact| contact.entry.numbers.iter().any(has_digits))
+        .collect::<Vec<_>>();
+
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    let left_out = match desk_phone {
+        DeskPhone::Cisco => write_cisco(&contacts, &mut writer)?,
+        DeskPhone::Yealink => write_yealink(&contacts, &mut writer)?,
+        DeskPhone::Grandstream => write_grandstream(&contacts, &mut writer)?,
+    };
+    writer.flush()?;
+
+    Ok(left_out)
+}
+
+fn write_cisco(contacts: &[&Contact], writer: &mut impl Write) -> Result<usize, PhoneBookError> {
+    let mut entries = Vec::new();
+    for contact in contacts {
+        let numbers = dialable(contact);
+        for number in &numbers {
+            let name = if numbers.len() == 1 {
+                contact.name.clone()
+            } else {
+                format!("{} ({})", contact.name, number.label)
+            };
+            entries.push((truncate(&name, CISCO_MAX_LENGTH), dial_string(number)));
+        }
+    }
+    // A number cut short would dial someone else, so long numbers are left out instead.
+    let (entries, too_long) = entries
+        .into_iter()
+        .partition::<Vec<_>, _>(|(_, number)| number.chars().count() <= CISCO_MAX_LENGTH);
+    let left_out = too_long.len() + entries.len().saturating_sub(CISCO_MAX_ENTRIES);
+
+    writeln!(writer, "<CiscoIPPhoneDirectory>")?;
+    writeln!(writer, "  <Title>Phone Book</Title>")?;
+    writeln!(writer, "  <Prompt>Select a contact</Prompt>")?;
+    for (name, number) in entries.iter().take(CISCO_MAX_ENTRIES) {
+        writeln!(writer, "  <DirectoryEntry>")?;
+        writeln!(writer, "    <Name>{}</Name>", escape(name))?;
+        writeln!(writer, "    <Telephone>{}</Telephone>", escape(number))?;
+        writeln!(writer, "  </DirectoryEntry>")?;
+    }
+    writeln!(writer, "</CiscoIPPhoneDirectory>")?;
+
+    Ok(left_out)
+}
+
+/// The three numbers a Yealink entry has room for.
+const YEALINK_SLOTS: [&str; 3] = ["Office Number", "Mobile Number", "Other Number"];
+
+fn write_yealink(contacts: &[&Contact], writer: &mut impl Write) -> Result<usize, PhoneBookError> {
+    let mut left_out = 0;
+
+    writeln!(writer, "<YealinkIPPhoneDirectory>")?;
+    for (position, contact) in contacts.iter().enumerate() {
+        let numbers = dialable(contact);
+        if position >= YEALINK_MAX_ENTRIES {
+            left_out += numbers.len();
+            continue;
+        }
+
+        let mut slots: [Option<String>; 3] = Default::default();
+        for number in numbers {
+            let slot = match number.label {
+                NumberLabel::Work | NumberLabel::Main => 0,
+                NumberLabel::Mobile => 1,
+                _ => 2,
+            };
+            // A second number of a kind takes the other slot while it is free.
+            match [slot, 2].into_iter().find(|&slot| slots[slot].is_none()) {
+                Some(slot) => slots[slot] = Some(dial_string(number)),
+                None => left_out += 1,
+            }
+        }
+
+        writeln!(writer, "  <DirectoryEntry>")?;
+        writeln!(writer, "    <Name>{}</Name>", escape(&contact.name))?;
+        for (label, number) in YEALINK_SLOTS.iter().zip(&slots) {
+            if let Some(number) = number {
+                writeln!(
+                    writer,
+                    r#"    <Telephone label="{label}">{}</Telephone>"#,
+                    escape(number)
+                )?;
+            }
+        }
+        writeln!(writer, "  </DirectoryEntry>")?;
+    }
+    writeln!(writer, "</YealinkIPPhoneDirectory>")?;
+
+    Ok(left_out)
+}
+
+fn write_grandstream(
+    contacts: &[&Contact],
+    writer: &mut impl Write,
+) -> Result<usize, PhoneBookError> {
+    let mut left_out = 0;
+
+    writeln!(writer, "<AddressBook>")?;
+    for (position, contact) in contacts.iter().enumerate() {
+        let numbers = dialable(contact);
+        if position >= GRANDSTREAM_MAX_CONTACTS {
+            left_out += numbers.len();
+            continue;
+        }
+
+        // Grandstream splits names, but a single name is what the phone shows best.
+        writeln!(writer, "  <Contact>")?;
+        writeln!(
+            writer,
+            "    <FirstName>{}</FirstName>",
+            escape(&contact.name)
+        )?;
+        writeln!(writer, "    <LastName></LastName>")?;
+        for number in numbers {
+            let phone_type = match number.label {
+                NumberLabel::Mobile => "Cell",
+                NumberLabel::Home => "Home",
+                _ => "Work",
+            };
+            writeln!(writer, r#"    <Phone type="{phone_type}">"#)?;
+            writeln!(
+                writer,
+                "      <phonenumber>{}</phonenumber>",
+                escape(&dial_string(number))
+            )?;
+            writeln!(writer, "      <accountindex>1</accountindex>")?;
+            writeln!(writer, "    </Phone>")?;
+        }
+        writeln!(writer, "  </Contact>")?;
+    }
+    writeln!(writer, "</AddressBook>")?;
+
+    Ok(left_out)
+}
+
+/// The numbers of a contact that can be dialled, the preferred one first.
+fn dialable(contact: &Contact) -> Vec<&PhoneNumber> {
+    let mut numbers = contact
+        .entry
+        .numbers
+        .iter()
+        .filter(|number| has_digits(number))
+        .collect::<Vec<_>>();
+    numbers.sort_by_key(|number| !number.preferred);
+    numbers
+}
+
+fn has_digits(number: &PhoneNumber) -> bool {
+    dial_string(number).chars().any(|c| c.is_ascii_digit())
+}
+
+/// The number without separators or extension, like "+442079460958".
+fn dial_string(number: &PhoneNumber) -> String {
+    let (number, _extension) = normalize::split_extension(&number.number);
+    number
+        .chars()
+        .filter(|&c| !normalize::is_separator(c))
+        .collect()
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    text.chars().take(max_length).collect()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_directory, DeskPhone};
+    use crate::entry::{Contact, ContactId, NumberLabel, PhoneEntry, PhoneNumber};
+    use uuid::Uuid;
+
+    fn contact(id: i64, name: &str, numbers: &[(NumberLabel, &str)]) -> Contact {
+        Contact {
+            id: ContactId(id),
+            uuid: Uuid::new_v4(),
+            name: name.to_owned(),
+            entry: PhoneEntry {
+                numbers: numbers
+                    .iter()
+                    .map(|(label, number)| PhoneNumber::new(label.clone(), *number))
+                    .collect(),
+                ..PhoneEntry::default()
+            },
+        }
+    }
+
+    fn contacts() -> Vec<Contact> {
+        let mut jack = contact(
+            2,
+            "Jack & Jill",
+            &[
+                (NumberLabel::Work, "+44 20 7946 0958 ext. 12"),
+                (NumberLabel::Mobile, "07700 900123"),
+                (NumberLabel::Home, "(0161) 496 0000"),
+                (NumberLabel::Main, "+44 113 496 0000"),
+            ],
+        );
+        jack.entry.numbers[1].preferred = true;
+        vec![
+            contact(1, "Arnold", &[(NumberLabel::Work, "+1 212-555-0100")]),
+            jack,
+            contact(3, "No Numbers", &[]),
+        ]
+    }
+
+    fn directory(contacts: &[Contact], desk_phone: DeskPhone) -> (String, usize) {
+        let mut xml = Vec::new();
+        let left_out = write_directory(contacts, &mut xml, desk_phone).unwrap();
+        (String::from_utf8(xml).unwrap(), left_out)
+    }
+
+    #[test]
+    fn cisco_has_an_entry_per_number() {
+        let (xml, left_out) = directory(&contacts(), DeskPhone::Cisco);
+
+        assert_eq!(left_out, 0);
+        assert!(xml
+            .starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<CiscoIPPhoneDirectory>\n"));
+        assert!(xml.contains(
+            "  <DirectoryEntry>\n    <Name>Arnold</Name>\n    <Telephone>+12125550100</Telephone>\n"
+        ));
+        // The preferred number comes first.
+        assert!(xml.contains(
+            "<Name>Jack &amp; Jill (mobile)</Name>\n    <Telephone>07700900123</Telephone>\n  </DirectoryEntry>\n  <DirectoryEntry>\n    <Name>Jack &amp; Jill (work)</Name>\n    <Telephone>+442079460958</Telephone>"
+        ));
+        assert!(!xml.contains("No Numbers"));
+        assert_eq!(xml.matches("<DirectoryEntry>").count(), 5);
+    }
+
+    #[test]
+    fn cisco_keeps_to_its_limits() {
+        let many = (0..40)
+            .map(|id| {
+                contact(
+                    id,
+                    "A name much longer than thirty-two characters",
+                    &[(NumberLabel::Mobile, "07700 900123")],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let (xml, left_out) = directory(&many, DeskPhone::Cisco);
+
+        assert_eq!(left_out, 8);
+        assert_eq!(xml.matches("<DirectoryEntry>").count(), 32);
+        assert!(xml.contains("<Name>A name much longer than thirty-t</Name>"));
+    }
+
+    #[test]
+    fn yealink_fills_office_mobile_and_other() {
+        let (xml, left_out) = directory(&contacts(), DeskPhone::Yealink);
+
+        // The main number finds both the office and the other slot taken.
+        assert_eq!(left_out, 1);
+        assert!(xml.contains(
+            "  <DirectoryEntry>\n    <Name>Jack &amp; Jill</Name>\n\
+             \x20   <Telephone label=\"Office Number\">+442079460958</Telephone>\n\
+             \x20   <Telephone label=\"Mobile Number\">07700900123</Telephone>\n\
+             \x20   <Telephone label=\"Other Number\">01614960000</Telephone>\n\
+             \x20 </DirectoryEntry>\n"
+        ));
+        assert!(xml.ends_with("</YealinkIPPhoneDirectory>\n"));
+    }
+
+    #[test]
+    fn grandstream_types_every_number() {
+        let (xml, left_out) = directory(&contacts(), DeskPhone::Grandstream);
+
+        assert_eq!(left_out, 0);
+        assert_eq!(xml.matches("<Contact>").count(), 2);
+        assert_eq!(xml.matches("<Phone type=\"Work\">").count(), 3);
+        assert!(xml.contains(
+            "    <Phone type=\"Cell\">\n      <phonenumber>07700900123</phonenumber>\n      <accountindex>1</accountindex>\n    </Phone>\n"
+        ));
+        assert!(xml.contains("<Phone type=\"Home\">\n      <phonenumber>01614960000</phonenumber>"));
+    }
+}
@@ -1,5 +1,331 @@
-#[derive(Debug, PartialEq, Eq, Clone)]
+use crate::normalize::{self, Country};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{convert::Infallible, fmt, str::FromStr};
+use uuid::Uuid;
+
+/// What kind of number a phone number is.
+/// Serialized as its name, like the database stores it.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub enum NumberLabel {
+    Mobile,
+    Work,
+    Home,
+    Fax,
+    Pager,
+    /// The main number or switchboard of an organisation.
+    Main,
+    #[default]
+    Other,
+    Custom(String),
+}
+
+impl NumberLabel {
+    /// The labels every user gets without having to type them.
+    pub const PREDEFINED: [NumberLabel; 7] = [
+        NumberLabel::Mobile,
+        NumberLabel::Work,
+        NumberLabel::Home,
+        NumberLabel::Fax,
+        NumberLabel::Pager,
+        NumberLabel::Main,
+        NumberLabel::Other,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            NumberLabel::Mobile => "mobile",
+            NumberLabel::Work => "work",
+            NumberLabel::Home => "home",
+            NumberLabel::Fax => "fax",
+            NumberLabel::Pager => "pager",
+            NumberLabel::Main => "main",
+            NumberLabel::Other => "other",
+            NumberLabel::Custom(label) => label,
+        }
+    }
+}
+
+impl FromStr for NumberLabel {
+    type Err = Infallible;
+
+    /// Predefined labels are matched ignoring case, anything else becomes a custom label.
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        let label = label.trim();
+        Ok(NumberLabel::PREDEFINED
+            .into_iter()
+            .find(|predefined| predefined.as_str().eq_ignore_ascii_case(label))
+            .unwrap_or_else(|| NumberLabel::Custom(label.to_owned())))
+    }
+}
+
+impl fmt::Display for NumberLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for NumberLabel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for NumberLabel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Ok(label) = String::deserialize(deserializer)?.parse();
+        Ok(label)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PhoneNumber {
+    #[serde(default)]
+    pub label: NumberLabel,
+    pub number: String,
+    /// Whether this is the number to call when no particular one is asked for.
+    #[serde(default)]
+    pub preferred: bool,
+}
+
+impl PhoneNumber {
+    pub fn new(label: NumberLabel, number: impl Into<String>) -> PhoneNumber {
+        PhoneNumber {
+            label,
+            number: number.into(),
+            preferred: false,
+        }
+    }
+}
+
+/// Whose an email or postal address is.
+/// Serialized as its name, like the database stores it.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub enum AddressLabel {
+    Home,
+    Work,
+    #[default]
+    Other,
+    Custom(String),
+}
+
+impl AddressLabel {
+    /// The labels every user gets without having to type them.
+    pub const PREDEFINED: [AddressLabel; 3] =
+        [AddressLabel::Home, AddressLabel::Work, AddressLabel::Other];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            AddressLabel::Home => "home",
+            AddressLabel::Work => "work",
+            AddressLabel::Other => "other",
+            AddressLabel::Custom(label) => label,
+        }
+    }
+}
+
+impl FromStr for AddressLabel {
+    type Err = Infallible;
+
+    /// Predefined labels are matched ignoring case, anything else becomes a custom label.
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        let label = label.trim();
+        Ok(AddressLabel::PREDEFINED
+            .into_iter()
+            .find(|predefined| predefined.as_str().eq_ignore_ascii_case(label))
+            .unwrap_or_else(|| AddressLabel::Custom(label.to_owned())))
+    }
+}
+
+impl fmt::Display for AddressLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for AddressLabel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AddressLabel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Ok(label) = String::deserialize(deserializer)?.parse();
+        Ok(label)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailAddress {
+    #[serde(default)]
+    pub label: AddressLabel,
+    pub address: String,
+}
+
+/// A postal address split into its parts. Parts that are not known are left empty.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostalAddress {
+    pub label: AddressLabel,
+    pub street: String,
+    pub city: String,
+    pub region: String,
+    pub postcode: String,
+    pub country: String,
+}
+
+impl fmt::Display for PostalAddress {
+    /// Writes the non-empty parts on one line, separated by commas.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            &self.street,
+            &self.city,
+            &self.region,
+            &self.postcode,
+            &self.country,
+        ];
+        let line = parts
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        f.write_str(&line)
+    }
+}
+
+impl PostalAddress {
+    /// Reads "STREET;CITY;REGION;POSTCODE;COUNTRY" as written by `parts`.
+    /// Trailing parts may be left out. Returns None if there are more than five parts.
+    pub fn from_parts(label: AddressLabel, parts: &str) -> Option<PostalAddress> {
+        let mut parts = parts.split(';').map(|part| part.trim().to_owned());
+        let address = PostalAddress {
+            label,
+            street: parts.next().unwrap_or_default(),
+            city: parts.next().unwrap_or_default(),
+            region: parts.next().unwrap_or_default(),
+            postcode: parts.next().unwrap_or_default(),
+            country: parts.next().unwrap_or_default(),
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(address),
+        }
+    }
+
+    /// All parts separated by ";", keeping empty ones so that `from_parts` can read them back.
+    pub fn parts(&self) -> String {
+        [
+            &self.street,
+            &self.city,
+            &self.region,
+            &self.postcode,
+            &self.country,
+        ]
+        .map(String::as_str)
+        .join(";")
+    }
+}
+
+/// Everything known about a contact apart from its name, in the order it is shown.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PhoneEntry {
-    pub mobile: String,
-    pub work: String,
+    pub numbers: Vec<PhoneNumber>,
+    pub emails: Vec<EmailAddress>,
+    pub addresses: Vec<PostalAddress>,
+}
+
+impl PhoneEntry {
+    /// Appends the details of `other` that this entry does not have yet.
+    /// Numbers are the same when their E.164 forms are, so differently written numbers are not repeated.
+    /// The preferred number of this entry stays preferred.
+    pub fn merge(&mut self, other: PhoneEntry, country: Option<&Country>) {
+        let has_preferred = self.numbers.iter().any(|number| number.preferred);
+        for mut number in other.numbers {
+            let e164 = normalize::to_e164(&number.number, country);
+            let known = self.numbers.iter().any(|known| {
+                known.number == number.number
+                    || e164.is_some() && normalize::to_e164(&known.number, country) == e164
+            });
+            if !known {
+                number.preferred &= !has_preferred;
+                self.numbers.push(number);
+            }
+        }
+
+        for email in other.emails {
+            let known = self
+                .emails
+                .iter()
+                .any(|known| known.address.eq_ignore_ascii_case(&email.address));
+            if !known {
+                self.emails.push(email);
+            }
+        }
+
+        for address in other.addresses {
+            if !self.addresses.contains(&address) {
+                self.addresses.push(address);
+            }
+        }
+    }
+}
+
+/// Identifies a contact in the database for as long as it exists, even when it is renamed.
+/// IDs of removed contacts are never handed out again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ContactId(pub i64);
+
+impl fmt::Display for ContactId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A stored contact together with its identity.
+/// `id` is local to one database, `uuid` stays the same when the contact is exported elsewhere.
+/// Serialized with the details of `entry` next to the name.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub id: ContactId,
+    pub uuid: Uuid,
+    pub name: String,
+    #[serde(flatten)]
+    pub entry: PhoneEntry,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{AddressLabel, NumberLabel, PostalAddress};
+
+    #[test]
+    fn labels_parse_case_insensitively() {
+        assert_eq!("Mobile".parse(), Ok(NumberLabel::Mobile));
+        assert_eq!(" FAX ".parse(), Ok(NumberLabel::Fax));
+        assert_eq!(
+            "Switchboard 2".parse(),
+            Ok(NumberLabel::Custom("Switchboard 2".to_owned()))
+        );
+    }
+
+    #[test]
+    fn postal_addresses_skip_empty_parts() {
+        let address = PostalAddress {
+            label: AddressLabel::Work,
+            street: "1 Main Street".to_owned(),
+            city: "Springfield".to_owned(),
+            region: String::new(),
+            postcode: "12345".to_owned(),
+            country: "USA".to_owned(),
+        };
+
+        assert_eq!(
+            address.to_string(),
+            "1 Main Street, Springfield, 12345, USA"
+        );
+    }
 }
@@ -0,0 +1,122 @@
+use crate::validate::Invalid;
+use std::fmt;
+
+/// Everything that can go wrong while working with the phone book.
+#[derive(Debug)]
+pub enum PhoneBookError {
+    /// No contact matches the given name or ID.
+    NotFound(String),
+    /// A contact with the given name already exists.
+    AlreadyExists(String),
+    /// The phone number was rejected, with the reason why.
+    InvalidNumber { number: String, reason: Invalid },
+    /// The country code is not one of `normalize::COUNTRIES`.
+    UnknownCountry(String),
+    /// Reading or writing a file other than the database failed.
+    Io(std::io::Error),
+    /// The underlying SQLite database failed.
+    Storage(rusqlite::Error),
+    /// The database was written by a newer version of this program.
+    SchemaTooNew { found: i64, supported: i64 },
+    /// A schema migration failed and was rolled back.
+    Migration {
+        version: i64,
+        description: &'static str,
+        source: rusqlite::Error,
+    },
+    /// Data coming from outside the database could not be imported.
+    Import(String),
+    /// The database location could not be worked out from the configuration.
+    Config(String),
+    /// Asterisk said something an AGI session did not expect.
+    Agi(String),
+    /// Another program changed the file since the phone book read it.
+    Conflict(String),
+    /// The git repository holding the history of changes could not be used.
+    History(String),
+    /// The store cannot do what was asked, like keeping an audit log.
+    Unsupported(String),
+}
+
+impl fmt::Display for PhoneBookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhoneBookError::NotFound(contact) => write!(f, "contact not found: {contact}"),
+            PhoneBookError::AlreadyExists(name) => {
+                write!(f, "a contact named {name:?} already exists")
+            }
+            PhoneBookError::InvalidNumber { number, reason } => {
+                write!(f, "invalid phone number {number:?}: {reason}")
+            }
+            PhoneBookError::UnknownCountry(code) => write!(f, "unknown country code {code:?}"),
+            PhoneBookError::Io(source) => write!(f, "{source}"),
+            PhoneBookError::Storage(source) => write!(f, "database error: {source}"),
+            PhoneBookError::SchemaTooNew { found, supported } => write!(
+                f,
+                "the database has schema version {found} but this program only supports up to version {supported}"
+            ),
+            PhoneBookError::Migration {
+                version,
+                description,
+                source,
+            } => write!(
+                f,
+                "migration to schema version {version} ({description}) failed: {source}"
+            ),
+            PhoneBookError::Import(message) => write!(f, "import failed: {message}"),
+            PhoneBookError::Config(message) => write!(f, "invalid configuration: {message}"),
+            PhoneBookError::Agi(message) => write!(f, "AGI session failed: {message}"),
+            PhoneBookError::Conflict(file) => write!(
+                f,
+                "{file} was changed by another program since it was read, try again"
+            ),
+            PhoneBookError::History(message) => write!(f, "history failed: {message}"),
+            PhoneBookError::Unsupported(message) => write!(f, "not supported: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for PhoneBookError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PhoneBookError::Io(source) => Some(source),
+            PhoneBookError::Storage(source) | PhoneBookError::Migration { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for PhoneBookError {
+    fn from(err: rusqlite::Error) -> Self {
+        PhoneBookError::Storage(err)
+    }
+}
+
+impl From<std::io::Error> for PhoneBookError {
+    fn from(err: std::io::Error) -> Self {
+        PhoneBookError::Io(err)
+    }
+}
+
+impl From<csv::Error> for PhoneBookError {
+    /// Malformed CSV is reported like any other data that cannot be imported.
+    fn from(err: csv::Error) -> Self {
+        let message = err.to_string();
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => PhoneBookError::Io(err),
+            _ => PhoneBookError::Import(message),
+        }
+    }
+}
+
+impl From<serde_json::Error> for PhoneBookError {
+    /// Malformed JSON is reported like any other data that cannot be imported.
+    fn from(err: serde_json::Error) -> Self {
+        match err.io_error_kind() {
+            Some(_) => PhoneBookError::Io(err.into()),
+            None => PhoneBookError::Import(err.to_string()),
+        }
+    }
+}
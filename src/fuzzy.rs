@@ -0,0 +1,147 @@
+//! Scores how alike two names are, so that mistyped names like "Arnlod" still find "Arnold".
+//!
+//! Names are folded first (see `search::fold`), so case and accents never count as typos.
+//! Two measures are combined, taking whichever is higher:
+//! the edit distance, which suits short names with a letter or two wrong or swapped,
+//! and trigram similarity, which suits long names with a word left out or moved.
+//! A query is also compared with every word of a name, so "Bakre" finds "Arnold Baker".
+
+use crate::search;
+use std::collections::BTreeSet;
+
+/// Candidates scoring below this are too different to be suggested.
+pub const MIN_SCORE: f64 = 0.6;
+
+/// How alike `query` and `name` are, from 0 for nothing in common to 1 for the same name.
+pub fn similarity(query: &str, name: &str) -> f64 {
+    let query = search::fold(query.trim());
+    let name = search::fold(name.trim());
+    if query.is_empty() || name.is_empty() {
+        return 0.0;
+    }
+
+    let whole = edit_similarity(&query, &name).max(trigram_similarity(&query, &name));
+    // A query of one word is compared with each word of the name on its own.
+    let best_word = if query.contains(char::is_whitespace) {
+        0.0
+    } else {
+        name.split_whitespace()
+            .map(|word| edit_similarity(&query, word))
+            .fold(0.0, f64::max)
+    };
+    whole.max(best_word)
+}
+
+/// Scores every candidate against `query` and returns the ones scoring at least `MIN_SCORE`,
+/// best first. Equal scores keep the order the candidates came in.
+pub fn rank<T>(query: &str, candidates: impl IntoIterator<Item = (T, String)>) -> Vec<(T, f64)> {
+    let mut ranked = candidates
+        .into_iter()
+        .map(|(candidate, name)| (candidate, similarity(query, &name)))
+        .filter(|(_, score)| *score >= MIN_SCORE)
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranked
+}
+
+/// 1 minus the edit distance relative to the longer string.
+fn edit_similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(a, b) as f64 / longest as f64
+}
+
+/// The number of characters inserted, removed, replaced or swapped with their neighbour
+/// to turn `a` into `b` (the optimal string alignment distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+
+    // Three rows of the distance matrix are enough: swaps look two rows back.
+    let mut before_previous = vec![0; b.len() + 1];
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before_previous, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// The Dice coefficient of the trigrams of both strings, each word padded with spaces
+/// so that the start and end of words count too.
+fn trigram_similarity(a: &str, b: &str) -> f64 {
+    let a = trigrams(a);
+    let b = trigrams(b);
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    2.0 * a.intersection(&b).count() as f64 / (a.len() + b.len()) as f64
+}
+
+fn trigrams(text: &str) -> BTreeSet<[char; 3]> {
+    let mut trigrams = BTreeSet::new();
+    for word in text.split_whitespace() {
+        let padded = [' ', ' ']
+            .into_iter()
+            .chain(word.chars())
+            .chain([' '])
+            .collect::<Vec<_>>();
+        trigrams.extend(
+            padded
+                .windows(3)
+                .map(|window| [window[0], window[1], window[2]]),
+        );
+    }
+    trigrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, rank, similarity, trigram_similarity, MIN_SCORE};
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(edit_distance("arnold", "arnold"), 0);
+        assert_eq!(edit_distance("arnlod", "arnold"), 1);
+        assert_eq!(edit_distance("arnld", "arnold"), 1);
+        assert_eq!(edit_distance("jack", "jill"), 3);
+        assert_eq!(edit_distance("", "zoë"), 3);
+        assert_eq!(trigram_similarity("arnold baker", "baker arnold"), 1.0);
+    }
+
+    #[test]
+    fn typos_still_score_high() {
+        assert!(similarity("Arnlod", "Arnold") >= 0.8);
+        assert!(similarity("zoe", "Zoë") == 1.0);
+        assert!(similarity("Bakre", "Arnold Baker") >= MIN_SCORE);
+        assert!(similarity("arnold bakr", "Baker, Arnold") >= MIN_SCORE);
+        assert!(similarity("Jack", "Jill") < MIN_SCORE);
+        assert_eq!(similarity("", "Jill"), 0.0);
+    }
+
+    #[test]
+    fn ranks_the_closest_first() {
+        let names = ["Arnie", "Jack", "Arnold", "Arnold Baker"].map(str::to_owned);
+
+        let ranked = rank("Arnlod", names.into_iter().enumerate());
+
+        assert_eq!(
+            ranked.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(ranked[0].1 > MIN_SCORE);
+    }
+}
@@ -0,0 +1,713 @@
+//! A git repository recording every change to the phone book, so that changes can be
+//! looked through and reverted like code.
+//!
+//! The repository holds one `<uuid>.json` file per contact, with the contact as `json_io`
+//! writes it. `Recorded` wraps a store and commits after every change, with a message
+//! saying what changed, like "Add Arnold Baker" or "Rename Arnold to Arnie Baker".
+//! Changes made while the history was not used, by another program or without
+//! `--history`, are committed on their own the next time the history is opened.
+//! Only contacts are recorded, not the default country.
+//!
+//! The repository is worked with through the `git` program, which has to be installed.
+
+use crate::{
+    database::{
+        AuditRecord, ImportSummary, NumberMatch, OnConflict, SearchHit, SimilarContact, StoreChange,
+    },
+    entry::{Contact, ContactId, PhoneEntry},
+    error::PhoneBookError,
+    normalize::Country,
+    search::NameMatch,
+    store::PhoneBookStore,
+    validate,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+use uuid::Uuid;
+
+/// How `log` asks git for commits: one line per commit, with tabs between the parts.
+const LOG_FORMAT: &str = "--format=%h%x09%ad%x09%an%x09%s";
+
+/// One commit of the history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The abbreviated commit hash, which `revert` takes.
+    pub commit: String,
+    pub date: String,
+    pub author: String,
+    pub message: String,
+}
+
+/// The git repository a phone book's history is kept in.
+#[derive(Debug, Clone)]
+pub struct GitHistory {
+    repo: PathBuf,
+    /// Changes that `Recorded` made but could not commit, until `take_warnings` is called.
+    warnings: RefCell<Vec<String>>,
+}
+
+impl GitHistory {
+    /// Opens the repository at `repo`, creating it if needed,
+    /// and commits what changed in `store` since the last commit.
+    pub fn open(
+        repo: impl Into<PathBuf>,
+        store: &dyn PhoneBookStore,
+    ) -> Result<Self, PhoneBookError> {
+        let history = GitHistory {
+            repo: repo.into(),
+            warnings: RefCell::default(),
+        };
+        fs::create_dir_all(&history.repo)?;
+        history.git(&["init", "--quiet"])?;
+        let message = if history.has_commits()? {
+            "Record changes made outside of the history"
+        } else {
+            "Start the history of the phone book"
+        };
+        history.commit(store, message)?;
+        Ok(history)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.repo
+    }
+
+    /// Returns the warnings about changes that were made but not committed, so that they can
+    /// be shown, and forgets them.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.take()
+    }
+
+    /// Wraps `store` so that every change made through it is committed.
+    pub fn record<'a>(&'a self, store: &'a mut dyn PhoneBookStore) -> Recorded<'a> {
+        Recorded {
+            history: self,
+            store,
+        }
+    }
+
+    /// Returns the commits that changed `contact`, or all commits, newest first.
+    pub fn log(&self, contact: Option<&Contact>) -> Result<Vec<Change>, PhoneBookError> {
+        if !self.has_commits()? {
+            return Ok(Vec::new());
+        }
+        let file_name = contact.map(|contact| file_name(contact.uuid));
+        let mut args = vec!["log", LOG_FORMAT, "--date=iso", "--"];
+        args.extend(file_name.as_deref());
+        self.git(&args)?.lines().map(parse_change).collect()
+    }
+
+    /// Undoes one commit, given by its hash: contacts it added are removed, and the ones it
+    /// changed or removed get back what they had before. Removed contacts come back with
+    /// their UUID, so that their history goes on, but under a new ID.
+    /// Fails, changing nothing, when a contact of the commit was changed again later on,
+    /// when a name to give back is taken by another contact by now, or when numbers to give
+    /// back are not accepted with the current default country.
+    /// The undoing is made in one go by the stores that can, and committed as one change.
+    /// Should a store fail halfway all the same, what it made is committed before failing.
+    /// Returns the reverted commit.
+    pub fn revert(
+        &self,
+        store: &mut dyn PhoneBookStore,
+        commit: &str,
+    ) -> Result<Change, PhoneBookError> {
+        // Only hashes are taken, so that nothing passed on to git is read as an option.
+        if commit.is_empty() || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(PhoneBookError::History(format!(
+                "{commit:?} is not a commit hash"
+            )));
+        }
+        let revision = format!("{commit}^{{commit}}");
+        let Ok(commit) = self.git(&["rev-parse", "--verify", "--quiet", &revision]) else {
+            return Err(PhoneBookError::History(format!(
+                "there is no commit {commit} in the history"
+            )));
+        };
+        let commit = commit.trim();
+        let change = parse_change(&self.git(&["log", "-1", LOG_FORMAT, "--date=iso", commit])?)?;
+
+        self.commit(&*store, "Record changes made outside of the history")?;
+        let mut current = store
+            .read_all_contacts()?
+            .into_iter()
+            .map(|contact| (contact.uuid, contact))
+            .collect::<BTreeMap<_, _>>();
+        let mut names = current
+            .values()
+            .map(|contact| (contact.uuid, contact.name.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        let changed_files = self.git(&[
+            "diff-tree",
+            "--root",
+            "--no-commit-id",
+            "-r",
+            "--name-only",
+            commit,
+        ])?;
+        let mut undo = Vec::new();
+        for file_name in changed_files.lines() {
+            let Some(uuid) = contact_uuid(Path::new(file_name)) else {
+                continue;
+            };
+            let before = self.read_contact_at(&format!("{commit}^"), file_name)?;
+            let after = self.read_contact_at(commit, file_name)?;
+            let now = current.remove(&uuid);
+            if let Some(after) = &after {
+                let unchanged = now
+                    .as_ref()
+                    .is_some_and(|now| now.name == after.name && now.entry == after.entry);
+                if !unchanged {
+                    return Err(PhoneBookError::History(format!(
+                        "{} was changed again after {}, revert the later changes first",
+                        after.name, change.commit
+                    )));
+                }
+            }
+            undo.push((before, now));
+        }
+
+        // Contacts are removed first, then removed ones are added back, then the others are
+        // changed back. Every step is checked in that order before any is taken.
+        let mut removals = Vec::new();
+        let mut restores = Vec::new();
+        let mut changes = Vec::new();
+        for step in undo {
+            match step {
+                (None, Some(now)) => removals.push(now),
+                (Some(before), None) => restores.push(before),
+                (Some(before), Some(now)) => changes.push((before, now)),
+                (None, None) => {}
+            }
+        }
+        let default_country = store.default_country();
+        for now in &removals {
+            names.remove(&now.uuid);
+        }
+        for before in &restores {
+            validate::validate_entry(&before.entry, default_country)?;
+            names.insert(before.uuid, before.name.clone());
+        }
+        for (before, now) in &changes {
+            if before.entry != now.entry {
+                validate::validate_new_numbers(&before.entry, &now.entry, default_country)?;
+            }
+            if before.name != now.name {
+                let taken = names
+                    .iter()
+                    .any(|(uuid, name)| *name == before.name && *uuid != now.uuid);
+                if taken {
+                    return Err(PhoneBookError::AlreadyExists(before.name.clone()));
+                }
+                names.insert(now.uuid, before.name.clone());
+            }
+        }
+
+        let mut steps = removals
+            .into_iter()
+            .map(|now| StoreChange::Remove(now.id))
+            .collect::<Vec<_>>();
+        if !restores.is_empty() {
+            steps.push(StoreChange::Copy(restores));
+        }
+        for (before, now) in changes {
+            steps.push(StoreChange::Edit {
+                id: now.id,
+                entry: (before.entry != now.entry).then_some(before.entry),
+                new_name: (before.name != now.name).then_some(before.name),
+            });
+        }
+        // A store that cannot take all steps at once may fail halfway,
+        // so what it did take is committed either way.
+        let result = store.apply_changes(steps);
+        let message = match result {
+            Ok(()) => format!("Revert \"{}\"", change.message),
+            Err(_) => format!("Revert part of \"{}\"", change.message),
+        };
+        self.commit(&*store, &message)?;
+        result.map(|()| change)
+    }
+
+    /// Writes every contact of `store` to the repository and commits, unless nothing changed.
+    fn commit(&self, store: &dyn PhoneBookStore, message: &str) -> Result<(), PhoneBookError> {
+        let mut stale = BTreeSet::new();
+        for dir_entry in fs::read_dir(&self.repo)? {
+            let file_path = dir_entry?.path();
+            if contact_uuid(&file_path).is_some() {
+                stale.insert(file_path);
+            }
+        }
+        for contact in store.read_all_contacts()? {
+            let file_path = self.repo.join(file_name(contact.uuid));
+            stale.remove(&file_path);
+            let text = serde_json::to_string_pretty(&contact)? + "\n";
+            if fs::read_to_string(&file_path).ok().as_deref() != Some(text.as_str()) {
+                fs::write(&file_path, text)?;
+            }
+        }
+        for file_path in stale {
+            fs::remove_file(file_path)?;
+        }
+
+        self.git(&["add", "--all", "."])?;
+        if self
+            .run_git(&["diff", "--cached", "--quiet"])?
+            .status
+            .success()
+        {
+            return Ok(());
+        }
+        self.git(&["commit", "--quiet", "--message", message])?;
+        Ok(())
+    }
+
+    /// Reads a contact file as it was in `revision`, or None if it did not exist then.
+    fn read_contact_at(
+        &self,
+        revision: &str,
+        file_name: &str,
+    ) -> Result<Option<Contact>, PhoneBookError> {
+        let output = self.run_git(&["show", &format!("{revision}:{file_name}")])?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        serde_json::from_slice(&output.stdout)
+            .map(Some)
+            .map_err(|err| PhoneBookError::History(format!("{file_name} in {revision}: {err}")))
+    }
+
+    fn has_commits(&self) -> Result<bool, PhoneBookError> {
+        Ok(self
+            .run_git(&["rev-parse", "--verify", "--quiet", "HEAD"])?
+            .status
+            .success())
+    }
+
+    /// Runs git in the repository and returns what it printed, failing if git did.
+    fn git(&self, args: &[&str]) -> Result<String, PhoneBookError> {
+        let output = self.run_git(args)?;
+        if !output.status.success() {
+            return Err(PhoneBookError::History(format!(
+                "git {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn run_git(&self, args: &[&str]) -> Result<Output, PhoneBookError> {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(&self.repo).args(args);
+        // Without an email address configured git refuses to commit, so one is made up
+        // from the user name. A configured address still wins over EMAIL.
+        if std::env::var_os("EMAIL").is_none() {
+            let user = std::env::var("USER").unwrap_or_else(|_| "phonebook".to_owned());
+            command.env("EMAIL", format!("{user}@localhost"));
+        }
+        command
+            .output()
+            .map_err(|err| PhoneBookError::History(format!("git could not be started: {err}")))
+    }
+}
+
+fn file_name(uuid: Uuid) -> String {
+    format!("{uuid}.json")
+}
+
+/// The UUID of the contact a file of the repository holds, or None for other files.
+fn contact_uuid(file_path: &Path) -> Option<Uuid> {
+    if file_path.extension()? != "json" {
+        return None;
+    }
+    Uuid::parse_str(file_path.file_stem()?.to_str()?).ok()
+}
+
+fn parse_change(line: &str) -> Result<Change, PhoneBookError> {
+    let mut parts = line.trim_end_matches('\n').splitn(4, '\t');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(commit), Some(date), Some(author), Some(message)) => Ok(Change {
+            commit: commit.to_owned(),
+            date: date.to_owned(),
+            author: author.to_owned(),
+            message: message.to_owned(),
+        }),
+        _ => Err(PhoneBookError::History(format!(
+            "unexpected git log line {line:?}"
+        ))),
+    }
+}
+
+/// Names the kinds of details that differ, like "numbers and emails".
+fn changed_details(before: &PhoneEntry, after: &PhoneEntry) -> String {
+    let kinds = [
+        ("numbers", before.numbers != after.numbers),
+        ("emails", before.emails != after.emails),
+        ("addresses", before.addresses != after.addresses),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(kind, _)| kind)
+    .collect::<Vec<_>>();
+    match kinds.as_slice() {
+        [] => "details".to_owned(),
+        [kind] => (*kind).to_owned(),
+        [kinds @ .., last] => format!("{} and {last}", kinds.join(", ")),
+    }
+}
+
+/// A store whose changes are committed to a `GitHistory`, one commit per change.
+/// When committing fails the change is kept all the same: a warning is left for
+/// `GitHistory::take_warnings`, and the change is committed together with the next one.
+pub struct Recorded<'a> {
+    history: &'a GitHistory,
+    store: &'a mut dyn PhoneBookStore,
+}
+
+impl Recorded<'_> {
+    /// Commits a change that was already made, warning instead of failing when git does.
+    fn commit(&self, message: &str) {
+        if let Err(err) = self.history.commit(&*self.store, message) {
+            self.history.warnings.borrow_mut().push(format!(
+                "\"{message}\" was made but not committed to the history: {err}"
+            ));
+        }
+    }
+}
+
+impl PhoneBookStore for Recorded<'_> {
+    fn file_path(&self) -> Option<&Path> {
+        self.store.file_path()
+    }
+
+    fn default_country(&self) -> Option<&'static Country> {
+        self.store.default_country()
+    }
+
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        self.store.set_default_country(code)
+    }
+
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        let message = format!("Add {name}");
+        let id = self.store.insert_contact(name, entry)?;
+        self.commit(&message);
+        Ok(id)
+    }
+
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        let ids = self.store.copy_contacts(contacts)?;
+        let names = contacts
+            .iter()
+            .map(|contact| contact.name.as_str())
+            .collect::<Vec<_>>();
+        self.commit(&format!("Add {}", names.join(", ")));
+        Ok(ids)
+    }
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        self.store.read_contact(id)
+    }
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        self.store.read_all_contacts()
+    }
+
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        let previous = self.store.modify_contact(id, entry.clone())?;
+        let name = match self.store.read_contact(id)? {
+            Some(contact) => contact.name,
+            None => id.to_string(),
+        };
+        let message = format!(
+            "Change the {} of {name}",
+            changed_details(&previous, &entry)
+        );
+        self.commit(&message);
+        Ok(previous)
+    }
+
+    /// The details and the name change in one commit.
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        let Some(contact) = self.store.read_contact(id)? else {
+            return Err(PhoneBookError::NotFound(id.to_string()));
+        };
+        let changed = entry
+            .as_ref()
+            .map(|entry| changed_details(&contact.entry, entry));
+        let message = match (changed, &new_name) {
+            (Some(changed), Some(new_name)) => {
+                format!(
+                    "Rename {} to {new_name} and change the {changed}",
+                    contact.name
+                )
+            }
+            (Some(changed), None) => format!("Change the {changed} of {}", contact.name),
+            (None, Some(new_name)) => format!("Rename {} to {new_name}", contact.name),
+            (None, None) => return Ok(()),
+        };
+        self.store.edit_contact(id, entry, new_name)?;
+        self.commit(&message);
+        Ok(())
+    }
+
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        let removed = self.store.remove_contact(id)?;
+        self.commit(&format!("Remove {}", removed.name));
+        Ok(removed)
+    }
+
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        let Some(contact) = self.store.read_contact(id)? else {
+            return Err(PhoneBookError::NotFound(id.to_string()));
+        };
+        let message = format!("Rename {} to {new_name}", contact.name);
+        self.store.rename_contact(id, new_name)?;
+        self.commit(&message);
+        Ok(())
+    }
+
+    /// The changes are committed together, and so is what a store that makes them one by
+    /// one made before failing.
+    fn apply_changes(&mut self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        let message = format!("Make {} changes", changes.len());
+        let result = self.store.apply_changes(changes);
+        self.commit(&message);
+        result
+    }
+
+    fn read_contacts_by_name(&self, name: &str) -> Result<Vec<Contact>, PhoneBookError> {
+        self.store.read_contacts_by_name(name)
+    }
+
+    fn search_contacts(
+        &self,
+        text: &str,
+        name_match: NameMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        self.store.search_contacts(text, name_match)
+    }
+
+    fn search_full_text(
+        &self,
+        query: &str,
+        marks: (&str, &str),
+    ) -> Result<Vec<SearchHit>, PhoneBookError> {
+        self.store.search_full_text(query, marks)
+    }
+
+    fn find_similar_names(&self, name: &str) -> Result<Vec<SimilarContact>, PhoneBookError> {
+        self.store.find_similar_names(name)
+    }
+
+    fn find_contacts_by_number(
+        &self,
+        number: &str,
+        number_match: NumberMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        self.store.find_contacts_by_number(number, number_match)
+    }
+
+    /// The whole import is one commit.
+    fn import_contacts(
+        &mut self,
+        contacts: Vec<(String, String, PhoneEntry)>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, PhoneBookError> {
+        let summary = self.store.import_contacts(contacts, on_conflict)?;
+        let message = format!(
+            "Import {} new contacts, overwrite {} and merge {}",
+            summary.created, summary.overwritten, summary.merged
+        );
+        self.commit(&message);
+        Ok(summary)
+    }
+
+    fn read_audit_log_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<Vec<AuditRecord>>, PhoneBookError> {
+        self.store.read_audit_log_by_name(name)
+    }
+
+    fn export_copy(&self, file_path: &Path) -> Result<(), PhoneBookError> {
+        self.store.export_copy(file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GitHistory;
+    use crate::{
+        entry::{NumberLabel, PhoneEntry, PhoneNumber},
+        error::PhoneBookError,
+        store::{MemoryStore, PhoneBookStore},
+    };
+    use std::fs;
+
+    fn entry(number: &str) -> PhoneEntry {
+        PhoneEntry {
+            numbers: vec![PhoneNumber::new(NumberLabel::Work, number)],
+            ..PhoneEntry::default()
+        }
+    }
+
+    fn messages(history: &GitHistory) -> Vec<String> {
+        history
+            .log(None)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.message)
+            .collect()
+    }
+
+    #[test]
+    fn commits_every_change_and_reverts_them() {
+        let repo =
+            std::env::temp_dir().join(format!("phone_book_history_{}", uuid::Uuid::new_v4()));
+        let mut store = MemoryStore::new();
+        let arnold = store
+            .insert_contact("Arnold Baker".to_owned(), entry("+44 20 7946 0958"))
+            .unwrap();
+        let arnold_uuid = store.read_contact(arnold).unwrap().unwrap().uuid;
+        let history = GitHistory::open(&repo, &store).unwrap();
+
+        let mut recorded = history.record(&mut store);
+        let zoe = recorded
+            .insert_contact("Zoe".to_owned(), entry("+1 212 555 0100"))
+            .unwrap();
+        recorded
+            .modify_entry("Zoe".to_owned(), entry("+1 212 555 0199"))
+            .unwrap();
+        recorded.rename_contact(zoe, "Zoë".to_owned()).unwrap();
+        recorded.remove_entry("Arnold Baker").unwrap();
+        assert_eq!(
+            messages(&history),
+            [
+                "Remove Arnold Baker",
+                "Rename Zoe to Zoë",
+                "Change the numbers of Zoe",
+                "Add Zoe",
+                "Start the history of the phone book",
+            ]
+        );
+        let zoe_contact = store.read_contact(zoe).unwrap().unwrap();
+        assert_eq!(history.log(Some(&zoe_contact)).unwrap().len(), 3);
+
+        let log = history.log(None).unwrap();
+        let change = history.revert(&mut store, &log[0].commit).unwrap();
+        assert_eq!(change.message, "Remove Arnold Baker");
+        let arnold = &store.read_contacts_by_name("Arnold Baker").unwrap()[0];
+        assert_eq!(arnold.entry, entry("+44 20 7946 0958"));
+        assert_eq!(arnold.uuid, arnold_uuid);
+        assert!(matches!(
+            history.revert(&mut store, &log[2].commit),
+            Err(PhoneBookError::History(message)) if message.contains("changed again")
+        ));
+        history.revert(&mut store, &log[1].commit).unwrap();
+        history.revert(&mut store, &log[2].commit).unwrap();
+        assert_eq!(store.read_contact(zoe).unwrap().unwrap().name, "Zoe");
+        assert_eq!(
+            store.read_contact(zoe).unwrap().unwrap().entry,
+            entry("+1 212 555 0100")
+        );
+        assert_eq!(
+            messages(&history)[0],
+            "Revert \"Change the numbers of Zoe\""
+        );
+        assert!(matches!(
+            history.revert(&mut store, "--help"),
+            Err(PhoneBookError::History(_))
+        ));
+
+        store.remove_contact(zoe).unwrap();
+        let history = GitHistory::open(&repo, &store).unwrap();
+        assert_eq!(
+            messages(&history)[0],
+            "Record changes made outside of the history"
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn changes_are_kept_when_committing_fails() {
+        let repo =
+            std::env::temp_dir().join(format!("phone_book_history_{}", uuid::Uuid::new_v4()));
+        let mut store = MemoryStore::new();
+        let history = GitHistory::open(&repo, &store).unwrap();
+        fs::remove_dir_all(&repo).unwrap();
+
+        let id = history
+            .record(&mut store)
+            .insert_contact("Zoe".to_owned(), entry("+1 212 555 0100"))
+            .unwrap();
+        assert_eq!(store.read_contact(id).unwrap().unwrap().name, "Zoe");
+        let warnings = history.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("\"Add Zoe\" was made but not committed"));
+        assert!(history.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn reverts_all_or_nothing() {
+        let repo =
+            std::env::temp_dir().join(format!("phone_book_history_{}", uuid::Uuid::new_v4()));
+        let mut store = MemoryStore::new();
+        store.set_default_country(Some("GB")).unwrap();
+        let ann = store
+            .insert_contact("Ann".to_owned(), PhoneEntry::default())
+            .unwrap();
+        let bob = store
+            .insert_contact("Bob".to_owned(), entry("020 7946 0958"))
+            .unwrap();
+        GitHistory::open(&repo, &store).unwrap();
+
+        // Made without the history, so both are committed at once when it is opened again.
+        store.rename_contact(ann, "Anna".to_owned()).unwrap();
+        store
+            .modify_contact(bob, entry("+44 20 7946 0000"))
+            .unwrap();
+        let history = GitHistory::open(&repo, &store).unwrap();
+        let both = history.log(None).unwrap()[0].commit.clone();
+
+        store.set_default_country(Some("US")).unwrap();
+        assert!(matches!(
+            history.revert(&mut store, &both),
+            Err(PhoneBookError::InvalidNumber { .. })
+        ));
+        assert_eq!(store.read_contact(ann).unwrap().unwrap().name, "Anna");
+
+        store.set_default_country(Some("GB")).unwrap();
+        history
+            .record(&mut store)
+            .insert_contact("Ann".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert!(matches!(
+            history.revert(&mut store, &both),
+            Err(PhoneBookError::AlreadyExists(name)) if name == "Ann"
+        ));
+        assert_eq!(
+            store.read_contact(bob).unwrap().unwrap().entry,
+            entry("+44 20 7946 0000")
+        );
+        fs::remove_dir_all(repo).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+//! Reading and writing contacts as JSON, for other services to consume.
+//!
+//! A full dump is a single document that says which format and version it is in:
+//! `{"format": "phone-book", "version": 1, "contacts": [...]}`.
+//! JSON Lines has one contact per line instead, which suits streaming.
+//!
+//! A contact looks like `{"id": 1, "uuid": "…", "name": "Arnold", "numbers": [...],
+//! "emails": [...], "addresses": [...]}`, with the fields of `entry::Contact`.
+//! When importing, `id` and `uuid` may be left out and are ignored, because the phone book
+//! hands out its own. Labels default to "other" and the lists to being empty.
+
+use crate::{
+    entry::{Contact, EmailAddress, PhoneEntry, PhoneNumber, PostalAddress},
+    error::PhoneBookError,
+    normalize::Country,
+    validate,
+};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use std::io::Write;
+
+/// The `format` of every document.
+pub const FORMAT: &str = "phone-book";
+/// The `version` of the documents written. It goes up when older readers would
+/// misunderstand a document, not when a field is added.
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document<Contacts> {
+    format: String,
+    version: u32,
+    contacts: Contacts,
+}
+
+/// A contact as it is read, rejecting fields that are not part of the format.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImportedContact {
+    #[serde(default, rename = "id")]
+    _id: IgnoredAny,
+    #[serde(default, rename = "uuid")]
+    _uuid: IgnoredAny,
+    name: String,
+    #[serde(default)]
+    numbers: Vec<PhoneNumber>,
+    #[serde(default)]
+    emails: Vec<EmailAddress>,
+    #[serde(default)]
+    addresses: Vec<PostalAddress>,
+}
+
+impl ImportedContact {
+    /// Checks the contact the way the database would, so nothing fails halfway through an import.
+    fn check(self, default_country: Option<&Country>) -> Result<(String, PhoneEntry), String> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("the name is empty".to_owned());
+        }
+        let entry = PhoneEntry {
+            numbers: self.numbers,
+            emails: self.emails,
+            addresses: self.addresses,
+        };
+        validate::validate_entry(&entry, default_country).map_err(|err| err.to_string())?;
+        if entry
+            .numbers
+            .iter()
+            .filter(|number| number.preferred)
+            .count()
+            > 1
+        {
+            return Err("more than one number is preferred".to_owned());
+        }
+
+        Ok((name.to_owned(), entry))
+    }
+}
+
+/// Writes all contacts as one document.
+pub fn write_json(contacts: &[Contact], mut writer: impl Write) -> Result<(), PhoneBookError> {
+    let document = Document {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+        contacts,
+    };
+    serde_json::to_writer_pretty(&mut writer, &document)?;
+    writeln!(writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes every contact on a line of its own.
+pub fn write_json_lines(
+    contacts: &[Contact],
+    mut writer: impl Write,
+) -> Result<(), PhoneBookError> {
+    for contact in contacts {
+        serde_json::to_writer(&mut writer, contact)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads the contacts of a document, each together with its position like "contact 3".
+/// The format and version are checked first, then all contacts, so every mistake is reported at once.
+pub fn read_json(
+    text: &str,
+    default_country: Option<&Country>,
+) -> Result<Vec<(String, String, PhoneEntry)>, PhoneBookError> {
+    let document = serde_json::from_str::<Document<Vec<serde_json::Value>>>(text)?;
+    if document.format != FORMAT {
+        return Err(PhoneBookError::Import(format!(
+            "the format is {:?}, not {FORMAT:?}",
+            document.format
+        )));
+    }
+    if !(1..=VERSION).contains(&document.version) {
+        return Err(PhoneBookError::Import(format!(
+            "format version {} is not supported, only up to version {VERSION}",
+            document.version
+        )));
+    }
+
+    read_contacts(
+        document
+            .contacts
+            .into_iter()
+            .enumerate()
+            .map(|(position, contact)| {
+                let origin = format!("contact {}", position + 1);
+                (origin, serde_json::from_value(contact))
+            }),
+        default_country,
+    )
+}
+
+/// Reads one contact from every line that is not empty, each together with its line number.
+pub fn read_json_lines(
+    text: &str,
+    default_country: Option<&Country>,
+) -> Result<Vec<(String, String, PhoneEntry)>, PhoneBookError> {
+    read_contacts(
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| (format!("line {}", number + 1), serde_json::from_str(line))),
+        default_country,
+    )
+}
+
+fn read_contacts(
+    contacts: impl Iterator<Item = (String, serde_json::Result<ImportedContact>)>,
+    default_country: Option<&Country>,
+) -> Result<Vec<(String, String, PhoneEntry)>, PhoneBookError> {
+    let mut checked = Vec::new();
+    let mut errors = Vec::new();
+    for (origin, contact) in contacts {
+        let contact = contact
+            .map_err(|err| err.to_string())
+            .and_then(|contact| contact.check(default_country));
+        match contact {
+            Ok((name, entry)) => checked.push((origin, name, entry)),
+            Err(message) => errors.push(format!("{origin}: {message}")),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(PhoneBookError::Import(errors.join("\n")));
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_json, read_json_lines, write_json, write_json_lines};
+    use crate::{
+        entry::{
+            AddressLabel, Contact, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+            PostalAddress,
+        },
+        error::PhoneBookError,
+    };
+    use uuid::Uuid;
+
+    fn arnold() -> Contact {
+        let mut mobile = PhoneNumber::new(NumberLabel::Mobile, "+44 7700 900123");
+        mobile.preferred = true;
+        Contact {
+            id: ContactId(7),
+            uuid: Uuid::new_v4(),
+            name: "Arnold".to_owned(),
+            entry: PhoneEntry {
+                numbers: vec![
+                    mobile,
+                    PhoneNumber::new(NumberLabel::Custom("boat".to_owned()), "+1 212 555 0100"),
+                ],
+                emails: vec![EmailAddress {
+                    label: AddressLabel::Work,
+                    address: "arnold@example.com".to_owned(),
+                }],
+                addresses: vec![PostalAddress::from_parts(
+                    AddressLabel::Home,
+                    "1 Road;London;;N1 1AA;UK",
+                )
+                .unwrap()],
+            },
+        }
+    }
+
+    #[test]
+    fn documents_have_a_stable_shape() {
+        let contact = arnold();
+        let mut json = Vec::new();
+        write_json(std::slice::from_ref(&contact), &mut json).unwrap();
+
+        let json = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "format": "phone-book",
+                "version": 1,
+                "contacts": [{
+                    "id": 7,
+                    "uuid": contact.uuid.to_string(),
+                    "name": "Arnold",
+                    "numbers": [
+                        {"label": "mobile", "number": "+44 7700 900123", "preferred": true},
+                        {"label": "boat", "number": "+1 212 555 0100", "preferred": false},
+                    ],
+                    "emails": [{"label": "work", "address": "arnold@example.com"}],
+                    "addresses": [{
+                        "label": "home",
+                        "street": "1 Road",
+                        "city": "London",
+                        "region": "",
+                        "postcode": "N1 1AA",
+                        "country": "UK",
+                    }],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let expected = vec![("Arnold".to_owned(), arnold().entry)];
+        let without_origin = |contacts: Vec<(String, String, PhoneEntry)>| {
+            contacts
+                .into_iter()
+                .map(|(_, name, entry)| (name, entry))
+                .collect::<Vec<_>>()
+        };
+
+        let mut json = Vec::new();
+        write_json(&[arnold()], &mut json).unwrap();
+        let contacts = read_json(std::str::from_utf8(&json).unwrap(), None).unwrap();
+        assert_eq!(contacts[0].0, "contact 1");
+        assert_eq!(without_origin(contacts), expected);
+
+        let mut json_lines = Vec::new();
+        write_json_lines(&[arnold(), arnold()], &mut json_lines).unwrap();
+        let json_lines = String::from_utf8(json_lines).unwrap();
+        assert_eq!(json_lines.lines().count(), 2);
+        let contacts = read_json_lines(&json_lines, None).unwrap();
+        assert_eq!(contacts[1].0, "line 2");
+        assert_eq!(
+            without_origin(contacts),
+            [expected.clone(), expected].concat()
+        );
+    }
+
+    #[test]
+    fn minimal_contacts_are_enough() {
+        let contacts = read_json_lines(
+            r#"{"name": "Jack", "numbers": [{"number": "+44 20 7946 0958"}]}"#,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            contacts[0].2.numbers,
+            vec![PhoneNumber::new(NumberLabel::Other, "+44 20 7946 0958")]
+        );
+    }
+
+    #[test]
+    fn rejects_what_is_not_the_format() {
+        let import_error = |result: Result<_, PhoneBookError>| match result {
+            Err(PhoneBookError::Import(message)) => message,
+            Err(err) => panic!("not an import error: {err}"),
+            Ok(_) => panic!("was imported"),
+        };
+
+        let message = import_error(read_json(
+            r#"{"format": "phone-book", "version": 2, "contacts": []}"#,
+            None,
+        ));
+        assert!(message.contains("version 2"), "{message}");
+        let message = import_error(read_json(
+            r#"{"format": "csv", "version": 1, "contacts": []}"#,
+            None,
+        ));
+        assert!(message.contains("\"csv\""), "{message}");
+        import_error(read_json(r#"{"contacts": []}"#, None));
+        import_error(read_json("[", None));
+
+        let message = import_error(read_json(
+            r#"{"format": "phone-book", "version": 1, "contacts": [
+                {"name": "Arnold"},
+                {"name": "Jack", "phone": "+44 20 7946 0958"},
+                {"name": " "},
+                {"name": "Jill", "numbers": [{"number": "asdf"}]}
+            ]}"#,
+            None,
+        ));
+        let lines = message.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{message}");
+        assert!(lines[0].starts_with("contact 2: unknown field `phone`"));
+        assert!(lines[1].starts_with("contact 3: the name is empty"));
+        assert!(lines[2].starts_with("contact 4: invalid phone number"));
+    }
+}
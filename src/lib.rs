@@ -0,0 +1,55 @@
+//! A phone book kept in SQLite, with search, caller ID lookups and
+//! imports and exports in the usual contact formats.
+//!
+//! `PhoneBookDB` is the way in: it opens or creates the database, migrating older schemas,
+//! and every read and write goes through it. The `PhoneBookStore` trait has the same
+//! operations for contacts kept elsewhere: in memory, in a JSON file or in a vdir.
+//!
+//! ```
+//! use phone_book_with_data::{NumberLabel, PhoneBookDB, PhoneEntry, PhoneNumber};
+//!
+//! let phone_book_db = PhoneBookDB::new(None)?;
+//! phone_book_db.insert_contact(
+//!     "Arnold Baker".to_owned(),
+//!     PhoneEntry {
+//!         numbers: vec![PhoneNumber::new(NumberLabel::Work, "+44 20 7946 0958")],
+//!         ..PhoneEntry::default()
+//!     },
+//! )?;
+//! assert_eq!(phone_book_db.read_all_contacts()?[0].name, "Arnold Baker");
+//! # Ok::<(), phone_book_with_data::PhoneBookError>(())
+//! ```
+
+pub mod agi;
+pub mod config;
+pub mod csv_io;
+pub mod database;
+pub mod desk_phone;
+pub mod entry;
+pub mod error;
+mod fuzzy;
+pub mod history;
+pub mod json_io;
+mod migrations;
+pub mod normalize;
+pub mod search;
+pub mod store;
+pub mod validate;
+pub mod vcard;
+pub mod vdir;
+
+pub use crate::{
+    database::{
+        AuditOperation, AuditRecord, NumberMatch, OnConflict, PhoneBookDB, SearchHit,
+        SimilarContact, StoreChange,
+    },
+    entry::{
+        AddressLabel, Contact, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+        PostalAddress,
+    },
+    error::PhoneBookError,
+    history::GitHistory,
+    search::NameMatch,
+    store::{JsonFileStore, MemoryStore, PhoneBookStore},
+    vdir::VdirStore,
+};
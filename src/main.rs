@@ -1,76 +1,203 @@
-const FILE_NAME: &str = "file.sqlite";
-use std::collections::BTreeMap;
-mod database;
-mod entry;
-
-use crate::entry::PhoneEntry;
-use prettytable::{row, Table};
-fn main() {
+//! The interactive phone book, and the command line for scripts in `cli`.
+//! Everything it does with the phone book goes through the library.
+
+mod cli;
+
+use phone_book_with_data::{
+    config, normalize::Country, validate, AddressLabel, AuditRecord, Contact, ContactId,
+    EmailAddress, GitHistory, NameMatch, NumberLabel, PhoneBookDB, PhoneBookError, PhoneBookStore,
+    PhoneEntry, PhoneNumber, PostalAddress, SearchHit, SimilarContact, VdirStore,
+};
+use prettytable::{Cell, Row, Table};
+use std::{path::PathBuf, process::ExitCode};
+
+/// Put around the words that matched a full-text search.
+const HIGHLIGHT_MARKS: (&str, &str) = ("[", "]");
+
+fn main() -> ExitCode {
+    let invocation = match cli::parse(std::env::args().skip(1)) {
+        Ok(invocation) => invocation,
+        Err(err) => {
+            eprintln!("error: {err}\nRun with --help to see the commands.");
+            return ExitCode::from(2);
+        }
+    };
+    let mut phone_book_db = match open_phone_book(invocation.database, invocation.vdir) {
+        Ok(phone_book_db) => phone_book_db,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let history = match open_history(invocation.history, phone_book_db.as_ref()) {
+        Ok(history) => history,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(command) = invocation.command {
+        return cli::run(command, phone_book_db.as_mut(), history.as_ref());
+    }
+    let mut recorded;
+    let phone_book_db: &mut dyn PhoneBookStore = match &history {
+        Some(history) => {
+            recorded = history.record(phone_book_db.as_mut());
+            &mut recorded
+        }
+        None => phone_book_db.as_mut(),
+    };
+
     loop {
-        let phone_book_db =
-            database::PhoneBookDB::new(Some(FILE_NAME.into())).expect("Failed to open file.");
+        for warning in history.iter().flat_map(GitHistory::take_warnings) {
+            eprintln!("warning: {warning}");
+        }
         println!("Please enter one of these commands:");
-        let command = get_input_from_user("show, add, remove, modify, exit");
+        let command = get_input_from_user(
+            "show, search, find, history, add, remove, modify, rename, country, where, exit",
+        );
         if command == "show" {
-            let phone_book = phone_book_db.read_all_entries().expect("Cannot read data");
-            show_phone_book(&phone_book);
+            let contacts = phone_book_db.read_all_contacts().expect("Cannot read data");
+            show_phone_book(&contacts);
+        } else if command == "search" {
+            let text = get_input_from_user("Please enter part of a name to search for");
+            let contacts = phone_book_db
+                .search_contacts(&text, NameMatch::Substring)
+                .expect("Cannot read data");
+            if contacts.is_empty() {
+                println!("No name contains {text:?}.");
+            } else {
+                show_phone_book(&contacts);
+            }
+        } else if command == "find" {
+            let query = get_input_from_user(
+                "Please enter words to find in names, numbers, emails and addresses",
+            );
+            let hits = phone_book_db
+                .search_full_text(&query, HIGHLIGHT_MARKS)
+                .expect("Cannot read data");
+            if !hits.is_empty() {
+                show_search_hits(&hits);
+                continue;
+            }
+            let similar = phone_book_db
+                .find_similar_names(&query)
+                .expect("Cannot read data");
+            if similar.is_empty() {
+                println!("Nothing matches {query:?}.");
+            } else {
+                println!("Nothing matches {query:?} exactly, but these names are alike:");
+                show_similar_contacts(&similar);
+            }
+        } else if command == "history" {
+            let name = get_input_from_user("Please enter a name to show the changes of");
+            match phone_book_db.read_audit_log_by_name(&name) {
+                Ok(Some(records)) if records.is_empty() => {
+                    println!("No contact ever had the name {name:?}.")
+                }
+                Ok(Some(records)) => show_audit_log(&records),
+                Ok(None) => println!("The audit log is only kept in databases."),
+                Err(err) => println!("Cannot read the audit log: {err}"),
+            }
         } else if command == "exit" {
-            return;
+            return ExitCode::SUCCESS;
         } else if command == "add" {
             let name = get_input_from_user("Please enter a name");
-            let mut phone_book = phone_book_db
-                .read_all_entries()
+            let existing = phone_book_db
+                .read_contacts_by_name(&name)
                 .expect("Cannot find the file.");
-            if phone_book.contains_key(&name) {
-                println!("The name already exists.");
-                continue;
+            if !existing.is_empty() {
+                let answer = get_input_from_user(
+                    "The name already exists. Add another contact with the same name? (yes/no)",
+                );
+                if answer != "yes" {
+                    continue;
+                }
             }
-            let phone_number = get_input_from_user("Please enter a phone number");
-            let phone_number1 = get_input_from_user("please enter another number");
-            phone_book.insert(
-                name.clone(),
-                PhoneEntry {
-                    mobile: phone_number.clone(),
-                    work: phone_number1.clone(),
-                },
-            );
+            let entry = PhoneEntry {
+                numbers: get_numbers_from_user(phone_book_db.default_country()),
+                emails: get_emails_from_user(),
+                addresses: get_addresses_from_user(),
+            };
             phone_book_db
-                .write_entry(
-                    name,
-                    PhoneEntry {
-                        mobile: phone_number,
-                        work: phone_number1,
-                    },
-                )
+                .insert_contact(name, entry)
                 .expect("Cannot write data");
         } else if command == "remove" {
             let name = get_input_from_user("Please enter a name to remove");
-            let phone_book = phone_book_db
-                .read_all_entries()
-                .expect("Cannot read the data from the file.");
-            if phone_book.contains_key(&name) {
-                phone_book_db.remove_entry(&name).unwrap();
-                println!("Entry removed successfully")
-            } else if !phone_book.contains_key(&name) {
-                println!("The file dosen't contain the data");
+            let removed = match choose_contact(&*phone_book_db, &name) {
+                Some(contact) => phone_book_db.remove_contact(contact.id),
+                None => Err(PhoneBookError::NotFound(name.clone())),
+            };
+            match removed {
+                Ok(_) => println!("Entry removed successfully"),
+                Err(PhoneBookError::NotFound(_)) => {
+                    println!("The file dosen't contain the data");
+                    suggest_similar_names(&*phone_book_db, &name);
+                }
+                Err(err) => println!("Cannot remove the entry: {err}"),
             }
         } else if command == "modify" {
             let name = get_input_from_user("Please enter a name to modify: ");
 
-            if phone_book_db.read_entry(name.clone()).unwrap().is_some() {
-                let new_phone_number = get_input_from_user("Please enter the new phone number");
-                let new_phone_number1 = get_input_from_user("Please enter another phone number");
-                phone_book_db
-                    .modify_entry(
-                        name,
-                        PhoneEntry {
-                            mobile: new_phone_number,
-                            work: new_phone_number1,
-                        },
-                    )
-                    .unwrap();
+            if let Some(contact) = choose_contact(&*phone_book_db, &name) {
+                show_phone_book(std::slice::from_ref(&contact));
+                let mut entry = contact.entry.clone();
+                let part =
+                    get_input_from_user("What do you want to replace? numbers, emails, addresses");
+                if part == "numbers" {
+                    entry.numbers = get_numbers_from_user(phone_book_db.default_country());
+                } else if part == "emails" {
+                    entry.emails = get_emails_from_user();
+                } else if part == "addresses" {
+                    entry.addresses = get_addresses_from_user();
+                } else {
+                    println!("try again");
+                    continue;
+                }
+                match phone_book_db.modify_contact(contact.id, entry) {
+                    Ok(_) => println!("Entry modified successfully"),
+                    Err(PhoneBookError::NotFound(_)) => println!("The name doesen't exist."),
+                    Err(err) => println!("Cannot modify the entry: {err}"),
+                }
             } else {
-                println!("The name doesen't exist.")
+                println!("The name doesen't exist.");
+                suggest_similar_names(&*phone_book_db, &name);
+            }
+        } else if command == "rename" {
+            let name = get_input_from_user("Please enter a name to rename");
+
+            if let Some(contact) = choose_contact(&*phone_book_db, &name) {
+                let new_name = get_input_from_user("Please enter the new name");
+                match phone_book_db.rename_contact(contact.id, new_name) {
+                    Ok(()) => println!("Entry renamed successfully"),
+                    Err(PhoneBookError::AlreadyExists(_)) => println!("The name already exists."),
+                    Err(err) => println!("Cannot rename the entry: {err}"),
+                }
+            } else {
+                println!("The name doesen't exist.");
+                suggest_similar_names(&*phone_book_db, &name);
+            }
+        } else if command == "country" {
+            let current = phone_book_db
+                .default_country()
+                .map_or("none", |country| country.code);
+            let code = get_input_from_user(&format!(
+                "Numbers without a calling code are read as numbers of {current}. \
+                 Please enter a country code like GB, or none"
+            ));
+            let code = if code == "none" {
+                None
+            } else {
+                Some(code.as_str())
+            };
+            match phone_book_db.set_default_country(code) {
+                Ok(()) => println!("Default country changed successfully"),
+                Err(PhoneBookError::UnknownCountry(_)) => println!("The country code is unknown."),
+                Err(err) => println!("Cannot change the default country: {err}"),
+            }
+        } else if command == "where" {
+            if let Some(file_path) = phone_book_db.file_path() {
+                println!("The phone book is kept in {}", file_path.display());
             }
         } else {
             println!("try again")
@@ -78,6 +205,68 @@ fn main() {
     }
 }
 
+/// Opens the directory chosen by `--vdir`, or else the database chosen by `--db`,
+/// the environment or the config file.
+fn open_phone_book(
+    flag: Option<PathBuf>,
+    vdir: Option<PathBuf>,
+) -> Result<Box<dyn PhoneBookStore>, String> {
+    if let Some(dir) = vdir {
+        return match VdirStore::open(&dir) {
+            Ok(store) => {
+                for skipped in store.skipped_cards() {
+                    eprintln!("warning: skipped {skipped}");
+                }
+                Ok(Box::new(store))
+            }
+            Err(err) => Err(format!(
+                "cannot open {} (chosen by --vdir): {err}",
+                dir.display()
+            )),
+        };
+    }
+    let location = config::database_location(flag).map_err(|err| err.to_string())?;
+    if let config::Source::Legacy(default) = &location.source {
+        eprintln!(
+            "warning: using {} in the current directory like older versions did; \
+             move it to {} to use it from anywhere",
+            location.path.display(),
+            default.display()
+        );
+    }
+    let cannot_open = |err: &dyn std::fmt::Display| {
+        format!(
+            "cannot open {} (chosen by {}): {err}",
+            location.path.display(),
+            location.source
+        )
+    };
+    location
+        .create_default_dir()
+        .map_err(|err| cannot_open(&err))?;
+    match PhoneBookDB::new(Some(location.path.clone())) {
+        Ok(phone_book_db) => Ok(Box::new(phone_book_db)),
+        Err(err) => Err(cannot_open(&err)),
+    }
+}
+
+/// Opens the history chosen by `--history` or the environment, if changes are recorded.
+fn open_history(
+    flag: Option<PathBuf>,
+    phone_book_db: &dyn PhoneBookStore,
+) -> Result<Option<GitHistory>, String> {
+    let Some(repo) = config::history_location(flag) else {
+        return Ok(None);
+    };
+    match GitHistory::open(&repo, phone_book_db) {
+        Ok(history) => Ok(Some(history)),
+        Err(err) => Err(format!(
+            "cannot open the history in {}: {err}",
+            repo.display()
+        )),
+    }
+}
+
 fn get_input_from_user(message: &str) -> String {
     println!("{message}");
 
@@ -87,15 +276,260 @@ fn get_input_from_user(message: &str) -> String {
     name
 }
 
-fn show_phone_book(phone_book: &BTreeMap<String, PhoneEntry>) {
-    if !phone_book.is_empty() {
+/// Asks for numbers with their labels until the user enters an empty number.
+/// Numbers that would be rejected by the database are asked for again.
+fn get_numbers_from_user(default_country: Option<&Country>) -> Vec<PhoneNumber> {
+    let mut numbers = Vec::new();
+    loop {
+        let number = get_input_from_user("Please enter a phone number (leave empty to finish)");
+        if number.is_empty() {
+            break;
+        }
+        if let Err(reason) = validate::validate(&number, default_country) {
+            println!("This is not a valid phone number: {reason}. Please try again.");
+            continue;
+        }
+        let label = get_input_from_user(
+            "Please enter a label for it: mobile, work, home, fax, pager, main, other or your own",
+        );
+        let Ok(label) = if label.is_empty() { "other" } else { &label }.parse::<NumberLabel>();
+        numbers.push(PhoneNumber::new(label, number));
+    }
+
+    if numbers.len() > 1 {
+        let preferred = get_input_from_user(&format!(
+            "Which number is preferred? Enter 1 to {} or leave empty for none",
+            numbers.len()
+        ));
+        if let Some(number) = preferred
+            .parse::<usize>()
+            .ok()
+            .and_then(|position| numbers.get_mut(position.checked_sub(1)?))
+        {
+            number.preferred = true;
+        }
+    } else if let Some(number) = numbers.first_mut() {
+        number.preferred = true;
+    }
+
+    numbers
+}
+
+fn get_address_label_from_user() -> AddressLabel {
+    let label = get_input_from_user("Please enter a label for it: home, work, other or your own");
+    let Ok(label) = if label.is_empty() { "other" } else { &label }.parse::<AddressLabel>();
+    label
+}
+
+/// Asks for email addresses with their labels until the user enters an empty address.
+fn get_emails_from_user() -> Vec<EmailAddress> {
+    let mut emails = Vec::new();
+    loop {
+        let address = get_input_from_user("Please enter an email address (leave empty to finish)");
+        if address.is_empty() {
+            return emails;
+        }
+        let label = get_address_label_from_user();
+        emails.push(EmailAddress { label, address });
+    }
+}
+
+/// Asks for postal addresses part by part until the user enters an empty street.
+fn get_addresses_from_user() -> Vec<PostalAddress> {
+    let mut addresses = Vec::new();
+    loop {
+        let street = get_input_from_user("Please enter a street address (leave empty to finish)");
+        if street.is_empty() {
+            return addresses;
+        }
+        addresses.push(PostalAddress {
+            street,
+            city: get_input_from_user("Please enter the city"),
+            region: get_input_from_user("Please enter the region or state"),
+            postcode: get_input_from_user("Please enter the postcode"),
+            country: get_input_from_user("Please enter the country"),
+            label: get_address_label_from_user(),
+        });
+    }
+}
+
+/// Finds the contact called `name`, asking the user for an ID when several contacts share the name.
+fn choose_contact(phone_book_db: &dyn PhoneBookStore, name: &str) -> Option<Contact> {
+    let mut contacts = phone_book_db
+        .read_contacts_by_name(name)
+        .expect("Cannot read data");
+    if contacts.len() <= 1 {
+        return contacts.pop();
+    }
+
+    show_phone_book(&contacts);
+    let id = get_input_from_user("Several contacts have this name, please enter an ID");
+    let id = ContactId(id.parse().ok()?);
+    contacts.into_iter().find(|contact| contact.id == id)
+}
+
+/// Prints up to three names like `name`, for when no contact has exactly that name.
+fn suggest_similar_names(phone_book_db: &dyn PhoneBookStore, name: &str) {
+    let similar = phone_book_db
+        .find_similar_names(name)
+        .expect("Cannot read data");
+    let mut names = Vec::new();
+    for contact in similar.into_iter().map(|similar| similar.contact) {
+        if contact.name != name && !names.contains(&contact.name) && names.len() < 3 {
+            names.push(contact.name);
+        }
+    }
+    if !names.is_empty() {
+        let names = names
+            .iter()
+            .map(|name| format!("{name:?}"))
+            .collect::<Vec<_>>();
+        println!("Did you mean {}?", names.join(" or "));
+    }
+}
+
+/// Shows the contacts whose names are like the one searched for, with how alike they are.
+fn show_similar_contacts(similar: &[SimilarContact]) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("ID"),
+        Cell::new("Name"),
+        Cell::new("Numbers"),
+        Cell::new("Score"),
+    ]));
+    for similar in similar {
+        table.add_row(Row::new(vec![
+            Cell::new(&similar.contact.id.to_string()),
+            Cell::new(&similar.contact.name),
+            Cell::new(&format_numbers(&similar.contact.entry)),
+            Cell::new(&format!("{:.0}%", similar.score * 100.0)),
+        ]));
+    }
+    table.printstd()
+}
+
+/// Shows recorded changes with the contact as it was before and after each of them.
+fn show_audit_log(records: &[AuditRecord]) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("When"),
+        Cell::new("User"),
+        Cell::new("Change"),
+        Cell::new("ID"),
+        Cell::new("Before"),
+        Cell::new("After"),
+    ]));
+    let format_contact = |contact: &Option<Contact>| match contact {
+        Some(contact) => [
+            contact.name.clone(),
+            format_numbers(&contact.entry),
+            format_emails(&contact.entry),
+            format_addresses(&contact.entry),
+        ]
+        .into_iter()
+        .filter(|lines| !lines.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n"),
+        None => String::new(),
+    };
+    for record in records {
+        table.add_row(Row::new(vec![
+            Cell::new(&record.timestamp),
+            Cell::new(&record.os_user),
+            Cell::new(record.operation.as_str()),
+            Cell::new(&record.contact_id.to_string()),
+            Cell::new(&format_contact(&record.old)),
+            Cell::new(&format_contact(&record.new)),
+        ]));
+    }
+    table.printstd()
+}
+
+/// Email and address columns are only shown when at least one contact has some.
+fn show_phone_book(contacts: &[Contact]) {
+    if !contacts.is_empty() {
+        let show_emails = contacts
+            .iter()
+            .any(|contact| !contact.entry.emails.is_empty());
+        let show_addresses = contacts
+            .iter()
+            .any(|contact| !contact.entry.addresses.is_empty());
+
         let mut table = Table::new();
-        table.add_row(row!("Name", "Mobile number", "Work number"));
-        phone_book.into_iter().for_each(|(name, phone_entry)| {
-            table.add_row(row!(name, phone_entry.mobile, phone_entry.work));
+        let mut titles = vec!["ID", "Name", "Numbers"];
+        if show_emails {
+            titles.push("Emails");
+        }
+        if show_addresses {
+            titles.push("Addresses");
+        }
+        table.add_row(Row::new(titles.into_iter().map(Cell::new).collect()));
+
+        contacts.iter().for_each(|contact| {
+            let mut cells = vec![
+                contact.id.to_string(),
+                contact.name.clone(),
+                format_numbers(&contact.entry),
+            ];
+            if show_emails {
+                cells.push(format_emails(&contact.entry));
+            }
+            if show_addresses {
+                cells.push(format_addresses(&contact.entry));
+            }
+            table.add_row(Row::new(cells.iter().map(|cell| Cell::new(cell)).collect()));
         });
         table.printstd()
     } else {
         println!("The phone book is empty.");
     }
 }
+
+/// Shows what matched in each contact found, with the matching words between `HIGHLIGHT_MARKS`.
+fn show_search_hits(hits: &[SearchHit]) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("ID"),
+        Cell::new("Name"),
+        Cell::new("Matches"),
+    ]));
+    for hit in hits {
+        table.add_row(Row::new(vec![
+            Cell::new(&hit.contact.id.to_string()),
+            Cell::new(&hit.name),
+            Cell::new(&hit.details.join("\n")),
+        ]));
+    }
+    table.printstd()
+}
+
+/// One line per number, with its label and a marker on the preferred one.
+fn format_numbers(entry: &PhoneEntry) -> String {
+    entry
+        .numbers
+        .iter()
+        .map(|number| {
+            let preferred = if number.preferred { " (preferred)" } else { "" };
+            format!("{}: {}{preferred}", number.label, number.number)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_emails(entry: &PhoneEntry) -> String {
+    entry
+        .emails
+        .iter()
+        .map(|email| format!("{}: {}", email.label, email.address))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_addresses(entry: &PhoneEntry) -> String {
+    entry
+        .addresses
+        .iter()
+        .map(|address| format!("{}: {address}", address.label))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,493 @@
+//! Versioned schema migrations for the phone book database.
+//!
+//! The schema version of a database file is kept in SQLite's `PRAGMA user_version`.
+//! Databases written before migrations existed report version 0, so the first
+//! migration has to accept a `phone_book` table that is already there.
+
+use crate::{error::PhoneBookError, normalize, search};
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior};
+use uuid::Uuid;
+
+struct Migration {
+    description: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// All migrations in the order they are applied.
+/// Migration `i` in this list upgrades the schema from version `i` to version `i + 1`,
+/// so new migrations must only ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the phone_book table",
+        up: create_phone_book,
+    },
+    Migration {
+        description: "give every contact a stable id and uuid",
+        up: add_contact_ids,
+    },
+    Migration {
+        description: "move phone numbers into the labelled phone_number table",
+        up: add_phone_numbers,
+    },
+    Migration {
+        description: "add email and postal addresses",
+        up: add_addresses,
+    },
+    Migration {
+        description: "store phone numbers in E.164 form next to the raw input",
+        up: add_e164_numbers,
+    },
+    Migration {
+        description: "keep a folded copy of every name for searching",
+        up: add_search_names,
+    },
+    Migration {
+        description: "index all contact details for full-text search",
+        up: add_full_text_search,
+    },
+    Migration {
+        description: "index phone numbers by their digits for reverse lookups",
+        up: add_number_digits,
+    },
+    Migration {
+        description: "keep an append-only audit log of every change to a contact",
+        up: add_audit_log,
+    },
+];
+
+/// The schema version this build of the program writes.
+pub const LATEST_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Reads the schema version stored in the database.
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Brings the database up to `LATEST_VERSION`.
+/// Every migration runs in its own transaction together with the version bump,
+/// so a failure leaves the database at the last version that fully succeeded.
+pub fn migrate(conn: &mut Connection) -> Result<(), PhoneBookError> {
+    // Reading the version takes no lock, so that a file that is up to date can be opened
+    // even when it is read-only or another process is writing to it.
+    if schema_version(conn)? == LATEST_VERSION {
+        return Ok(());
+    }
+    loop {
+        // An immediate transaction takes the write lock before the version is read,
+        // so two processes opening the same file cannot both run a migration.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version = schema_version(&tx)?;
+        if version > LATEST_VERSION {
+            return Err(PhoneBookError::SchemaTooNew {
+                found: version,
+                supported: LATEST_VERSION,
+            });
+        }
+        if version == LATEST_VERSION {
+            return Ok(());
+        }
+
+        let migration = &MIGRATIONS[version as usize];
+        (migration.up)(&tx).map_err(|source| PhoneBookError::Migration {
+            version: version + 1,
+            description: migration.description,
+            source,
+        })?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+}
+
+/// Databases created before migrations existed already have this table,
+/// so it is only created when missing.
+fn create_phone_book(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS
+         phone_book (name TEXT NOT NULL, phone_number TEXT NOT NULL, work_number TEXT NOT NULL)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Rebuilds `phone_book` with an integer primary key and a UUID column.
+/// Existing rows keep their order and get a fresh random UUID each.
+fn add_contact_ids(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE phone_book_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            uuid TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            phone_number TEXT NOT NULL,
+            work_number TEXT NOT NULL
+        )",
+    )?;
+
+    {
+        let mut select =
+            tx.prepare("SELECT name, phone_number, work_number FROM phone_book ORDER BY rowid")?;
+        let mut insert = tx.prepare(
+            "INSERT INTO phone_book_new (uuid, name, phone_number, work_number) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let mut rows = select.query(())?;
+        while let Some(row) = rows.next()? {
+            insert.execute((
+                Uuid::new_v4().to_string(),
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))?;
+        }
+    }
+
+    tx.execute_batch(
+        "DROP TABLE phone_book;
+         ALTER TABLE phone_book_new RENAME TO phone_book;
+         CREATE INDEX phone_book_name ON phone_book (name);",
+    )
+}
+
+/// Replaces the fixed `phone_number` and `work_number` columns with one row per number.
+/// Empty legacy numbers are dropped rather than turned into empty rows.
+fn add_phone_numbers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE phone_number (
+            id INTEGER PRIMARY KEY,
+            contact_id INTEGER NOT NULL REFERENCES phone_book (id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            number TEXT NOT NULL,
+            preferred INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (contact_id, position)
+        );
+        INSERT INTO phone_number (contact_id, position, label, number)
+            SELECT id, 0, 'mobile', phone_number FROM phone_book WHERE phone_number != '';
+        INSERT INTO phone_number (contact_id, position, label, number)
+            SELECT id, 1, 'work', work_number FROM phone_book WHERE work_number != '';
+        ALTER TABLE phone_book DROP COLUMN phone_number;
+        ALTER TABLE phone_book DROP COLUMN work_number;",
+    )
+}
+
+fn add_addresses(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE email_address (
+            id INTEGER PRIMARY KEY,
+            contact_id INTEGER NOT NULL REFERENCES phone_book (id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            address TEXT NOT NULL,
+            UNIQUE (contact_id, position)
+        );
+        CREATE TABLE postal_address (
+            id INTEGER PRIMARY KEY,
+            contact_id INTEGER NOT NULL REFERENCES phone_book (id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            street TEXT NOT NULL,
+            city TEXT NOT NULL,
+            region TEXT NOT NULL,
+            postcode TEXT NOT NULL,
+            country TEXT NOT NULL,
+            UNIQUE (contact_id, position)
+        );",
+    )
+}
+
+/// Existing numbers are normalized without a default country,
+/// so only the ones written with a calling code get an E.164 form here.
+fn add_e164_numbers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE phone_number ADD COLUMN e164 TEXT;
+        CREATE INDEX phone_number_e164 ON phone_number (e164);
+        CREATE TABLE setting (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+    )?;
+
+    let numbers = tx
+        .prepare("SELECT id, number FROM phone_number")?
+        .query_map((), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = tx.prepare("UPDATE phone_number SET e164 = ?2 WHERE id = ?1")?;
+    for (id, number) in numbers {
+        update.execute((id, normalize::to_e164(&number, None)))?;
+    }
+
+    Ok(())
+}
+
+fn add_search_names(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE phone_book ADD COLUMN search_name TEXT NOT NULL DEFAULT '';
+        CREATE INDEX phone_book_search_name ON phone_book (search_name);",
+    )?;
+
+    let names = tx
+        .prepare("SELECT id, name FROM phone_book")?
+        .query_map((), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = tx.prepare("UPDATE phone_book SET search_name = ?2 WHERE id = ?1")?;
+    for (id, name) in names {
+        update.execute((id, search::fold(&name)))?;
+    }
+
+    Ok(())
+}
+
+/// Keeps one `contact_search` row per contact, with the same rowid as its `phone_book` row.
+/// The schema sticks to what SQLite 3.40 understands, so older `sqlite3` tools can still open the file:
+/// details are put in order by a subquery, as `group_concat` cannot order them itself there.
+/// `contact_document` gathers what is indexed, and triggers on every table holding a part
+/// of it write the row again, so the index can never fall behind the contacts.
+fn add_full_text_search(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE VIEW contact_document AS
+            SELECT
+                id,
+                name,
+                (SELECT group_concat(line, char(10)) FROM (
+                    SELECT number || ' ' || coalesce(e164, '') AS line
+                    FROM phone_number WHERE contact_id = phone_book.id ORDER BY position
+                )) AS numbers,
+                (SELECT group_concat(address, char(10)) FROM (
+                    SELECT address
+                    FROM email_address WHERE contact_id = phone_book.id ORDER BY position
+                )) AS emails,
+                (SELECT group_concat(line, char(10)) FROM (
+                    SELECT substr(
+                        coalesce(', ' || nullif(street, ''), '')
+                            || coalesce(', ' || nullif(city, ''), '')
+                            || coalesce(', ' || nullif(region, ''), '')
+                            || coalesce(', ' || nullif(postcode, ''), '')
+                            || coalesce(', ' || nullif(country, ''), ''),
+                        3) AS line
+                    FROM postal_address WHERE contact_id = phone_book.id ORDER BY position
+                )) AS addresses
+            FROM phone_book;
+        CREATE VIRTUAL TABLE contact_search USING fts5(
+            name, numbers, emails, addresses,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        INSERT INTO contact_search (rowid, name, numbers, emails, addresses)
+            SELECT * FROM contact_document;",
+    )?;
+
+    let refresh = |id: &str| {
+        format!(
+            "DELETE FROM contact_search WHERE rowid = {id};
+            INSERT INTO contact_search (rowid, name, numbers, emails, addresses)
+                SELECT * FROM contact_document WHERE id = {id};"
+        )
+    };
+    tx.execute_batch(&format!(
+        "CREATE TRIGGER phone_book_search_insert AFTER INSERT ON phone_book BEGIN {} END;
+        CREATE TRIGGER phone_book_search_update AFTER UPDATE OF name ON phone_book BEGIN {} END;
+        CREATE TRIGGER phone_book_search_delete AFTER DELETE ON phone_book BEGIN
+            DELETE FROM contact_search WHERE rowid = OLD.id;
+        END;",
+        refresh("NEW.id"),
+        refresh("NEW.id"),
+    ))?;
+    for table in ["phone_number", "email_address", "postal_address"] {
+        tx.execute_batch(&format!(
+            "CREATE TRIGGER {table}_search_insert AFTER INSERT ON {table} BEGIN {} END;
+            CREATE TRIGGER {table}_search_update AFTER UPDATE ON {table} BEGIN {} {} END;
+            CREATE TRIGGER {table}_search_delete AFTER DELETE ON {table} BEGIN {} END;",
+            refresh("NEW.contact_id"),
+            refresh("OLD.contact_id"),
+            refresh("NEW.contact_id"),
+            refresh("OLD.contact_id"),
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Stores `normalize::lookup_digits` backwards, so that numbers ending in some digits
+/// can be found through the index by the digits they start with.
+fn add_number_digits(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE phone_number ADD COLUMN reversed_digits TEXT NOT NULL DEFAULT '';
+        CREATE INDEX phone_number_reversed_digits ON phone_number (reversed_digits);",
+    )?;
+
+    let country = tx
+        .query_row(
+            "SELECT value FROM setting WHERE key = 'default_country'",
+            (),
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+        .and_then(|code| normalize::find_country(&code));
+    let numbers = tx
+        .prepare("SELECT id, number FROM phone_number")?
+        .query_map((), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut update = tx.prepare("UPDATE phone_number SET reversed_digits = ?2 WHERE id = ?1")?;
+    for (id, number) in numbers {
+        let digits = normalize::lookup_digits(&number, country);
+        update.execute((id, digits.chars().rev().collect::<String>()))?;
+    }
+
+    Ok(())
+}
+
+/// `contact_id` is not a foreign key, because the log outlives the contacts it is about.
+/// The contact before and after the change is kept as `entry::Contact` serialized to JSON,
+/// so that the log does not have to follow later changes of the schema.
+/// Triggers refuse to change or delete what was logged.
+fn add_audit_log(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            os_user TEXT NOT NULL,
+            operation TEXT NOT NULL,
+            contact_id INTEGER NOT NULL,
+            contact_uuid TEXT NOT NULL,
+            old_value TEXT,
+            new_value TEXT
+        );
+        CREATE INDEX audit_log_contact_id ON audit_log (contact_id);
+        CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+            SELECT RAISE(ABORT, 'the audit log is append-only');
+        END;
+        CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+            SELECT RAISE(ABORT, 'the audit log is append-only');
+        END;",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, schema_version, LATEST_VERSION};
+    use crate::error::PhoneBookError;
+    use rusqlite::{Connection, TransactionBehavior};
+    use std::{fs, time::Duration};
+
+    #[test]
+    fn migrates_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+    }
+
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+    }
+
+    #[test]
+    fn up_to_date_database_needs_no_write_lock() {
+        let file_path =
+            std::env::temp_dir().join(format!("phone_book_lock_{}.sqlite", std::process::id()));
+        migrate(&mut Connection::open(&file_path).unwrap()).unwrap();
+
+        let mut writer = Connection::open(&file_path).unwrap();
+        let tx = writer
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .unwrap();
+        let mut reader = Connection::open(&file_path).unwrap();
+        reader.busy_timeout(Duration::ZERO).unwrap();
+        let result = migrate(&mut reader);
+        drop(tx);
+        fs::remove_file(file_path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn upgrades_legacy_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE phone_book (name TEXT NOT NULL, phone_number TEXT NOT NULL, work_number TEXT NOT NULL)",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO phone_book (name, phone_number, work_number) VALUES ('Arnold', '9027590', '3795780357')",
+            (),
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION);
+        let (id, uuid): (i64, String) = conn
+            .query_row(
+                "SELECT id, uuid FROM phone_book WHERE name = 'Arnold'",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(id, 1);
+        assert!(uuid::Uuid::parse_str(&uuid).is_ok());
+        let search_name: String = conn
+            .query_row(
+                "SELECT search_name FROM phone_book WHERE id = 1",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(search_name, "arnold");
+        let indexed: i64 = conn
+            .query_row(
+                "SELECT rowid FROM contact_search WHERE contact_search MATCH '3795780357'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, 1);
+        let reversed_digits: String = conn
+            .query_row(
+                "SELECT reversed_digits FROM phone_number WHERE contact_id = 1 AND position = 1",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(reversed_digits, "7530875973");
+
+        let numbers = conn
+            .prepare(
+                "SELECT label, number FROM phone_number WHERE contact_id = 1 ORDER BY position",
+            )
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, String)>, _>>()
+            .unwrap();
+        assert_eq!(
+            numbers,
+            vec![
+                ("mobile".to_owned(), "9027590".to_owned()),
+                ("work".to_owned(), "3795780357".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", LATEST_VERSION + 1)
+            .unwrap();
+
+        let err = migrate(&mut conn).unwrap_err();
+
+        assert!(matches!(
+            err,
+            PhoneBookError::SchemaTooNew { found, supported }
+                if found == LATEST_VERSION + 1 && supported == LATEST_VERSION
+        ));
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_VERSION + 1);
+    }
+}
@@ -0,0 +1,257 @@
+//! Turns phone numbers the way people type them into E.164 (`+` followed by up to 15 digits),
+//! so that "+44 20 7946 0958", "020 7946 0958" and "00442079460958" all become "+442079460958".
+//!
+//! National numbers can only be normalized when the country they belong to is known,
+//! which is why callers pass a default country.
+
+/// What is needed to turn a national number of a country into an international one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Country {
+    /// ISO 3166-1 alpha-2 code, like "GB".
+    pub code: &'static str,
+    pub calling_code: &'static str,
+    /// Dialled before national numbers and dropped in international format, like the "0" in the UK.
+    pub trunk_prefix: Option<&'static str>,
+    /// Dialled before a calling code to reach another country, like "00" in most of Europe.
+    pub international_prefix: &'static str,
+}
+
+const fn country(
+    code: &'static str,
+    calling_code: &'static str,
+    trunk_prefix: Option<&'static str>,
+    international_prefix: &'static str,
+) -> Country {
+    Country {
+        code,
+        calling_code,
+        trunk_prefix,
+        international_prefix,
+    }
+}
+
+/// The countries a default country can be chosen from.
+pub const COUNTRIES: &[Country] = &[
+    country("AE", "971", Some("0"), "00"),
+    country("AF", "93", Some("0"), "00"),
+    country("AR", "54", Some("0"), "00"),
+    country("AT", "43", Some("0"), "00"),
+    country("AU", "61", Some("0"), "0011"),
+    country("BE", "32", Some("0"), "00"),
+    country("BR", "55", Some("0"), "00"),
+    country("CA", "1", Some("1"), "011"),
+    country("CH", "41", Some("0"), "00"),
+    country("CN", "86", Some("0"), "00"),
+    country("CZ", "420", None, "00"),
+    country("DE", "49", Some("0"), "00"),
+    country("DK", "45", None, "00"),
+    country("EG", "20", Some("0"), "00"),
+    country("ES", "34", None, "00"),
+    country("FI", "358", Some("0"), "00"),
+    country("FR", "33", Some("0"), "00"),
+    country("GB", "44", Some("0"), "00"),
+    country("GR", "30", None, "00"),
+    country("HK", "852", None, "001"),
+    country("IE", "353", Some("0"), "00"),
+    country("IL", "972", Some("0"), "00"),
+    country("IN", "91", Some("0"), "00"),
+    country("IR", "98", Some("0"), "00"),
+    // Italian numbers keep their leading 0 in international format.
+    country("IT", "39", None, "00"),
+    country("JP", "81", Some("0"), "010"),
+    country("KE", "254", Some("0"), "000"),
+    country("KR", "82", Some("0"), "001"),
+    country("LU", "352", None, "00"),
+    country("MX", "52", None, "00"),
+    country("NG", "234", Some("0"), "009"),
+    country("NL", "31", Some("0"), "00"),
+    country("NO", "47", None, "00"),
+    country("NZ", "64", Some("0"), "00"),
+    country("PK", "92", Some("0"), "00"),
+    country("PL", "48", None, "00"),
+    country("PT", "351", None, "00"),
+    country("RU", "7", Some("8"), "810"),
+    country("SA", "966", Some("0"), "00"),
+    country("SE", "46", Some("0"), "00"),
+    country("SG", "65", None, "000"),
+    country("TR", "90", Some("0"), "00"),
+    country("UA", "380", Some("0"), "00"),
+    country("US", "1", Some("1"), "011"),
+    country("ZA", "27", Some("0"), "00"),
+];
+
+/// Finds a country by its ISO 3166-1 alpha-2 code, ignoring case.
+pub fn find_country(code: &str) -> Option<&'static Country> {
+    COUNTRIES
+        .iter()
+        .find(|country| country.code.eq_ignore_ascii_case(code.trim()))
+}
+
+/// Characters people use to group digits, which carry no meaning.
+pub fn is_separator(c: char) -> bool {
+    matches!(c, ' ' | '-' | '.' | '(' | ')' | '/')
+}
+
+/// Ways of writing an extension after a number, longest first so "ext." wins over "x".
+const EXTENSION_MARKERS: &[&str] = &[";ext=", "extension", "ext.", "ext", "x", "#"];
+
+/// Splits "020 7946 0958 ext. 123" into the number and the extension digits.
+/// Returns the whole input and None if it does not end in an extension.
+pub fn split_extension(raw: &str) -> (&str, Option<&str>) {
+    let raw = raw.trim();
+    // ASCII lowercasing keeps byte offsets the same as in `raw`.
+    let lowercase = raw.to_ascii_lowercase();
+    for marker in EXTENSION_MARKERS {
+        if let Some(position) = lowercase.rfind(marker) {
+            let extension = raw[position + marker.len()..].trim_start_matches([' ', ':']);
+            if !extension.is_empty() && extension.chars().all(|c| c.is_ascii_digit()) {
+                return (raw[..position].trim_end(), Some(extension));
+            }
+        }
+    }
+    (raw, None)
+}
+
+/// E.164 allows at most 15 digits, and a calling code alone is not a number.
+pub const E164_LENGTH: std::ops::RangeInclusive<usize> = 4..=15;
+
+/// Returns the E.164 form of `raw`, or None if it cannot be worked out.
+/// Numbers starting with "+" are always understood; other numbers need a default country.
+/// E.164 has no extensions, so any extension is left out.
+pub fn to_e164(raw: &str, default_country: Option<&Country>) -> Option<String> {
+    let digits = international_digits(raw, default_country)?;
+    if !E164_LENGTH.contains(&digits.len()) || digits.starts_with('0') {
+        return None;
+    }
+
+    Some(format!("+{digits}"))
+}
+
+/// The digits of `raw` with the calling code in front, without checking how many there are.
+pub fn international_digits(raw: &str, default_country: Option<&Country>) -> Option<String> {
+    let (raw, _extension) = split_extension(raw);
+    let (international, rest) = match raw.strip_prefix('+') {
+        Some(rest) => (true, rest),
+        None => (false, raw),
+    };
+
+    let mut digits = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+        } else if !is_separator(c) {
+            return None;
+        }
+    }
+
+    if international {
+        return Some(digits);
+    }
+
+    let country = default_country?;
+    if let Some(digits) = digits.strip_prefix(country.international_prefix) {
+        return Some(digits.to_owned());
+    }
+    let national = country
+        .trunk_prefix
+        .and_then(|trunk_prefix| digits.strip_prefix(trunk_prefix))
+        .unwrap_or(&digits);
+    Some(format!("{}{national}", country.calling_code))
+}
+
+/// The digits numbers are looked up by: those of the E.164 form when it can be worked out,
+/// otherwise all digits as they were typed. Extensions and formatting are left out.
+pub fn lookup_digits(raw: &str, default_country: Option<&Country>) -> String {
+    match to_e164(raw, default_country) {
+        Some(e164) => e164[1..].to_owned(),
+        None => split_extension(raw)
+            .0
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_country, lookup_digits, split_extension, to_e164};
+
+    #[test]
+    fn same_number_written_three_ways() {
+        let gb = find_country("gb");
+        for raw in ["+44 20 7946 0958", "020 7946 0958", "00442079460958"] {
+            assert_eq!(to_e164(raw, gb).as_deref(), Some("+442079460958"), "{raw}");
+        }
+    }
+
+    #[test]
+    fn lookup_digits_ignore_formatting() {
+        let gb = find_country("GB");
+        assert_eq!(lookup_digits("020 7946 0958 ext. 12", gb), "442079460958");
+        assert_eq!(lookup_digits("020 7946 0958 ext. 12", None), "02079460958");
+        assert_eq!(lookup_digits("(555) 0100", None), "5550100");
+    }
+
+    #[test]
+    fn extensions_are_split_off() {
+        assert_eq!(
+            split_extension("020 7946 0958 ext. 123"),
+            ("020 7946 0958", Some("123"))
+        );
+        assert_eq!(
+            split_extension("+1 212 555 0100 x42"),
+            ("+1 212 555 0100", Some("42"))
+        );
+        assert_eq!(
+            split_extension("+12125550100;ext=7"),
+            ("+12125550100", Some("7"))
+        );
+        assert_eq!(split_extension("0800 123 456"), ("0800 123 456", None));
+        assert_eq!(split_extension("123 ext abc"), ("123 ext abc", None));
+        assert_eq!(
+            to_e164("020 7946 0958 ext 12", find_country("GB")).as_deref(),
+            Some("+442079460958")
+        );
+    }
+
+    #[test]
+    fn national_numbers_need_a_country() {
+        assert_eq!(to_e164("020 7946 0958", None), None);
+        assert_eq!(
+            to_e164("+1 (212) 555-0100", None).as_deref(),
+            Some("+12125550100")
+        );
+    }
+
+    #[test]
+    fn country_specific_prefixes() {
+        let us = find_country("US");
+        assert_eq!(
+            to_e164("1-212-555-0100", us).as_deref(),
+            Some("+12125550100")
+        );
+        assert_eq!(
+            to_e164("011 44 20 7946 0958", us).as_deref(),
+            Some("+442079460958")
+        );
+        assert_eq!(
+            to_e164("06 1234 5678", find_country("IT")).as_deref(),
+            Some("+390612345678")
+        );
+        assert_eq!(
+            to_e164("8 495 123-45-67", find_country("RU")).as_deref(),
+            Some("+74951234567")
+        );
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_number() {
+        let gb = find_country("GB");
+        assert_eq!(to_e164("asdf", gb), None);
+        assert_eq!(to_e164("", gb), None);
+        assert_eq!(to_e164("+44", gb), None);
+        assert_eq!(to_e164("+0044 20 7946 0958", gb), None);
+        assert_eq!(to_e164("+1234567890123456", gb), None);
+        assert!(find_country("XX").is_none());
+    }
+}
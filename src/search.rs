@@ -0,0 +1,236 @@
+//! Matching names the way people type them, without caring about case or accents,
+//! so that "zoe" finds "Zoë" and "ARN" finds "Arnold".
+//!
+//! Every contact keeps a folded copy of its name in `phone_book.search_name`,
+//! which searches compare against the folded search text.
+//!
+//! Searching all details goes through the FTS5 table `contact_search` instead,
+//! which the database keeps in sync with triggers.
+
+use std::ops::Range;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Which part of a name the search text has to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameMatch {
+    /// The name starts with the text.
+    Prefix,
+    /// The text appears anywhere in the name.
+    Substring,
+}
+
+/// Lowercases `text` and strips its accents, also turning compatibility characters
+/// like "ﬁ" into their plain form.
+pub fn fold(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The `LIKE` pattern that finds folded names matching `text`, to be used with `ESCAPE '\'`.
+pub fn like_pattern(text: &str, name_match: NameMatch) -> String {
+    let mut pattern = String::new();
+    if name_match == NameMatch::Substring {
+        pattern.push('%');
+    }
+    for c in fold(text.trim()).chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Turns what the user typed into an FTS5 query that finds contacts having every term.
+/// `"two words"` is a phrase and `term*` matches every word starting with `term`;
+/// everything else is taken literally, so the query can never be a syntax error.
+/// Returns None when there is nothing to search for.
+pub fn full_text_query(text: &str) -> Option<String> {
+    let terms = terms(text)
+        .into_iter()
+        .map(|(term, prefix)| format!("\"{term}\"{}", if prefix { "*" } else { "" }))
+        .collect::<Vec<_>>();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Splits what the user typed into its words and phrases, each with whether it is a prefix.
+fn terms(text: &str) -> Vec<(&str, bool)> {
+    let mut terms = Vec::new();
+    // Every other piece between quotes is a phrase.
+    for (position, piece) in text.split('"').enumerate() {
+        let words = if position % 2 == 1 {
+            vec![piece]
+        } else {
+            piece.split_whitespace().collect()
+        };
+        for word in words {
+            let (word, prefix) = match word.trim().strip_suffix('*') {
+                Some(word) => (word.trim_end_matches('*').trim(), true),
+                None => (word.trim(), false),
+            };
+            if !word.is_empty() {
+                terms.push((word, prefix));
+            }
+        }
+    }
+    terms
+}
+
+/// A full-text query for matching text in memory, for phone books without an FTS5 index.
+/// It understands the same queries as `full_text_query` and matches words the way the
+/// `unicode61` tokenizer of `contact_search` splits them: runs of letters and digits,
+/// ignoring case and accents.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FullTextQuery {
+    /// The folded words of every term, and whether the last of them is a prefix.
+    terms: Vec<(Vec<String>, bool)>,
+}
+
+impl FullTextQuery {
+    /// Returns None when there is nothing to search for.
+    pub fn parse(text: &str) -> Option<FullTextQuery> {
+        let terms = terms(text)
+            .into_iter()
+            .map(|(term, prefix)| {
+                let words = words(term).into_iter().map(|(_, word)| word).collect();
+                (words, prefix)
+            })
+            .filter(|(words, _): &(Vec<String>, bool)| !words.is_empty())
+            .collect::<Vec<_>>();
+        (!terms.is_empty()).then_some(FullTextQuery { terms })
+    }
+
+    /// Finds the terms in `text`. Returns which of them were found,
+    /// and `text` with every match put between the `marks`.
+    pub fn highlight(&self, text: &str, marks: (&str, &str)) -> (Vec<bool>, String) {
+        let words = words(text);
+        let mut found = vec![false; self.terms.len()];
+        let mut matched = vec![false; words.len()];
+        for ((term, prefix), found) in self.terms.iter().zip(&mut found) {
+            for start in 0..words.len() {
+                let Some(candidates) = words.get(start..start + term.len()) else {
+                    break;
+                };
+                let last = term.len() - 1;
+                let matches = term.iter().zip(candidates).enumerate().all(
+                    |(position, (word, (_, candidate)))| {
+                        if *prefix && position == last {
+                            candidate.starts_with(word.as_str())
+                        } else {
+                            candidate == word
+                        }
+                    },
+                );
+                if matches {
+                    *found = true;
+                    matched[start..start + term.len()].fill(true);
+                }
+            }
+        }
+
+        // Neighbouring matched words share one pair of marks, as with FTS5.
+        let mut highlighted = String::new();
+        let mut copied = 0;
+        let mut position = 0;
+        while position < words.len() {
+            if !matched[position] {
+                position += 1;
+                continue;
+            }
+            let start = words[position].0.start;
+            while position + 1 < words.len() && matched[position + 1] {
+                position += 1;
+            }
+            let end = words[position].0.end;
+            highlighted.push_str(&text[copied..start]);
+            highlighted.push_str(marks.0);
+            highlighted.push_str(&text[start..end]);
+            highlighted.push_str(marks.1);
+            copied = end;
+            position += 1;
+        }
+        highlighted.push_str(&text[copied..]);
+
+        (found, highlighted)
+    }
+}
+
+/// The runs of letters and digits in `text`, folded, each with where it is in `text`.
+fn words(text: &str) -> Vec<(Range<usize>, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (position, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        // Combining marks belong to the letter before them.
+        let in_word = c.is_alphanumeric() || is_combining_mark(c) && start.is_some();
+        match (in_word, start) {
+            (true, None) => start = Some(position),
+            (false, Some(word_start)) => {
+                words.push((word_start..position, fold(&text[word_start..position])));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold, full_text_query, like_pattern, FullTextQuery, NameMatch};
+
+    #[test]
+    fn folds_case_and_accents() {
+        assert_eq!(fold("Zoë Ångström"), "zoe angstrom");
+        assert_eq!(fold("JOSÉ"), fold("jose\u{301}"));
+        assert_eq!(fold("Ｆｉｎｎ"), "finn");
+        assert_eq!(fold("Ǆemal"), "dzemal");
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(like_pattern(" Zoë ", NameMatch::Prefix), "zoe%");
+        assert_eq!(
+            like_pattern("100%_a\\b", NameMatch::Substring),
+            "%100\\%\\_a\\\\b%"
+        );
+    }
+
+    #[test]
+    fn full_text_queries_are_quoted() {
+        assert_eq!(
+            full_text_query(r#"arn* "Baker Street" O'Brien"#).as_deref(),
+            Some(r#""arn"* "Baker Street" "O'Brien""#)
+        );
+        assert_eq!(full_text_query(r#""Lond*"#).as_deref(), Some(r#""Lond"*"#));
+        assert_eq!(
+            full_text_query("NOT a OR b").as_deref(),
+            Some(r#""NOT" "a" "OR" "b""#)
+        );
+        assert_eq!(full_text_query(r#" * "" "#), None);
+    }
+
+    #[test]
+    fn full_text_queries_match_in_memory() {
+        let query = FullTextQuery::parse(r#"arn* "baker street" zoe"#).unwrap();
+
+        let (found, highlighted) = query.highlight("Arnold, 221B Baker Street", ("[", "]"));
+        assert_eq!(found, vec![true, true, false]);
+        assert_eq!(highlighted, "[Arnold], 221B [Baker Street]");
+        let (found, highlighted) = query.highlight("Zoë Barn", ("<", ">"));
+        assert_eq!(found, vec![false, false, true]);
+        assert_eq!(highlighted, "<Zoë> Barn");
+        assert_eq!(
+            query.highlight("Baker Streets", ("[", "]")).1,
+            "Baker Streets"
+        );
+        assert_eq!(FullTextQuery::parse(" - * "), None);
+    }
+}
@@ -0,0 +1,1136 @@
+//! Where contacts are kept, behind one trait so that applications can choose.
+//!
+//! `PhoneBookStore` has what reading and changing contacts takes. Three backends implement it:
+//! `PhoneBookDB` keeps contacts in SQLite, `MemoryStore` in a map that is gone when the program
+//! ends, and `JsonFileStore` in a JSON file that is rewritten on every change.
+//! Searching, importing and exporting have default implementations that go through the other
+//! methods, looking at every contact. `PhoneBookDB` replaces them with indexed queries and
+//! transactions of its own.
+
+use crate::{
+    database::{
+        AuditRecord, ImportSummary, NumberMatch, OnConflict, PhoneBookDB, SearchHit,
+        SimilarContact, StoreChange, WriteOutcome,
+    },
+    entry::{Contact, ContactId, PhoneEntry},
+    error::PhoneBookError,
+    fuzzy,
+    normalize::{self, Country},
+    search::{self, FullTextQuery, NameMatch},
+    validate,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// Reading, writing and searching contacts, whatever they are kept in.
+/// Contacts come back oldest first unless a method says otherwise.
+pub trait PhoneBookStore {
+    /// The file or directory the contacts are kept in, or None when they are only in memory.
+    fn file_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The country used to understand numbers written without a calling code.
+    fn default_country(&self) -> Option<&'static Country>;
+
+    /// Changes the default country, failing with `UnknownCountry` for codes that are not known.
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError>;
+
+    /// Adds a new contact, even if another contact already has the same name.
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError>;
+
+    /// Adds copies of contacts, keeping their UUIDs so that they are still recognized as the
+    /// same contacts, and returns the IDs they were given. Adds none when one is invalid.
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError>;
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError>;
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError>;
+
+    /// Replaces the details of the contact and returns the ones it had before.
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError>;
+
+    /// Changes the details, the name or both, so that either all of it happens or nothing
+    /// does. What is None is left as it is. Fails with `AlreadyExists` like `rename_contact`.
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError>;
+
+    /// Removes the contact and returns it as it was just before.
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError>;
+
+    /// Gives the contact a new name, failing with `AlreadyExists` if another contact has it.
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError>;
+
+    /// Makes the changes in order. Backends that can make them all at once, so that none is
+    /// made when one fails; this default makes them one by one and stops at the first failure,
+    /// keeping the ones made before.
+    fn apply_changes(&mut self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        for change in changes {
+            apply_change(self, change)?;
+        }
+        Ok(())
+    }
+
+    /// Returns every contact with exactly this name.
+    fn read_contacts_by_name(&self, name: &str) -> Result<Vec<Contact>, PhoneBookError> {
+        let mut contacts = self.read_all_contacts()?;
+        contacts.retain(|contact| contact.name == name);
+        Ok(contacts)
+    }
+
+    /// Updates the oldest contact with this name, keeping its ID, or creates it if there is none.
+    fn write_entry(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<WriteOutcome, PhoneBookError> {
+        match self.read_contacts_by_name(&name)?.into_iter().next() {
+            Some(existing) => Ok(WriteOutcome::Replaced {
+                id: existing.id,
+                previous: self.modify_contact(existing.id, entry)?,
+            }),
+            None => Ok(WriteOutcome::Created(self.insert_contact(name, entry)?)),
+        }
+    }
+
+    /// Replaces the details of the oldest contact with this name and returns the ones it had.
+    fn modify_entry(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        match self.read_contacts_by_name(&name)?.into_iter().next() {
+            Some(existing) => self.modify_contact(existing.id, entry),
+            None => Err(PhoneBookError::NotFound(name)),
+        }
+    }
+
+    /// Removes the oldest contact with this name and returns the details it had.
+    fn remove_entry(&mut self, name: &str) -> Result<PhoneEntry, PhoneBookError> {
+        match self.read_contacts_by_name(name)?.into_iter().next() {
+            Some(existing) => Ok(self.remove_contact(existing.id)?.entry),
+            None => Err(PhoneBookError::NotFound(name.to_owned())),
+        }
+    }
+
+    /// Returns every contact whose name matches `text`, ignoring case and accents.
+    /// Empty text matches every contact.
+    fn search_contacts(
+        &self,
+        text: &str,
+        name_match: NameMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        let text = search::fold(text.trim());
+        let mut contacts = self.read_all_contacts()?;
+        contacts.retain(|contact| {
+            let name = search::fold(&contact.name);
+            match name_match {
+                NameMatch::Prefix => name.starts_with(&text),
+                NameMatch::Substring => name.contains(&text),
+            }
+        });
+        Ok(contacts)
+    }
+
+    /// Searches the names, numbers, emails and addresses of all contacts, best matches first.
+    /// See `search::full_text_query` for what `query` may contain.
+    /// Matching words are put between the `marks`.
+    fn search_full_text(
+        &self,
+        query: &str,
+        marks: (&str, &str),
+    ) -> Result<Vec<SearchHit>, PhoneBookError> {
+        let Some(query) = FullTextQuery::parse(query) else {
+            return Ok(Vec::new());
+        };
+        let country = self.default_country();
+
+        let mut hits = Vec::new();
+        for contact in self.read_all_contacts()? {
+            let (mut found, name) = query.highlight(&contact.name, marks);
+            let name_matches = found.iter().filter(|found| **found).count();
+            // The same lines as in `contact_document`.
+            let entry = &contact.entry;
+            let lines = entry
+                .numbers
+                .iter()
+                .map(|number| {
+                    let e164 = normalize::to_e164(&number.number, country).unwrap_or_default();
+                    format!("{} {e164}", number.number).trim_end().to_owned()
+                })
+                .chain(entry.emails.iter().map(|email| email.address.clone()))
+                .chain(entry.addresses.iter().map(|address| address.to_string()));
+
+            let mut details = Vec::new();
+            for line in lines {
+                let (found_in_line, highlighted) = query.highlight(&line, marks);
+                for (found, found_in_line) in found.iter_mut().zip(found_in_line) {
+                    *found |= found_in_line;
+                }
+                if highlighted != line {
+                    details.push(highlighted);
+                }
+            }
+            if found.iter().all(|found| *found) {
+                hits.push((
+                    name_matches,
+                    SearchHit {
+                        contact,
+                        name,
+                        details,
+                    },
+                ));
+            }
+        }
+
+        // Name matches count for more than matches in the other details.
+        hits.sort_by_key(|(name_matches, _)| Reverse(*name_matches));
+        Ok(hits.into_iter().map(|(_, hit)| hit).collect())
+    }
+
+    /// Returns the contacts whose name is like `name`, allowing for typos, the most alike first.
+    fn find_similar_names(&self, name: &str) -> Result<Vec<SimilarContact>, PhoneBookError> {
+        let candidates = self.read_all_contacts()?.into_iter().map(|contact| {
+            let name = contact.name.clone();
+            (contact, name)
+        });
+        Ok(fuzzy::rank(name, candidates)
+            .into_iter()
+            .map(|(contact, score)| SimilarContact { contact, score })
+            .collect())
+    }
+
+    /// Returns every contact that has `number`, however it was written.
+    fn find_contacts_by_number(
+        &self,
+        number: &str,
+        number_match: NumberMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        let country = self.default_country();
+        let digits = normalize::lookup_digits(number, country);
+        if digits.is_empty() {
+            return Ok(Vec::new());
+        }
+        let has_number = |candidate: &str| match number_match {
+            NumberMatch::Whole => candidate == digits,
+            NumberMatch::LastDigits(count) => {
+                candidate.ends_with(&digits[digits.len().saturating_sub(count)..])
+            }
+        };
+
+        let mut contacts = self.read_all_contacts()?;
+        contacts.retain(|contact| {
+            contact
+                .entry
+                .numbers
+                .iter()
+                .any(|number| has_number(&normalize::lookup_digits(&number.number, country)))
+        });
+        Ok(contacts)
+    }
+
+    /// Adds all contacts, deciding with `on_conflict` what happens to the ones whose name is
+    /// taken, by the phone book or by an earlier imported contact. Every contact comes with
+    /// where it was read from, like "line 3", to point out the one that failed.
+    /// All contacts are checked before the first one is added, so that a mistake leaves the
+    /// phone book as it was; only failing to write can stop an import halfway.
+    fn import_contacts(
+        &mut self,
+        contacts: Vec<(String, String, PhoneEntry)>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, PhoneBookError> {
+        let country = self.default_country();
+        for (origin, _, entry) in &contacts {
+            validate::validate_entry(entry, country)
+                .map_err(|err| PhoneBookError::Import(format!("{origin}: {err}")))?;
+        }
+
+        let mut summary = ImportSummary::default();
+        for (origin, name, entry) in contacts {
+            let existing = self.read_contacts_by_name(&name)?.into_iter().next();
+            let imported = match (existing, on_conflict) {
+                (None, _) => self
+                    .insert_contact(name, entry)
+                    .map(|_| summary.created += 1),
+                (Some(_), OnConflict::Skip) => {
+                    summary.skipped += 1;
+                    Ok(())
+                }
+                (Some(existing), OnConflict::Overwrite) => self
+                    .modify_contact(existing.id, entry)
+                    .map(|_| summary.overwritten += 1),
+                (Some(existing), OnConflict::Merge) => {
+                    let mut merged = existing.entry;
+                    merged.merge(entry, country);
+                    self.modify_contact(existing.id, merged)
+                        .map(|_| summary.merged += 1)
+                }
+            };
+            imported.map_err(|err| PhoneBookError::Import(format!("{origin}: {err}")))?;
+        }
+        Ok(summary)
+    }
+
+    /// Returns every recorded change of the contacts that ever had this name, oldest first,
+    /// or None when the store keeps no audit log. Only `PhoneBookDB` keeps one.
+    fn read_audit_log_by_name(
+        &self,
+        _name: &str,
+    ) -> Result<Option<Vec<AuditRecord>>, PhoneBookError> {
+        Ok(None)
+    }
+
+    /// Writes a copy of the whole phone book to a new SQLite database file.
+    fn export_copy(&self, file_path: &Path) -> Result<(), PhoneBookError> {
+        let mut copy = PhoneBookDB::new(None)?;
+        copy.set_default_country(self.default_country().map(|country| country.code))?;
+        copy.copy_contacts(&self.read_all_contacts()?)?;
+        copy.export_copy(file_path)
+    }
+}
+
+/// Makes one change of `apply_changes` through the single-change methods.
+fn apply_change<S: PhoneBookStore + ?Sized>(
+    store: &mut S,
+    change: StoreChange,
+) -> Result<(), PhoneBookError> {
+    match change {
+        StoreChange::Remove(id) => {
+            store.remove_contact(id)?;
+        }
+        StoreChange::Copy(contacts) => {
+            store.copy_contacts(&contacts)?;
+        }
+        StoreChange::Edit {
+            id,
+            entry,
+            new_name,
+        } => store.edit_contact(id, entry, new_name)?,
+    }
+    Ok(())
+}
+
+/// The inherent methods of `PhoneBookDB` are called by path,
+/// because taking `&mut self` would otherwise pick the trait methods again.
+impl PhoneBookStore for PhoneBookDB {
+    fn file_path(&self) -> Option<&Path> {
+        PhoneBookDB::file_path(self)
+    }
+
+    fn default_country(&self) -> Option<&'static Country> {
+        PhoneBookDB::default_country(self)
+    }
+
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        PhoneBookDB::set_default_country(self, code)
+    }
+
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        PhoneBookDB::insert_contact(self, name, entry)
+    }
+
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        PhoneBookDB::copy_contacts(self, contacts)
+    }
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        PhoneBookDB::read_contact(self, id)
+    }
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        PhoneBookDB::read_all_contacts(self)
+    }
+
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        PhoneBookDB::modify_contact(self, id, entry)
+    }
+
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        PhoneBookDB::edit_contact(self, id, entry, new_name)
+    }
+
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        PhoneBookDB::remove_contact(self, id)
+    }
+
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        PhoneBookDB::rename_contact(self, id, new_name)
+    }
+
+    fn apply_changes(&mut self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        PhoneBookDB::apply_changes(self, changes)
+    }
+
+    fn read_contacts_by_name(&self, name: &str) -> Result<Vec<Contact>, PhoneBookError> {
+        PhoneBookDB::read_contacts_by_name(self, name)
+    }
+
+    fn write_entry(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<WriteOutcome, PhoneBookError> {
+        PhoneBookDB::write_entry(self, name, entry)
+    }
+
+    fn modify_entry(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        PhoneBookDB::modify_entry(self, name, entry)
+    }
+
+    fn remove_entry(&mut self, name: &str) -> Result<PhoneEntry, PhoneBookError> {
+        PhoneBookDB::remove_entry(self, name)
+    }
+
+    fn search_contacts(
+        &self,
+        text: &str,
+        name_match: NameMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        PhoneBookDB::search_contacts(self, text, name_match)
+    }
+
+    fn find_similar_names(&self, name: &str) -> Result<Vec<SimilarContact>, PhoneBookError> {
+        PhoneBookDB::find_similar_names(self, name)
+    }
+
+    fn find_contacts_by_number(
+        &self,
+        number: &str,
+        number_match: NumberMatch,
+    ) -> Result<Vec<Contact>, PhoneBookError> {
+        PhoneBookDB::find_contacts_by_number(self, number, number_match)
+    }
+
+    fn search_full_text(
+        &self,
+        query: &str,
+        marks: (&str, &str),
+    ) -> Result<Vec<SearchHit>, PhoneBookError> {
+        PhoneBookDB::search_full_text(self, query, marks)
+    }
+
+    fn import_contacts(
+        &mut self,
+        contacts: Vec<(String, String, PhoneEntry)>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, PhoneBookError> {
+        PhoneBookDB::import_contacts(self, contacts, on_conflict)
+    }
+
+    fn read_audit_log_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<Vec<AuditRecord>>, PhoneBookError> {
+        PhoneBookDB::read_audit_log_by_name(self, name).map(Some)
+    }
+
+    fn export_copy(&self, file_path: &Path) -> Result<(), PhoneBookError> {
+        PhoneBookDB::export_copy(self, file_path)
+    }
+}
+
+/// Contacts kept in memory only, for tests and for programs that load contacts from elsewhere.
+/// Checks numbers the way the database does.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MemoryStore {
+    #[serde(with = "country_code")]
+    default_country: Option<&'static Country>,
+    /// Never goes down, so that IDs of removed contacts are not handed out again.
+    next_id: i64,
+    #[serde(with = "contact_list")]
+    contacts: BTreeMap<ContactId, Contact>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn existing_contact(&mut self, id: ContactId) -> Result<&mut Contact, PhoneBookError> {
+        self.contacts
+            .get_mut(&id)
+            .ok_or_else(|| PhoneBookError::NotFound(format!("ID {id}")))
+    }
+
+    /// Adds a contact whose details were already checked.
+    fn add_contact(&mut self, uuid: Uuid, name: String, entry: PhoneEntry) -> ContactId {
+        self.next_id += 1;
+        let id = ContactId(self.next_id);
+        self.contacts.insert(
+            id,
+            Contact {
+                id,
+                uuid,
+                name,
+                entry,
+            },
+        );
+        id
+    }
+}
+
+impl PhoneBookStore for MemoryStore {
+    fn default_country(&self) -> Option<&'static Country> {
+        self.default_country
+    }
+
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        self.default_country = match code {
+            Some(code) => Some(
+                normalize::find_country(code)
+                    .ok_or_else(|| PhoneBookError::UnknownCountry(code.to_owned()))?,
+            ),
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        validate::validate_entry(&entry, self.default_country)?;
+        Ok(self.add_contact(Uuid::new_v4(), name, entry))
+    }
+
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        for contact in contacts {
+            validate::validate_entry(&contact.entry, self.default_country)?;
+        }
+        Ok(contacts
+            .iter()
+            .map(|contact| {
+                self.add_contact(contact.uuid, contact.name.clone(), contact.entry.clone())
+            })
+            .collect())
+    }
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        Ok(self.contacts.get(&id).cloned())
+    }
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        Ok(self.contacts.values().cloned().collect())
+    }
+
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        let country = self.default_country;
+        let contact = self.existing_contact(id)?;
+        validate::validate_new_numbers(&entry, &contact.entry, country)?;
+        Ok(std::mem::replace(&mut contact.entry, entry))
+    }
+
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        if let Some(new_name) = &new_name {
+            let taken = self
+                .contacts
+                .values()
+                .any(|contact| contact.name == *new_name && contact.id != id);
+            if taken {
+                return Err(PhoneBookError::AlreadyExists(new_name.clone()));
+            }
+        }
+        let country = self.default_country;
+        let contact = self.existing_contact(id)?;
+        if let Some(entry) = entry {
+            validate::validate_new_numbers(&entry, &contact.entry, country)?;
+            contact.entry = entry;
+        }
+        if let Some(new_name) = new_name {
+            contact.name = new_name;
+        }
+        Ok(())
+    }
+
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        self.contacts
+            .remove(&id)
+            .ok_or_else(|| PhoneBookError::NotFound(format!("ID {id}")))
+    }
+
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        let taken = self
+            .contacts
+            .values()
+            .any(|contact| contact.name == new_name && contact.id != id);
+        if taken {
+            return Err(PhoneBookError::AlreadyExists(new_name));
+        }
+        self.existing_contact(id)?.name = new_name;
+        Ok(())
+    }
+
+    /// Makes the changes to a copy, which replaces the contacts only when all of them worked.
+    fn apply_changes(&mut self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        let mut changed = self.clone();
+        for change in changes {
+            apply_change(&mut changed, change)?;
+        }
+        *self = changed;
+        Ok(())
+    }
+}
+
+/// Contacts kept in a JSON file, which is read once when opened and written again after
+/// every change. The file is replaced in one step, so it is never left half written,
+/// and a change that cannot be written is not made at all.
+///
+/// The file looks like `{"format": "phone-book-store", "version": 1, "default_country": "GB",
+/// "next_id": 3, "contacts": [...]}`, with contacts as in `json_io`. Unlike the exports of
+/// `json_io` it keeps what is needed to hand out IDs, so it cannot be imported as it is.
+#[derive(Debug)]
+pub struct JsonFileStore {
+    file_path: PathBuf,
+    contacts: MemoryStore,
+}
+
+/// The `format` of the file.
+pub const JSON_STORE_FORMAT: &str = "phone-book-store";
+/// The `version` of the files written.
+pub const JSON_STORE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    format: String,
+    version: u32,
+    #[serde(flatten)]
+    contacts: MemoryStore,
+}
+
+impl JsonFileStore {
+    /// Opens the store in `file_path`. A file that does not exist yet is created on the first change.
+    pub fn open(file_path: impl Into<PathBuf>) -> Result<JsonFileStore, PhoneBookError> {
+        let file_path = file_path.into();
+        let contacts = match fs::read_to_string(&file_path) {
+            Ok(text) => {
+                let file = serde_json::from_str::<StoreFile>(&text)?;
+                if file.format != JSON_STORE_FORMAT {
+                    return Err(PhoneBookError::Import(format!(
+                        "the format is {:?}, not {JSON_STORE_FORMAT:?}",
+                        file.format
+                    )));
+                }
+                if !(1..=JSON_STORE_VERSION).contains(&file.version) {
+                    return Err(PhoneBookError::Import(format!(
+                        "format version {} is not supported, only up to version {JSON_STORE_VERSION}",
+                        file.version
+                    )));
+                }
+                file.contacts
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => MemoryStore::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(JsonFileStore {
+            file_path,
+            contacts,
+        })
+    }
+
+    /// Makes a change to a copy of the contacts and keeps it only once the file is written.
+    fn change<T>(
+        &mut self,
+        f: impl FnOnce(&mut MemoryStore) -> Result<T, PhoneBookError>,
+    ) -> Result<T, PhoneBookError> {
+        let mut changed = self.contacts.clone();
+        let result = f(&mut changed)?;
+        self.save(changed)?;
+        Ok(result)
+    }
+
+    fn save(&mut self, contacts: MemoryStore) -> Result<(), PhoneBookError> {
+        let file = StoreFile {
+            format: JSON_STORE_FORMAT.to_owned(),
+            version: JSON_STORE_VERSION,
+            contacts,
+        };
+        let mut text = serde_json::to_vec_pretty(&file)?;
+        text.push(b'\n');
+        write_file_atomically(&self.file_path, &text)?;
+
+        self.contacts = file.contacts;
+        Ok(())
+    }
+}
+
+impl PhoneBookStore for JsonFileStore {
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.file_path)
+    }
+
+    fn default_country(&self) -> Option<&'static Country> {
+        self.contacts.default_country()
+    }
+
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        self.change(|contacts| contacts.set_default_country(code))
+    }
+
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        self.change(|contacts| contacts.insert_contact(name, entry))
+    }
+
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        self.change(|store| store.copy_contacts(contacts))
+    }
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        self.contacts.read_contact(id)
+    }
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        self.contacts.read_all_contacts()
+    }
+
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        self.change(|contacts| contacts.modify_contact(id, entry))
+    }
+
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        self.change(|contacts| contacts.edit_contact(id, entry, new_name))
+    }
+
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        self.change(|contacts| contacts.remove_contact(id))
+    }
+
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        self.change(|contacts| contacts.rename_contact(id, new_name))
+    }
+
+    fn apply_changes(&mut self, changes: Vec<StoreChange>) -> Result<(), PhoneBookError> {
+        self.change(|contacts| contacts.apply_changes(changes))
+    }
+
+    /// Unlike the default, writes the file once, and only if the whole import worked.
+    fn import_contacts(
+        &mut self,
+        contacts: Vec<(String, String, PhoneEntry)>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, PhoneBookError> {
+        self.change(|store| store.import_contacts(contacts, on_conflict))
+    }
+}
+
+/// Writes `contents` to a temporary file next to `file_path` and renames it over `file_path`,
+/// so that other programs see either the old contents or the new ones, never a mix.
+/// The temporary file starts with a dot, which sync tools take as a file to leave alone.
+pub fn write_file_atomically(file_path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = file_path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file name", file_path.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = file_path.with_file_name(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, file_path)
+}
+
+/// Writes the default country as its code, like the `setting` table of the database does.
+mod country_code {
+    use crate::normalize::{self, Country};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        country: &Option<&'static Country>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match country {
+            Some(country) => serializer.serialize_some(country.code),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<&'static Country>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(code) => normalize::find_country(&code)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("unknown country code {code:?}"))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Writes contacts as a list, since their IDs are in them already.
+mod contact_list {
+    use crate::entry::{Contact, ContactId};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        contacts: &BTreeMap<ContactId, Contact>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(contacts.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<ContactId, Contact>, D::Error> {
+        Ok(Vec::<Contact>::deserialize(deserializer)?
+            .into_iter()
+            .map(|contact| (contact.id, contact))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonFileStore, MemoryStore, PhoneBookStore};
+    use crate::{
+        database::{
+            ImportSummary, NumberMatch, OnConflict, PhoneBookDB, StoreChange, WriteOutcome,
+        },
+        entry::{Contact, ContactId, NumberLabel, PhoneEntry, PhoneNumber},
+        error::PhoneBookError,
+        search::NameMatch,
+    };
+    use std::fs;
+
+    fn entry(number: &str) -> PhoneEntry {
+        PhoneEntry {
+            numbers: vec![PhoneNumber::new(NumberLabel::Work, number)],
+            ..PhoneEntry::default()
+        }
+    }
+
+    /// What every backend has to do the same way.
+    fn behaves_like_a_phone_book(store: &mut impl PhoneBookStore) {
+        store.set_default_country(Some("GB")).unwrap();
+        let arnold = store
+            .insert_contact("Arnold Baker".to_owned(), entry("020 7946 0958"))
+            .unwrap();
+        let zoe = store
+            .insert_contact("Zoë".to_owned(), entry("+1 212 555 0100"))
+            .unwrap();
+        let other_zoe = store
+            .insert_contact("Zoë".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert!(arnold < zoe && zoe < other_zoe);
+
+        let ids = |contacts: Vec<Contact>| {
+            contacts
+                .into_iter()
+                .map(|contact| contact.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(store.read_all_contacts().unwrap()),
+            vec![arnold, zoe, other_zoe]
+        );
+        assert_eq!(
+            ids(store.read_contacts_by_name("Zoë").unwrap()),
+            vec![zoe, other_zoe]
+        );
+        assert_eq!(
+            ids(store.search_contacts("zoe", NameMatch::Prefix).unwrap()),
+            vec![zoe, other_zoe]
+        );
+        assert_eq!(
+            ids(store.search_contacts("BAK", NameMatch::Substring).unwrap()),
+            vec![arnold]
+        );
+        assert_eq!(
+            ids(store
+                .find_contacts_by_number("+44 20 7946 0958", NumberMatch::Whole)
+                .unwrap()),
+            vec![arnold]
+        );
+        assert_eq!(
+            ids(store
+                .find_contacts_by_number("555-0100", NumberMatch::LastDigits(7))
+                .unwrap()),
+            vec![zoe]
+        );
+        let similar = store.find_similar_names("Arnlod Baker").unwrap();
+        assert_eq!(similar[0].contact.id, arnold);
+        let hits = store.search_full_text("baker 0958", ("[", "]")).unwrap();
+        assert_eq!(
+            ids(hits.iter().map(|hit| hit.contact.clone()).collect()),
+            vec![arnold]
+        );
+        assert_eq!(hits[0].name, "Arnold [Baker]");
+        assert_eq!(hits[0].details, vec!["020 7946 [0958] +442079460958"]);
+        assert!(store
+            .search_full_text("baker zoe", ("[", "]"))
+            .unwrap()
+            .is_empty());
+
+        let previous = store.modify_contact(zoe, entry("+1 212 555 0199")).unwrap();
+        assert_eq!(previous, entry("+1 212 555 0100"));
+        assert!(matches!(
+            store.modify_contact(zoe, entry("12")),
+            Err(PhoneBookError::InvalidNumber { .. })
+        ));
+        assert!(matches!(
+            store.rename_contact(other_zoe, "Arnold Baker".to_owned()),
+            Err(PhoneBookError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            store.edit_contact(
+                other_zoe,
+                Some(entry("+1 212 555 0123")),
+                Some("Arnold Baker".to_owned())
+            ),
+            Err(PhoneBookError::AlreadyExists(_))
+        ));
+        assert_eq!(
+            store.read_contact(other_zoe).unwrap().unwrap().entry,
+            PhoneEntry::default(),
+            "a failed rename must not change the details either"
+        );
+        store
+            .edit_contact(
+                other_zoe,
+                Some(entry("+1 212 555 0123")),
+                Some("Zoey".to_owned()),
+            )
+            .unwrap();
+        assert_eq!(
+            store.read_contact(other_zoe).unwrap().unwrap().entry,
+            entry("+1 212 555 0123")
+        );
+        store
+            .edit_contact(other_zoe, Some(PhoneEntry::default()), None)
+            .unwrap();
+        assert!(matches!(
+            store.apply_changes(vec![
+                StoreChange::Remove(zoe),
+                StoreChange::Edit {
+                    id: other_zoe,
+                    entry: None,
+                    new_name: Some("Arnold Baker".to_owned()),
+                },
+            ]),
+            Err(PhoneBookError::AlreadyExists(_))
+        ));
+        assert!(
+            store.read_contact(zoe).unwrap().is_some(),
+            "no change is made when one of them fails"
+        );
+        store.rename_contact(other_zoe, "Zoe".to_owned()).unwrap();
+        assert_eq!(store.read_contact(other_zoe).unwrap().unwrap().name, "Zoe");
+        assert_eq!(
+            store
+                .modify_entry("Zoë".to_owned(), entry("+1 212 555 0100"))
+                .unwrap(),
+            entry("+1 212 555 0199")
+        );
+        assert_eq!(
+            store
+                .write_entry("Zoë".to_owned(), entry("+1 212 555 0199"))
+                .unwrap(),
+            WriteOutcome::Replaced {
+                id: zoe,
+                previous: entry("+1 212 555 0100"),
+            }
+        );
+
+        let removed = store.remove_contact(zoe).unwrap();
+        assert_eq!(removed.entry, entry("+1 212 555 0199"));
+        assert!(store.read_contact(zoe).unwrap().is_none());
+        assert!(matches!(
+            store.remove_contact(zoe),
+            Err(PhoneBookError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.remove_entry("Zoë"),
+            Err(PhoneBookError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.set_default_country(Some("XX")),
+            Err(PhoneBookError::UnknownCountry(_))
+        ));
+        // "020 7946 0958" is too long for France, but numbers that are stored already
+        // must not keep a contact from being changed.
+        store.set_default_country(Some("FR")).unwrap();
+        let mut more = entry("020 7946 0958");
+        more.numbers
+            .push(PhoneNumber::new(NumberLabel::Mobile, "+1 212 555 0123"));
+        store.modify_contact(arnold, more).unwrap();
+        assert!(matches!(
+            store.modify_contact(arnold, entry("020 7946 0000")),
+            Err(PhoneBookError::InvalidNumber { .. })
+        ));
+        store.set_default_country(Some("GB")).unwrap();
+
+        let newest = store
+            .insert_contact("Jack".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert!(newest > other_zoe, "IDs are not handed out again");
+        assert_eq!(store.read_contact(ContactId(0)).unwrap(), None);
+
+        let imported =
+            |name: &str, entry: PhoneEntry| (format!("contact {name}"), name.to_owned(), entry);
+        let failed = store.import_contacts(
+            vec![
+                imported("Mark", PhoneEntry::default()),
+                imported("Ann", entry("12")),
+            ],
+            OnConflict::Skip,
+        );
+        assert!(
+            matches!(failed, Err(PhoneBookError::Import(message)) if message.starts_with("contact Ann"))
+        );
+        assert!(store.read_contacts_by_name("Mark").unwrap().is_empty());
+        let summary = store
+            .import_contacts(
+                vec![
+                    imported("Mark", PhoneEntry::default()),
+                    imported("Jack", entry("+44 20 7946 0000")),
+                    imported("Zoe", PhoneEntry::default()),
+                ],
+                OnConflict::Merge,
+            )
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                created: 1,
+                merged: 2,
+                ..ImportSummary::default()
+            }
+        );
+        assert_eq!(
+            store.read_contact(newest).unwrap().unwrap().entry,
+            entry("+44 20 7946 0000")
+        );
+
+        let file_path =
+            std::env::temp_dir().join(format!("phone_book_store_{}.sqlite", uuid::Uuid::new_v4()));
+        store.export_copy(&file_path).unwrap();
+        let copy = PhoneBookDB::open_copy(&file_path).unwrap();
+        fs::remove_file(file_path).unwrap();
+        let without_ids = |contacts: Vec<Contact>| {
+            contacts
+                .into_iter()
+                .map(|contact| (contact.uuid, contact.name, contact.entry))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            without_ids(copy.read_all_contacts().unwrap()),
+            without_ids(store.read_all_contacts().unwrap())
+        );
+        assert_eq!(copy.default_country().unwrap().code, "GB");
+    }
+
+    #[test]
+    fn every_backend_behaves_the_same() {
+        behaves_like_a_phone_book(&mut PhoneBookDB::new(None).unwrap());
+        behaves_like_a_phone_book(&mut MemoryStore::new());
+
+        let file_path =
+            std::env::temp_dir().join(format!("phone_book_store_{}.json", uuid::Uuid::new_v4()));
+        behaves_like_a_phone_book(&mut JsonFileStore::open(&file_path).unwrap());
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn json_files_keep_everything() {
+        let file_path =
+            std::env::temp_dir().join(format!("phone_book_store_{}.json", uuid::Uuid::new_v4()));
+        let mut store = JsonFileStore::open(&file_path).unwrap();
+        assert!(!file_path.exists(), "nothing is written before a change");
+        store.set_default_country(Some("GB")).unwrap();
+        let arnold = store
+            .insert_contact("Arnold".to_owned(), entry("020 7946 0958"))
+            .unwrap();
+        let jack = store
+            .insert_contact("Jack".to_owned(), PhoneEntry::default())
+            .unwrap();
+        store.remove_contact(jack).unwrap();
+        assert!(store.modify_contact(arnold, entry("12")).is_err());
+
+        let reopened = JsonFileStore::open(&file_path).unwrap();
+        assert_eq!(reopened.default_country().unwrap().code, "GB");
+        assert_eq!(
+            reopened.read_all_contacts().unwrap(),
+            store.read_all_contacts().unwrap()
+        );
+        let mut reopened = reopened;
+        let next = reopened
+            .insert_contact("Mark".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert!(next > jack);
+
+        fs::write(
+            &file_path,
+            r#"{"format": "phone-book", "version": 1, "contacts": []}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            JsonFileStore::open(&file_path),
+            Err(PhoneBookError::Import(_))
+        ));
+        fs::remove_file(file_path).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+//! Checks that a phone number looks like one before it is stored.
+
+use crate::{
+    entry::{PhoneEntry, PhoneNumber},
+    error::PhoneBookError,
+    normalize::{self, Country},
+};
+use std::{fmt, ops::RangeInclusive};
+
+/// Why a phone number was rejected.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Invalid {
+    Empty,
+    /// The character is neither a digit nor one of the separators people use.
+    Character(char),
+    /// A "+" is only allowed at the very start.
+    MisplacedPlus,
+    UnbalancedParentheses,
+    /// Something that starts like an extension ("x", "ext") is not followed by 1 to 6 digits.
+    Extension,
+    /// No country uses this calling code.
+    CallingCode,
+    TooShort {
+        digits: usize,
+        min: usize,
+    },
+    TooLong {
+        digits: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::Empty => write!(f, "the number is empty"),
+            Invalid::Character(c) => write!(f, "{c:?} is not allowed in a phone number"),
+            Invalid::MisplacedPlus => write!(f, "\"+\" is only allowed at the start"),
+            Invalid::UnbalancedParentheses => write!(f, "the parentheses do not match"),
+            Invalid::Extension => {
+                write!(
+                    f,
+                    "an extension must be written like \"x123\" or \"ext. 123\""
+                )
+            }
+            Invalid::CallingCode => write!(f, "the calling code does not exist"),
+            Invalid::TooShort { digits, min } => {
+                write!(f, "{digits} digits is too short, at least {min} are needed")
+            }
+            Invalid::TooLong { digits, max } => {
+                write!(f, "{digits} digits is too long, at most {max} are allowed")
+            }
+        }
+    }
+}
+
+/// Numbers without a known country are checked against these limits.
+/// Three digits still allows emergency and other short numbers.
+const ANY_LENGTH: RangeInclusive<usize> = 3..=15;
+
+const MAX_EXTENSION_LENGTH: usize = 6;
+
+/// How many digits may follow each calling code (the national significant number).
+/// Calling codes are prefix free, so at most one of them matches the start of a number.
+const NATIONAL_LENGTHS: &[(&str, RangeInclusive<usize>)] = &[
+    ("1", 10..=10),
+    ("7", 10..=10),
+    ("20", 8..=10),
+    ("27", 9..=9),
+    ("30", 10..=10),
+    ("31", 9..=10),
+    ("32", 8..=9),
+    ("33", 9..=9),
+    ("34", 9..=9),
+    ("39", 6..=11),
+    ("41", 9..=9),
+    ("43", 4..=13),
+    ("44", 7..=10),
+    ("45", 8..=8),
+    ("46", 7..=10),
+    ("47", 5..=8),
+    ("48", 9..=9),
+    ("49", 5..=13),
+    ("52", 10..=10),
+    ("54", 10..=11),
+    ("55", 10..=11),
+    ("61", 9..=9),
+    ("64", 8..=10),
+    ("65", 8..=8),
+    ("81", 9..=10),
+    ("82", 8..=10),
+    ("86", 9..=11),
+    ("90", 10..=10),
+    ("91", 10..=10),
+    ("92", 9..=10),
+    ("93", 9..=9),
+    ("98", 10..=10),
+    ("234", 8..=10),
+    ("254", 9..=9),
+    ("351", 9..=9),
+    ("352", 4..=11),
+    ("353", 7..=9),
+    ("358", 5..=12),
+    ("380", 9..=9),
+    ("420", 9..=9),
+    ("852", 8..=8),
+    ("966", 9..=9),
+    ("971", 8..=9),
+    ("972", 8..=9),
+];
+
+/// The spare codes of the ITU list of E.164 calling codes, which no country uses.
+/// A code of two digits stands for all ten codes starting with it.
+const SPARE_CALLING_CODES: &[&str] = &[
+    "28", "83", "89", "210", "214", "215", "217", "219", "259", "292", "293", "294", "295", "296",
+    "384", "422", "424", "425", "426", "427", "428", "429", "693", "694", "695", "696", "697",
+    "698", "699", "801", "802", "803", "804", "805", "806", "807", "809", "851", "854", "857",
+    "858", "859", "884", "885", "887", "889", "978", "990", "997", "999",
+];
+
+/// Calling codes that are not assigned to any country: the spare codes, and everything
+/// starting with 0, which no calling code does.
+fn is_unassigned_calling_code(digits: &str) -> bool {
+    digits.starts_with('0')
+        || SPARE_CALLING_CODES
+            .iter()
+            .any(|code| digits.starts_with(code))
+}
+
+/// Checks every number of `entry`, failing on the first one that is invalid.
+pub fn validate_entry(
+    entry: &PhoneEntry,
+    default_country: Option<&Country>,
+) -> Result<(), PhoneBookError> {
+    validate_numbers(&entry.numbers, default_country)
+}
+
+/// Checks the numbers of `entry` that `previous` does not have, failing on the first one that
+/// is invalid. Numbers that were stored before are not checked again, so that a contact can
+/// still be changed after the default country changed to one its numbers do not fit.
+pub fn validate_new_numbers(
+    entry: &PhoneEntry,
+    previous: &PhoneEntry,
+    default_country: Option<&Country>,
+) -> Result<(), PhoneBookError> {
+    let is_new = |number: &&PhoneNumber| {
+        !previous
+            .numbers
+            .iter()
+            .any(|known| known.number == number.number)
+    };
+    validate_numbers(entry.numbers.iter().filter(is_new), default_country)
+}
+
+fn validate_numbers<'a>(
+    numbers: impl IntoIterator<Item = &'a PhoneNumber>,
+    default_country: Option<&Country>,
+) -> Result<(), PhoneBookError> {
+    for number in numbers {
+        validate(&number.number, default_country).map_err(|reason| {
+            PhoneBookError::InvalidNumber {
+                number: number.number.clone(),
+                reason,
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks the number, using `default_country` to understand numbers written without a calling code.
+pub fn validate(raw: &str, default_country: Option<&Country>) -> Result<(), Invalid> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Err(Invalid::Empty);
+    }
+
+    let (number, extension) = normalize::split_extension(raw);
+    if extension.is_some_and(|extension| extension.len() > MAX_EXTENSION_LENGTH) {
+        return Err(Invalid::Extension);
+    }
+
+    let mut depth = 0;
+    let mut digits = 0;
+    for (position, c) in number.char_indices() {
+        match c {
+            '0'..='9' => digits += 1,
+            '+' if position == 0 => {}
+            '+' => return Err(Invalid::MisplacedPlus),
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(Invalid::UnbalancedParentheses),
+            ')' => depth -= 1,
+            c if normalize::is_separator(c) => {}
+            c => {
+                let rest = number[position..].to_ascii_lowercase();
+                return Err(if rest.starts_with("ext") || rest.starts_with('x') {
+                    Invalid::Extension
+                } else {
+                    Invalid::Character(c)
+                });
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(Invalid::UnbalancedParentheses);
+    }
+
+    let Some(digits) = normalize::international_digits(number, default_country) else {
+        // Without a calling code or a country, the length is all that can be checked.
+        return check_length(digits, &ANY_LENGTH);
+    };
+    if is_unassigned_calling_code(&digits) {
+        return Err(Invalid::CallingCode);
+    }
+
+    match NATIONAL_LENGTHS
+        .iter()
+        .find(|(calling_code, _)| digits.starts_with(calling_code))
+    {
+        Some((calling_code, lengths)) => check_length(digits.len() - calling_code.len(), lengths),
+        None => check_length(digits.len(), &normalize::E164_LENGTH),
+    }
+}
+
+fn check_length(digits: usize, lengths: &RangeInclusive<usize>) -> Result<(), Invalid> {
+    if digits < *lengths.start() {
+        Err(Invalid::TooShort {
+            digits,
+            min: *lengths.start(),
+        })
+    } else if digits > *lengths.end() {
+        Err(Invalid::TooLong {
+            digits,
+            max: *lengths.end(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, Invalid};
+    use crate::normalize::find_country;
+
+    #[test]
+    fn accepts_numbers_as_people_write_them() {
+        let gb = find_country("GB");
+        for number in [
+            "+44 20 7946 0958",
+            "020 7946 0958",
+            "(020) 7946-0958",
+            "00442079460958",
+            "020 7946 0958 ext. 123",
+            "+1 (212) 555-0100 x42",
+            "+12125550100;ext=7",
+        ] {
+            assert_eq!(validate(number, gb), Ok(()), "{number}");
+        }
+        // Tunisia, and the international freephone and shared cost numbers.
+        assert_eq!(validate("+216 71 234 567", gb), Ok(()));
+        assert_eq!(validate("+800 1234 5678", gb), Ok(()));
+        assert_eq!(validate("+808 1234 5678", gb), Ok(()));
+        // Without a country only the number of digits can be checked.
+        assert_eq!(validate("9027590", None), Ok(()));
+        assert_eq!(validate("999", None), Ok(()));
+    }
+
+    #[test]
+    fn rejects_garbage_with_a_reason() {
+        let gb = find_country("GB");
+        assert_eq!(validate("", gb), Err(Invalid::Empty));
+        assert_eq!(validate("   ", None), Err(Invalid::Empty));
+        assert_eq!(validate("asdf", gb), Err(Invalid::Character('a')));
+        assert_eq!(validate("020 7946 0958!", gb), Err(Invalid::Character('!')));
+        assert_eq!(validate("020 +7946", gb), Err(Invalid::MisplacedPlus));
+        assert_eq!(
+            validate("(020 7946 0958", gb),
+            Err(Invalid::UnbalancedParentheses)
+        );
+        assert_eq!(validate("020 7946 0958 ext", gb), Err(Invalid::Extension));
+        assert_eq!(
+            validate("020 7946 0958 x1234567", gb),
+            Err(Invalid::Extension)
+        );
+        assert_eq!(validate("+0 123 456 789", gb), Err(Invalid::CallingCode));
+        assert_eq!(validate("+28 123 456 789", gb), Err(Invalid::CallingCode));
+        for number in [
+            "+801 1234 5678",
+            "+259 123 456",
+            "+384 123 456",
+            "+697 123 456",
+            "+889 123 456",
+            "+999 123 456 789",
+        ] {
+            assert_eq!(validate(number, gb), Err(Invalid::CallingCode), "{number}");
+        }
+        assert_eq!(
+            validate("12", None),
+            Err(Invalid::TooShort { digits: 2, min: 3 })
+        );
+    }
+
+    #[test]
+    fn lengths_depend_on_the_calling_code() {
+        assert_eq!(
+            validate("+1 212 555 010", None),
+            Err(Invalid::TooShort { digits: 9, min: 10 })
+        );
+        assert_eq!(
+            validate("+33 1 23 45 67 89 0", None),
+            Err(Invalid::TooLong { digits: 10, max: 9 })
+        );
+        assert_eq!(validate("0612 345 678", find_country("FR")), Ok(()));
+        assert_eq!(
+            validate("06 12 34 56 78 9", find_country("FR")),
+            Err(Invalid::TooLong { digits: 10, max: 9 })
+        );
+    }
+}
@@ -0,0 +1,938 @@
+//! Reading and writing contacts as vCards, version 3.0 (RFC 2426) or 4.0 (RFC 6350).
+//!
+//! Reading also understands the 2.1 format many phones still export, with bare
+//! parameters like `TEL;CELL` and quoted-printable values in any of the usual charsets.
+//! Labels vCard has no type for are written the way Apple Contacts writes them:
+//! as an `X-ABLabel` property in the same group as the number or address.
+
+use crate::{
+    entry::{
+        AddressLabel, Contact, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber, PostalAddress,
+    },
+    error::PhoneBookError,
+    normalize::Country,
+    validate,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+/// Lines longer than this many bytes are folded onto the next line.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// What `write_vcards` writes from the details of a contact. When a card is updated,
+/// these properties are written again and everything else is kept.
+const WRITTEN_PROPERTIES: &[&str] = &["BEGIN", "END", "VERSION", "FN", "TEL", "EMAIL", "ADR"];
+
+/// Types that say nothing about whose a number or address is.
+const GENERIC_TYPES: &[&str] = &[
+    "voice", "pref", "internet", "x400", "text", "msg", "other", "dom", "intl", "postal", "parcel",
+];
+
+/// The vCard version to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VCardVersion {
+    V3,
+    V4,
+}
+
+impl VCardVersion {
+    fn as_str(self) -> &'static str {
+        match self {
+            VCardVersion::V3 => "3.0",
+            VCardVersion::V4 => "4.0",
+        }
+    }
+}
+
+/// One unfolded content line, like `item1.TEL;TYPE=cell,pref:+44 7700 900123`.
+#[derive(Debug)]
+struct Property {
+    group: Option<String>,
+    /// In upper case.
+    name: String,
+    /// Parameter names in upper case, with their values split at commas.
+    params: Vec<(String, Vec<String>)>,
+    value: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Option<Property> {
+        let head = split_unquoted(line, ':')[0];
+        // Without a colon the head is the whole line and there is no value.
+        let value = line.get(head.len() + 1..)?;
+
+        let mut parts = split_unquoted(head, ';').into_iter();
+        let name = parts.next()?;
+        let (group, name) = match name.split_once('.') {
+            Some((group, name)) => (Some(group.to_owned()), name),
+            None => (None, name),
+        };
+        if name.is_empty() {
+            return None;
+        }
+
+        let params = parts
+            .map(|param| match param.split_once('=') {
+                Some((name, values)) => (
+                    name.trim().to_ascii_uppercase(),
+                    values
+                        .trim_matches('"')
+                        .split(',')
+                        .map(|value| value.trim().trim_matches('"').to_owned())
+                        .collect(),
+                ),
+                // vCard 2.1 leaves out the parameter name, as in `TEL;CELL;PREF`.
+                None if is_encoding(param) => ("ENCODING".to_owned(), vec![param.to_owned()]),
+                None => ("TYPE".to_owned(), vec![param.to_owned()]),
+            })
+            .collect();
+
+        Some(Property {
+            group,
+            name: name.trim().to_ascii_uppercase(),
+            params,
+            value: value.to_owned(),
+        })
+    }
+
+    fn param<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params
+            .iter()
+            .filter(move |(param, _)| param == name)
+            .flat_map(|(_, values)| values.iter().map(String::as_str))
+    }
+
+    fn has_type(&self, wanted: &str) -> bool {
+        self.param("TYPE").any(|ty| ty.eq_ignore_ascii_case(wanted))
+    }
+
+    /// The first type that is not one of `GENERIC_TYPES`, without an "x-" prefix.
+    fn custom_type(&self) -> Option<&str> {
+        self.param("TYPE")
+            .find(|ty| !GENERIC_TYPES.contains(&ty.to_ascii_lowercase().as_str()))
+            .map(|ty| {
+                ty.get(..2)
+                    .filter(|prefix| prefix.eq_ignore_ascii_case("x-"))
+                    .map_or(ty, |_| &ty[2..])
+            })
+    }
+
+    fn is_preferred(&self) -> bool {
+        self.has_type("pref") || self.param("PREF").next().is_some()
+    }
+
+    /// The value with any quoted-printable encoding undone, or None for binary values like photos.
+    fn decoded_value(&self) -> Option<String> {
+        let Some(encoding) = self.param("ENCODING").next() else {
+            return Some(self.value.clone());
+        };
+        if encoding.eq_ignore_ascii_case("8bit") || encoding.eq_ignore_ascii_case("7bit") {
+            return Some(self.value.clone());
+        }
+        if !encoding.eq_ignore_ascii_case("quoted-printable") {
+            return None;
+        }
+
+        let bytes = decode_quoted_printable(&self.value);
+        let latin1 = self.param("CHARSET").any(|charset| {
+            ["iso-8859-1", "latin1", "windows-1252"]
+                .iter()
+                .any(|latin1| charset.eq_ignore_ascii_case(latin1))
+        });
+        Some(match latin1 {
+            true => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            false => String::from_utf8_lossy(&bytes).into_owned(),
+        })
+    }
+}
+
+fn is_encoding(param: &str) -> bool {
+    ["quoted-printable", "base64", "8bit", "7bit"]
+        .iter()
+        .any(|encoding| param.eq_ignore_ascii_case(encoding))
+}
+
+/// Splits at `separator`, except inside double quotes.
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (position, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..position]);
+            start = position + 1;
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn decode_quoted_printable(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        let byte = bytes
+            .get(position + 1..position + 3)
+            .filter(|_| bytes[position] == b'=')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                position += 3;
+            }
+            None => {
+                decoded.push(bytes[position]);
+                position += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Joins folded lines, and quoted-printable lines ending in a soft line break, with the line after.
+/// Every line comes with its line number in `text`.
+fn unfold(text: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if let Some((_, last)) = lines.last_mut() {
+            if last.ends_with('=') && is_quoted_printable(last) {
+                last.pop();
+                last.push_str(line);
+                continue;
+            }
+            if let Some(folded) = line.strip_prefix([' ', '\t']) {
+                last.push_str(folded);
+                continue;
+            }
+        }
+        if !line.trim().is_empty() {
+            lines.push((number + 1, line.to_owned()));
+        }
+    }
+    lines
+}
+
+fn is_quoted_printable(line: &str) -> bool {
+    split_unquoted(line, ':')[0]
+        .to_ascii_uppercase()
+        .contains("QUOTED-PRINTABLE")
+}
+
+/// Splits a structured value like `N` or `ADR` at unescaped semicolons and undoes the escapes.
+fn components(value: &str) -> Vec<String> {
+    unescape(value, true)
+}
+
+/// Undoes the escapes of a text value, in which semicolons have no special meaning.
+fn text(value: &str) -> String {
+    unescape(value, false).concat()
+}
+
+fn unescape(value: &str, split: bool) -> Vec<String> {
+    let mut components = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let component = components
+            .last_mut()
+            .expect("there is always one component");
+        match c {
+            '\\' => match chars.next() {
+                Some('n' | 'N') => component.push('\n'),
+                Some(escaped) => component.push(escaped),
+                None => {}
+            },
+            ';' if split => components.push(String::new()),
+            c => component.push(c),
+        }
+    }
+    components
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+/// Apple wraps its own labels like `_$!<HomePage>!$_`.
+fn apple_label(label: &str) -> &str {
+    label
+        .strip_prefix("_$!<")
+        .and_then(|label| label.strip_suffix(">!$_"))
+        .unwrap_or(label)
+}
+
+/// Reads every card in `text`, each together with the line it starts on.
+/// All cards are checked before anything is returned, so every mistake is reported at once.
+/// Numbers are validated with `default_country`, just like the database will.
+pub fn read_vcards(
+    text: &str,
+    default_country: Option<&Country>,
+) -> Result<Vec<(String, String, PhoneEntry)>, PhoneBookError> {
+    let mut contacts = Vec::new();
+    let mut errors = Vec::new();
+    let mut card: Option<(usize, Vec<Property>)> = None;
+
+    for (line, content) in unfold(text) {
+        let Some(property) = Property::parse(&content) else {
+            errors.push(format!("line {line}: {content:?} is not a vCard property"));
+            continue;
+        };
+        let is_vcard = property.value.trim().eq_ignore_ascii_case("vcard");
+        match (property.name.as_str(), &mut card) {
+            ("BEGIN", None) if is_vcard => card = Some((line, Vec::new())),
+            ("END", Some(_)) if is_vcard => {
+                let (start, properties) = card.take().expect("inside a card");
+                match read_card(&properties, default_country) {
+                    Ok((name, entry)) => contacts.push((format!("line {start}"), name, entry)),
+                    Err(message) => errors.push(format!("line {start}: {message}")),
+                }
+            }
+            (_, Some((_, properties))) => properties.push(property),
+            (name, None) => errors.push(format!("line {line}: {name} is outside of a card")),
+        }
+    }
+    if let Some((start, _)) = card {
+        errors.push(format!("line {start}: the card has no END:VCARD"));
+    }
+
+    if !errors.is_empty() {
+        return Err(PhoneBookError::Import(errors.join("\n")));
+    }
+    Ok(contacts)
+}
+
+fn read_card(
+    properties: &[Property],
+    default_country: Option<&Country>,
+) -> Result<(String, PhoneEntry), String> {
+    let custom_labels = properties
+        .iter()
+        .filter(|property| property.name == "X-ABLABEL")
+        .filter_map(|property| {
+            let group = property.group.as_ref()?.to_ascii_lowercase();
+            Some((group, text(&property.decoded_value()?)))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut full_name = None;
+    let mut structured_name = None;
+    let mut entry = PhoneEntry::default();
+    for property in properties {
+        let Some(value) = property.decoded_value() else {
+            continue;
+        };
+        let custom_label = property
+            .group
+            .as_ref()
+            .and_then(|group| custom_labels.get(&group.to_ascii_lowercase()))
+            .map(|label| apple_label(label));
+
+        match property.name.as_str() {
+            "FN" => full_name = Some(text(&value)),
+            "N" => structured_name = Some(components(&value)),
+            "TEL" => {
+                let value = text(&value);
+                let number = value
+                    .get(..4)
+                    .filter(|scheme| scheme.eq_ignore_ascii_case("tel:"))
+                    .map_or(value.as_str(), |_| &value[4..])
+                    .trim();
+                let label = match custom_label {
+                    Some(label) => {
+                        let Ok(label) = label.parse();
+                        label
+                    }
+                    None => number_label(property),
+                };
+                let mut number = PhoneNumber::new(label, number);
+                number.preferred =
+                    property.is_preferred() && !entry.numbers.iter().any(|number| number.preferred);
+                entry.numbers.push(number);
+            }
+            "EMAIL" => entry.emails.push(EmailAddress {
+                label: address_label(property, custom_label),
+                address: text(&value).trim().to_owned(),
+            }),
+            "ADR" => {
+                let mut parts = components(&value)
+                    .into_iter()
+                    .map(|part| part.trim().to_owned());
+                let mut part = || parts.next().unwrap_or_default();
+                // The post office box and extended address are rarely used, so they go with the street.
+                let street = [part(), part(), part()]
+                    .into_iter()
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                entry.addresses.push(PostalAddress {
+                    label: address_label(property, custom_label),
+                    street,
+                    city: part(),
+                    region: part(),
+                    postcode: part(),
+                    country: part(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let name = full_name
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .or_else(|| {
+            // Family, given, additional names, prefixes and suffixes, in the order they are spoken.
+            let parts = structured_name?;
+            let name = [3, 1, 2, 0, 4]
+                .into_iter()
+                .filter_map(|position| parts.get(position))
+                .map(|part| part.trim())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            (!name.is_empty()).then_some(name)
+        })
+        .ok_or_else(|| "the card has no name".to_owned())?;
+    validate::validate_entry(&entry, default_country).map_err(|err| err.to_string())?;
+
+    Ok((name, entry))
+}
+
+fn number_label(property: &Property) -> NumberLabel {
+    const TYPES: [(&str, NumberLabel); 6] = [
+        ("fax", NumberLabel::Fax),
+        ("pager", NumberLabel::Pager),
+        ("cell", NumberLabel::Mobile),
+        ("main", NumberLabel::Main),
+        ("work", NumberLabel::Work),
+        ("home", NumberLabel::Home),
+    ];
+    if let Some((_, label)) = TYPES.into_iter().find(|(ty, _)| property.has_type(ty)) {
+        return label;
+    }
+    match property.custom_type() {
+        Some(label) => NumberLabel::Custom(label.to_owned()),
+        None => NumberLabel::Other,
+    }
+}
+
+fn address_label(property: &Property, custom_label: Option<&str>) -> AddressLabel {
+    if let Some(label) = custom_label {
+        let Ok(label) = label.parse();
+        return label;
+    }
+    if property.has_type("home") {
+        AddressLabel::Home
+    } else if property.has_type("work") {
+        AddressLabel::Work
+    } else {
+        match property.custom_type() {
+            Some(label) => AddressLabel::Custom(label.to_owned()),
+            None => AddressLabel::Other,
+        }
+    }
+}
+
+/// How a label is written in a vCard.
+enum LabelType<'a> {
+    /// As a type parameter, or without one for "other".
+    Type(Option<&'static str>),
+    /// As an `X-ABLabel`, because vCard has no type for it.
+    Custom(&'a str),
+}
+
+fn number_type(label: &NumberLabel) -> LabelType<'_> {
+    LabelType::Type(Some(match label {
+        NumberLabel::Mobile => "cell",
+        NumberLabel::Work => "work",
+        NumberLabel::Home => "home",
+        NumberLabel::Fax => "fax",
+        NumberLabel::Pager => "pager",
+        NumberLabel::Main => "main",
+        NumberLabel::Other => return LabelType::Type(None),
+        NumberLabel::Custom(label) => return LabelType::Custom(label),
+    }))
+}
+
+fn address_type(label: &AddressLabel) -> LabelType<'_> {
+    match label {
+        AddressLabel::Home => LabelType::Type(Some("home")),
+        AddressLabel::Work => LabelType::Type(Some("work")),
+        AddressLabel::Other => LabelType::Type(None),
+        AddressLabel::Custom(label) => LabelType::Custom(label),
+    }
+}
+
+/// The content lines of one card, before folding.
+struct Card {
+    version: VCardVersion,
+    lines: Vec<String>,
+    groups: usize,
+}
+
+impl Card {
+    fn new(contact: &Contact, version: VCardVersion) -> Card {
+        let name = escape(&contact.name);
+        let mut card = Card {
+            version,
+            lines: vec![
+                "BEGIN:VCARD".to_owned(),
+                format!("VERSION:{}", version.as_str()),
+                format!("FN:{name}"),
+                // 3.0 requires N, but the name cannot be told apart into its parts.
+                format!("N:;{name};;;"),
+                format!("UID:urn:uuid:{}", contact.uuid),
+            ],
+            groups: 0,
+        };
+
+        let entry = &contact.entry;
+        for number in &entry.numbers {
+            let value = escape(&number.number);
+            card.push("TEL", number_type(&number.label), number.preferred, &value);
+        }
+        for email in &entry.emails {
+            card.push(
+                "EMAIL",
+                address_type(&email.label),
+                false,
+                &escape(&email.address),
+            );
+        }
+        for address in &entry.addresses {
+            let parts = [
+                &address.street,
+                &address.city,
+                &address.region,
+                &address.postcode,
+                &address.country,
+            ]
+            .map(|part| escape(part))
+            .join(";");
+            card.push(
+                "ADR",
+                address_type(&address.label),
+                false,
+                &format!(";;{parts}"),
+            );
+        }
+        card.lines.push("END:VCARD".to_owned());
+        card
+    }
+
+    /// Replaces the line of the property `name` that `new` wrote.
+    fn replace(&mut self, name: &str, line: &str) {
+        let prefix = format!("{name}:");
+        if let Some(written) = self
+            .lines
+            .iter_mut()
+            .find(|written| written.starts_with(&prefix))
+        {
+            *written = line.to_owned();
+        }
+    }
+
+    fn push(&mut self, name: &str, label: LabelType, preferred: bool, value: &str) {
+        let mut types = Vec::new();
+        let mut params = String::new();
+        if let LabelType::Type(Some(ty)) = label {
+            types.push(ty);
+        }
+        if self.version == VCardVersion::V4 && name == "TEL" {
+            // Numbers in 4.0 are tel: URIs unless said otherwise.
+            params.push_str(";VALUE=text");
+        }
+        if preferred {
+            match self.version {
+                VCardVersion::V3 => types.push("pref"),
+                VCardVersion::V4 => params.push_str(";PREF=1"),
+            }
+        }
+        if !types.is_empty() {
+            params = format!(";TYPE={}{params}", types.join(","));
+        }
+
+        match label {
+            LabelType::Type(_) => self.lines.push(format!("{name}{params}:{value}")),
+            LabelType::Custom(label) => {
+                self.groups += 1;
+                let group = format!("item{}", self.groups);
+                self.lines.push(format!("{group}.{name}{params}:{value}"));
+                self.lines
+                    .push(format!("{group}.X-ABLabel:{}", escape(label)));
+            }
+        }
+    }
+}
+
+/// Writes one card for every contact.
+pub fn write_vcards(
+    contacts: &[Contact],
+    mut writer: impl Write,
+    version: VCardVersion,
+) -> Result<(), PhoneBookError> {
+    for contact in contacts {
+        for line in &Card::new(contact, version).lines {
+            write_folded(&mut writer, line)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes `contact` as a new version of `previous`, a card of the same contact that another
+/// program may have written. Whatever this program does not know about, like birthdays,
+/// photos or notes, is kept as it was, and so is the UID. The structured name is kept too,
+/// unless the contact was renamed.
+pub fn update_vcard(
+    previous: &str,
+    contact: &Contact,
+    mut writer: impl Write,
+) -> Result<(), PhoneBookError> {
+    let properties = unfold(previous)
+        .into_iter()
+        .filter_map(|(_, line)| Some((Property::parse(&line)?, line)))
+        .collect::<Vec<_>>();
+    let find = |name: &str| {
+        properties
+            .iter()
+            .find(|(property, _)| property.name == name)
+    };
+    let version = match find("VERSION") {
+        Some((property, _)) if property.value.trim() == "4.0" => VCardVersion::V4,
+        _ => VCardVersion::V3,
+    };
+    let renamed = find("FN")
+        .and_then(|(property, _)| property.decoded_value())
+        .is_none_or(|name| text(&name).trim() != contact.name);
+
+    let mut card = Card::new(contact, version);
+    let end = card.lines.pop().expect("every card ends with END:VCARD");
+    // Groups are numbered again after the ones just written, but the ones that held
+    // numbers, emails or addresses are left out with them.
+    let written_groups = properties
+        .iter()
+        .filter(|(property, _)| WRITTEN_PROPERTIES.contains(&property.name.as_str()))
+        .filter_map(|(property, _)| property.group.as_ref())
+        .map(|group| group.to_ascii_lowercase())
+        .collect::<HashSet<_>>();
+    let mut groups = HashMap::new();
+    for (property, line) in &properties {
+        match property.name.as_str() {
+            "UID" => card.replace("UID", line),
+            "N" if !renamed => card.replace("N", line),
+            name if WRITTEN_PROPERTIES.contains(&name) || name == "N" => {}
+            _ => match &property.group {
+                Some(group) if written_groups.contains(&group.to_ascii_lowercase()) => {}
+                Some(group) => {
+                    let renamed_group =
+                        groups.entry(group.to_ascii_lowercase()).or_insert_with(|| {
+                            card.groups += 1;
+                            format!("item{}", card.groups)
+                        });
+                    card.lines
+                        .push(format!("{renamed_group}{}", &line[group.len()..]));
+                }
+                None => card.lines.push(line.clone()),
+            },
+        }
+    }
+    card.lines.push(end);
+
+    for line in &card.lines {
+        write_folded(&mut writer, line)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// The UID of the first card in `text`, if it has one.
+pub fn read_uid(text: &str) -> Option<String> {
+    unfold(text)
+        .into_iter()
+        .filter_map(|(_, line)| Property::parse(&line))
+        .find(|property| property.name == "UID")
+        .and_then(|property| property.decoded_value())
+        .map(|uid| self::text(&uid).trim().to_owned())
+        .filter(|uid| !uid.is_empty())
+}
+
+/// Writes a line with CRLF, continuing it on indented lines when it is too long.
+fn write_folded(writer: &mut impl Write, line: &str) -> io::Result<()> {
+    let mut rest = line;
+    let mut limit = MAX_LINE_LENGTH;
+    while rest.len() > limit {
+        let mut split = limit;
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        write!(writer, "{}\r\n ", &rest[..split])?;
+        rest = &rest[split..];
+        // The space starting the next line counts towards its length.
+        limit = MAX_LINE_LENGTH - 1;
+    }
+    write!(writer, "{rest}\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_uid, read_vcards, update_vcard, write_vcards, VCardVersion, MAX_LINE_LENGTH};
+    use crate::{
+        entry::{
+            AddressLabel, Contact, ContactId, EmailAddress, NumberLabel, PhoneEntry, PhoneNumber,
+            PostalAddress,
+        },
+        error::PhoneBookError,
+    };
+    use uuid::Uuid;
+
+    fn read(text: &str) -> Vec<(String, PhoneEntry)> {
+        read_vcards(text, None)
+            .unwrap()
+            .into_iter()
+            .map(|(_, name, entry)| (name, entry))
+            .collect()
+    }
+
+    fn number(label: NumberLabel, number: &str, preferred: bool) -> PhoneNumber {
+        PhoneNumber {
+            preferred,
+            ..PhoneNumber::new(label, number)
+        }
+    }
+
+    fn address(label: AddressLabel, parts: &str) -> PostalAddress {
+        PostalAddress::from_parts(label, parts).unwrap()
+    }
+
+    /// Writes the contacts in both versions and checks they are read back unchanged.
+    fn assert_round_trips(contacts: &[(String, PhoneEntry)]) {
+        let contacts = contacts
+            .iter()
+            .enumerate()
+            .map(|(id, (name, entry))| Contact {
+                id: ContactId(id as i64),
+                uuid: Uuid::new_v4(),
+                name: name.clone(),
+                entry: entry.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        for version in [VCardVersion::V3, VCardVersion::V4] {
+            let mut vcf = Vec::new();
+            write_vcards(&contacts, &mut vcf, version).unwrap();
+            let vcf = String::from_utf8(vcf).unwrap();
+
+            assert!(vcf.contains(&format!("\r\nVERSION:{}\r\n", version.as_str())));
+            for line in vcf.split("\r\n") {
+                assert!(line.len() <= MAX_LINE_LENGTH, "{line:?} is too long");
+            }
+            let read_back = read(&vcf);
+            for (contact, (name, entry)) in contacts.iter().zip(&read_back) {
+                assert_eq!((&contact.name, &contact.entry), (name, entry), "{vcf}");
+            }
+            assert_eq!(read_back.len(), contacts.len());
+        }
+    }
+
+    #[test]
+    fn reads_apple_contacts() {
+        let contacts = read(include_str!("../tests/fixtures/apple.vcf"));
+
+        assert_eq!(
+            contacts,
+            vec![
+                (
+                    "Jane Doe".to_owned(),
+                    PhoneEntry {
+                        numbers: vec![
+                            number(NumberLabel::Mobile, "+44 7700 900123", true),
+                            number(NumberLabel::Work, "+44 20 7946 0958", false),
+                            number(
+                                NumberLabel::Custom("boat".to_owned()),
+                                "+1 212 555 0100",
+                                false
+                            ),
+                            number(NumberLabel::Fax, "+44 20 7946 0000", false),
+                        ],
+                        emails: vec![EmailAddress {
+                            label: AddressLabel::Home,
+                            address: "jane@example.com".to_owned(),
+                        }],
+                        addresses: vec![address(
+                            AddressLabel::Work,
+                            "1 Infinite Loop, Building 2;Cupertino;CA;95014;United States",
+                        )],
+                    }
+                ),
+                (
+                    "John Smith".to_owned(),
+                    PhoneEntry {
+                        numbers: vec![
+                            number(NumberLabel::Mobile, "+1 (212) 555-0199", false),
+                            number(NumberLabel::Pager, "+1 212 555 0198", false),
+                        ],
+                        ..PhoneEntry::default()
+                    }
+                ),
+            ]
+        );
+        assert_round_trips(&contacts);
+    }
+
+    #[test]
+    fn reads_quoted_printable_from_phones() {
+        let contacts = read(include_str!("../tests/fixtures/android-2.1.vcf"));
+
+        assert_eq!(
+            contacts,
+            vec![(
+                "Jürgen Müller".to_owned(),
+                PhoneEntry {
+                    numbers: vec![
+                        number(NumberLabel::Mobile, "+49 151 23456789", true),
+                        number(NumberLabel::Work, "+49 30 1234567", false),
+                        number(
+                            NumberLabel::Custom("Werkstatt".to_owned()),
+                            "+49 30 7654321",
+                            false
+                        ),
+                    ],
+                    emails: vec![EmailAddress {
+                        label: AddressLabel::Other,
+                        address: "juergen@example.de".to_owned(),
+                    }],
+                    addresses: vec![PostalAddress {
+                        street: "Hauptstraße 1\nHinterhaus".to_owned(),
+                        ..address(AddressLabel::Home, ";Berlin;;10115;Deutschland")
+                    }],
+                }
+            )]
+        );
+        assert_round_trips(&contacts);
+    }
+
+    #[test]
+    fn reads_version_4() {
+        let contacts = read(include_str!("../tests/fixtures/rfc6350.vcf"));
+
+        assert_eq!(
+            contacts,
+            vec![
+                (
+                    "Zoë Ångström".to_owned(),
+                    PhoneEntry {
+                        numbers: vec![
+                            number(NumberLabel::Home, "+1-555-555-5555;ext=5555", true),
+                            number(NumberLabel::Mobile, "+33-6-12-34-56-78", false),
+                        ],
+                        emails: vec![EmailAddress {
+                            label: AddressLabel::Work,
+                            address: "zoe@example.com".to_owned(),
+                        }],
+                        addresses: vec![address(
+                            AddressLabel::Home,
+                            "123 Main St;Anytown;;12345;France"
+                        )],
+                    }
+                ),
+                // Only N is given, so the name is put together from its parts.
+                ("Dr. Ada Lovelace".to_owned(), PhoneEntry::default()),
+            ]
+        );
+        assert_round_trips(&contacts);
+    }
+
+    #[test]
+    fn escapes_and_folds_what_it_writes() {
+        assert_round_trips(&[(
+            "Smith; Jones, and \\ \"Partners\" with a name much too long for a single line"
+                .to_owned(),
+            PhoneEntry {
+                emails: vec![EmailAddress {
+                    label: AddressLabel::Custom("Très, très long; label".to_owned()),
+                    address: "someone@example.com".to_owned(),
+                }],
+                addresses: vec![address(
+                    AddressLabel::Other,
+                    "Flat 1, ünïcödé Hôüsé, ünïcödé Strèèt\nBackyard;Town;;;",
+                )],
+                ..PhoneEntry::default()
+            },
+        )]);
+    }
+
+    #[test]
+    fn reports_every_bad_card() {
+        let vcf = "BEGIN:VCARD\r\nVERSION:3.0\r\nTEL:+44 20 7946 0958\r\nEND:VCARD\r\n\
+                   BEGIN:VCARD\r\nFN:Jack\r\nTEL:asdf\r\nEND:VCARD\r\n\
+                   FN:Jill\r\n\
+                   BEGIN:VCARD\r\nFN:Joe\r\n";
+
+        let err = read_vcards(vcf, None).unwrap_err();
+
+        let PhoneBookError::Import(message) = err else {
+            panic!("not an import error: {err}");
+        };
+        let lines = message.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4, "{message}");
+        assert!(lines[0].starts_with("line 1: the card has no name"));
+        assert!(lines[1].starts_with("line 5: invalid phone number"));
+        assert!(lines[2].starts_with("line 9: FN is outside of a card"));
+        assert!(lines[3].starts_with("line 10: the card has no END:VCARD"));
+    }
+
+    #[test]
+    fn updates_keep_what_other_programs_wrote() {
+        let previous = "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:sync-1234\r\nFN:Arnold Baker\r\n\
+            N:Baker;Arnold;;;\r\nTEL;TYPE=cell:07700 900123\r\n\
+            item1.TEL:020 7946 0958\r\nitem1.X-ABLabel:Desk\r\n\
+            item2.URL:https://example.com\r\nitem2.X-ABLabel:Blog\r\n\
+            BDAY:19700101\r\nEND:VCARD\r\n";
+        assert_eq!(read_uid(previous).as_deref(), Some("sync-1234"));
+
+        let mut contact = Contact {
+            id: ContactId(1),
+            uuid: Uuid::new_v4(),
+            name: "Arnold Baker".to_owned(),
+            entry: PhoneEntry {
+                numbers: vec![PhoneNumber::new(
+                    NumberLabel::Custom("Office".to_owned()),
+                    "020 7946 0000",
+                )],
+                ..PhoneEntry::default()
+            },
+        };
+        let mut updated = Vec::new();
+        update_vcard(previous, &contact, &mut updated).unwrap();
+        let updated = String::from_utf8(updated).unwrap();
+
+        assert_eq!(
+            updated,
+            "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:Arnold Baker\r\nN:Baker;Arnold;;;\r\n\
+            UID:sync-1234\r\nitem1.TEL;VALUE=text:020 7946 0000\r\nitem1.X-ABLabel:Office\r\n\
+            item2.URL:https://example.com\r\nitem2.X-ABLabel:Blog\r\n\
+            BDAY:19700101\r\nEND:VCARD\r\n"
+        );
+        assert_eq!(
+            read(&updated),
+            vec![(contact.name.clone(), contact.entry.clone())]
+        );
+
+        contact.name = "Arnie Baker".to_owned();
+        let mut renamed = Vec::new();
+        update_vcard(&updated, &contact, &mut renamed).unwrap();
+        let renamed = String::from_utf8(renamed).unwrap();
+        assert!(renamed.contains("\r\nN:;Arnie Baker;;;\r\n"), "{renamed}");
+        assert!(renamed.contains("\r\nUID:sync-1234\r\n"), "{renamed}");
+    }
+}
@@ -0,0 +1,595 @@
+//! A phone book kept as a vdir: a directory with one vCard file per contact,
+//! the way vdirsyncer, khard and other programs that sync contacts keep them.
+//!
+//! Cards created here are named after their UID, like `<uuid>.vcf`; cards other programs
+//! created keep the name they have. A card is written to a temporary file first and then
+//! renamed over the old one, so that no program ever reads half of it. Updating a card keeps
+//! everything in it this program does not know about (see `vcard::update_vcard`).
+//!
+//! The directory is read once when it is opened. Before a card is changed or removed, its
+//! modification time and size are compared with what they were then, and if another program
+//! has changed the file in the meantime, the change fails with `Conflict` instead of undoing
+//! theirs. Opening the directory again picks up what they did. Cards that cannot be read
+//! as a contact are skipped and never touched, see `VdirStore::skipped_cards`.
+//!
+//! Contact IDs and the default country have no place in a vCard, so they are kept in
+//! `.phone-book.json` in the same directory, which sync tools ignore like every dotfile.
+
+use crate::{
+    entry::{Contact, ContactId, PhoneEntry},
+    error::PhoneBookError,
+    normalize::{self, Country},
+    store::{self, PhoneBookStore},
+    validate,
+    vcard::{self, VCardVersion},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use uuid::Uuid;
+
+/// Where the contact IDs and the default country are kept.
+pub const INDEX_FILE_NAME: &str = ".phone-book.json";
+
+/// What a file looked like when it was last read or written, to notice when it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Etag {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Etag {
+    /// None when the file is gone.
+    fn of(file_path: &Path) -> io::Result<Option<Etag>> {
+        match fs::metadata(file_path) {
+            Ok(metadata) => Ok(Some(Etag {
+                modified: metadata.modified()?,
+                len: metadata.len(),
+            })),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Card {
+    file_name: String,
+    etag: Etag,
+    /// The card as it was read or written, to keep what other programs put in it.
+    text: String,
+    contact: Contact,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Index {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_country: Option<String>,
+    /// Never goes down, so that IDs of removed contacts are not handed out again.
+    next_id: i64,
+    /// The ID of the contact in every file, by file name.
+    ids: BTreeMap<String, ContactId>,
+}
+
+/// A card file that could not be read as a contact.
+#[derive(Debug)]
+struct SkippedCard {
+    file_name: String,
+    reason: String,
+    /// Kept in the index, so that the contact gets its ID back once the card is fixed.
+    id: Option<ContactId>,
+}
+
+/// Contacts kept as one vCard file each in a directory.
+#[derive(Debug)]
+pub struct VdirStore {
+    dir: PathBuf,
+    default_country: Option<&'static Country>,
+    next_id: i64,
+    cards: BTreeMap<ContactId, Card>,
+    skipped: Vec<SkippedCard>,
+}
+
+impl VdirStore {
+    /// Reads every `.vcf` file in `dir`, creating the directory if there is none.
+    /// Files that were not there before get new IDs, the oldest file first.
+    /// Files that cannot be read as a contact are skipped, see `skipped_cards`.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<VdirStore, PhoneBookError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let index = match fs::read_to_string(dir.join(INDEX_FILE_NAME)) {
+            Ok(text) => serde_json::from_str::<Index>(&text)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let default_country = match &index.default_country {
+            Some(code) => Some(
+                normalize::find_country(code)
+                    .ok_or_else(|| PhoneBookError::UnknownCountry(code.clone()))?,
+            ),
+            None => None,
+        };
+
+        let mut files = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name().to_string_lossy().into_owned();
+            let is_card = Path::new(&file_name)
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("vcf"));
+            if is_card && !file_name.starts_with('.') && dir_entry.file_type()?.is_file() {
+                // The etag is taken first, so that a change made while reading is noticed later.
+                let Some(etag) = Etag::of(&dir_entry.path())? else {
+                    continue;
+                };
+                files.push((etag, file_name));
+            }
+        }
+        files.sort_by(|(a, a_name), (b, b_name)| (a.modified, a_name).cmp(&(b.modified, b_name)));
+
+        let mut store = VdirStore {
+            dir,
+            default_country,
+            next_id: index.next_id,
+            cards: BTreeMap::new(),
+            skipped: Vec::new(),
+        };
+        for (etag, file_name) in files {
+            match store.read_card(&file_name) {
+                Ok((text, name, entry)) => {
+                    let id = match index.ids.get(&file_name) {
+                        Some(id) => *id,
+                        None => {
+                            store.next_id += 1;
+                            ContactId(store.next_id)
+                        }
+                    };
+                    let uuid = card_uuid(&text, &file_name);
+                    store.cards.insert(
+                        id,
+                        Card {
+                            file_name,
+                            etag,
+                            text,
+                            contact: Contact {
+                                id,
+                                uuid,
+                                name,
+                                entry,
+                            },
+                        },
+                    );
+                }
+                Err(reason) => {
+                    let id = index.ids.get(&file_name).copied();
+                    store.skipped.push(SkippedCard {
+                        file_name,
+                        reason,
+                        id,
+                    });
+                }
+            }
+        }
+
+        if store.known_ids() != index.ids || store.next_id != index.next_id {
+            store.save_index()?;
+        }
+        Ok(store)
+    }
+
+    /// The files that were skipped when the directory was opened, each with the reason,
+    /// like "broken.vcf: …". They are left as they are and are not part of the phone book.
+    pub fn skipped_cards(&self) -> Vec<String> {
+        self.skipped
+            .iter()
+            .map(|skipped| format!("{}: {}", skipped.file_name, skipped.reason))
+            .collect()
+    }
+
+    /// The ID of the contact in every file, by file name.
+    fn known_ids(&self) -> BTreeMap<String, ContactId> {
+        let skipped = self
+            .skipped
+            .iter()
+            .filter_map(|skipped| Some((skipped.file_name.clone(), skipped.id?)));
+        self.cards
+            .values()
+            .map(|card| (card.file_name.clone(), card.contact.id))
+            .chain(skipped)
+            .collect()
+    }
+
+    /// Reads a card file that should hold exactly one contact.
+    fn read_card(&self, file_name: &str) -> Result<(String, String, PhoneEntry), String> {
+        let text = fs::read_to_string(self.dir.join(file_name)).map_err(|err| err.to_string())?;
+        let mut contacts =
+            vcard::read_vcards(&text, self.default_country).map_err(|err| match err {
+                PhoneBookError::Import(message) => message,
+                err => err.to_string(),
+            })?;
+        if contacts.len() != 1 {
+            return Err(format!("has {} cards instead of one", contacts.len()));
+        }
+        let (_, name, entry) = contacts.remove(0);
+        Ok((text, name, entry))
+    }
+
+    fn save_index(&self) -> Result<(), PhoneBookError> {
+        let index = Index {
+            default_country: self.default_country.map(|country| country.code.to_owned()),
+            next_id: self.next_id,
+            ids: self.known_ids(),
+        };
+        let mut text = serde_json::to_vec_pretty(&index)?;
+        text.push(b'\n');
+        store::write_file_atomically(&self.dir.join(INDEX_FILE_NAME), &text)?;
+        Ok(())
+    }
+
+    fn existing_card(&self, id: ContactId) -> Result<&Card, PhoneBookError> {
+        self.cards
+            .get(&id)
+            .ok_or_else(|| PhoneBookError::NotFound(format!("ID {id}")))
+    }
+
+    /// Fails with `Conflict` if the file of the card is not as it was read.
+    fn check_unchanged(&self, card: &Card) -> Result<(), PhoneBookError> {
+        let file_path = self.dir.join(&card.file_name);
+        if Etag::of(&file_path)? == Some(card.etag) {
+            Ok(())
+        } else {
+            Err(PhoneBookError::Conflict(file_path.display().to_string()))
+        }
+    }
+
+    /// Writes a new version of the card of `contact`, keeping the rest of what it held.
+    fn update_card(&mut self, contact: Contact) -> Result<(), PhoneBookError> {
+        let card = self.existing_card(contact.id)?;
+        self.check_unchanged(card)?;
+        let mut text = Vec::new();
+        vcard::update_vcard(&card.text, &contact, &mut text)?;
+        let file_name = card.file_name.clone();
+        self.write_card(file_name, text, contact)
+    }
+
+    /// Writes the card of a new contact whose details were already checked.
+    fn add_card(
+        &mut self,
+        uuid: Uuid,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        let contact = Contact {
+            id: ContactId(self.next_id + 1),
+            uuid,
+            name,
+            entry,
+        };
+        let mut text = Vec::new();
+        vcard::write_vcards(std::slice::from_ref(&contact), &mut text, VCardVersion::V3)?;
+
+        let id = contact.id;
+        self.write_card(format!("{}.vcf", contact.uuid), text, contact)?;
+        self.next_id = id.0;
+        Ok(id)
+    }
+
+    fn write_card(
+        &mut self,
+        file_name: String,
+        text: Vec<u8>,
+        contact: Contact,
+    ) -> Result<(), PhoneBookError> {
+        let file_path = self.dir.join(&file_name);
+        store::write_file_atomically(&file_path, &text)?;
+        let etag = Etag::of(&file_path)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the card vanished after writing it",
+            )
+        })?;
+        let text = String::from_utf8(text).expect("cards are written as UTF-8");
+        self.cards.insert(
+            contact.id,
+            Card {
+                file_name,
+                etag,
+                text,
+                contact,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// The UID of a card as a UUID, from the card itself or else from the file name.
+/// A UID or file name that is not a UUID is hashed into one, so the card keeps its UUID
+/// every time it is read, while its UID stays as it is in the file.
+fn card_uuid(text: &str, file_name: &str) -> Uuid {
+    let as_uuid = |id: &str| {
+        let id = id.strip_prefix("urn:uuid:").unwrap_or(id);
+        Uuid::parse_str(id).unwrap_or_else(|_| Uuid::new_v5(&Uuid::NAMESPACE_URL, id.as_bytes()))
+    };
+    match vcard::read_uid(text) {
+        Some(uid) => as_uuid(&uid),
+        None => as_uuid(
+            &Path::new(file_name)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy(),
+        ),
+    }
+}
+
+impl PhoneBookStore for VdirStore {
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+
+    fn default_country(&self) -> Option<&'static Country> {
+        self.default_country
+    }
+
+    fn set_default_country(&mut self, code: Option<&str>) -> Result<(), PhoneBookError> {
+        self.default_country = match code {
+            Some(code) => Some(
+                normalize::find_country(code)
+                    .ok_or_else(|| PhoneBookError::UnknownCountry(code.to_owned()))?,
+            ),
+            None => None,
+        };
+        self.save_index()
+    }
+
+    fn insert_contact(
+        &mut self,
+        name: String,
+        entry: PhoneEntry,
+    ) -> Result<ContactId, PhoneBookError> {
+        validate::validate_entry(&entry, self.default_country)?;
+        let id = self.add_card(Uuid::new_v4(), name, entry)?;
+        self.save_index()?;
+        Ok(id)
+    }
+
+    fn copy_contacts(&mut self, contacts: &[Contact]) -> Result<Vec<ContactId>, PhoneBookError> {
+        for contact in contacts {
+            validate::validate_entry(&contact.entry, self.default_country)?;
+        }
+        let ids = contacts
+            .iter()
+            .map(|contact| self.add_card(contact.uuid, contact.name.clone(), contact.entry.clone()))
+            .collect::<Result<Vec<_>, _>>();
+        // The cards that were written are saved in the index even if a later one failed.
+        self.save_index()?;
+        ids
+    }
+
+    fn read_contact(&self, id: ContactId) -> Result<Option<Contact>, PhoneBookError> {
+        Ok(self.cards.get(&id).map(|card| card.contact.clone()))
+    }
+
+    fn read_all_contacts(&self) -> Result<Vec<Contact>, PhoneBookError> {
+        Ok(self
+            .cards
+            .values()
+            .map(|card| card.contact.clone())
+            .collect())
+    }
+
+    fn modify_contact(
+        &mut self,
+        id: ContactId,
+        entry: PhoneEntry,
+    ) -> Result<PhoneEntry, PhoneBookError> {
+        let mut contact = self.existing_card(id)?.contact.clone();
+        validate::validate_new_numbers(&entry, &contact.entry, self.default_country)?;
+        let previous = std::mem::replace(&mut contact.entry, entry);
+        self.update_card(contact)?;
+        Ok(previous)
+    }
+
+    /// Writes the card once, with the new details and the new name.
+    fn edit_contact(
+        &mut self,
+        id: ContactId,
+        entry: Option<PhoneEntry>,
+        new_name: Option<String>,
+    ) -> Result<(), PhoneBookError> {
+        if let Some(new_name) = &new_name {
+            let taken = self
+                .cards
+                .values()
+                .any(|card| card.contact.name == *new_name && card.contact.id != id);
+            if taken {
+                return Err(PhoneBookError::AlreadyExists(new_name.clone()));
+            }
+        }
+        let mut contact = self.existing_card(id)?.contact.clone();
+        if let Some(entry) = entry {
+            validate::validate_new_numbers(&entry, &contact.entry, self.default_country)?;
+            contact.entry = entry;
+        }
+        if let Some(new_name) = new_name {
+            contact.name = new_name;
+        }
+        self.update_card(contact)
+    }
+
+    fn remove_contact(&mut self, id: ContactId) -> Result<Contact, PhoneBookError> {
+        let card = self.existing_card(id)?;
+        self.check_unchanged(card)?;
+        fs::remove_file(self.dir.join(&card.file_name))?;
+
+        let card = self.cards.remove(&id).expect("the card was just found");
+        self.save_index()?;
+        Ok(card.contact)
+    }
+
+    fn rename_contact(&mut self, id: ContactId, new_name: String) -> Result<(), PhoneBookError> {
+        self.edit_contact(id, None, Some(new_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VdirStore, INDEX_FILE_NAME};
+    use crate::{
+        entry::{NumberLabel, PhoneEntry, PhoneNumber},
+        error::PhoneBookError,
+        store::PhoneBookStore,
+    };
+    use std::{fs, path::PathBuf};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("phone_book_vdir_{}", uuid::Uuid::new_v4()))
+    }
+
+    fn entry(number: &str) -> PhoneEntry {
+        PhoneEntry {
+            numbers: vec![PhoneNumber::new(NumberLabel::Work, number)],
+            ..PhoneEntry::default()
+        }
+    }
+
+    fn card_files(dir: &PathBuf) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|dir_entry| {
+                dir_entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn keeps_one_card_per_contact() {
+        let dir = temp_dir();
+        let mut store = VdirStore::open(&dir).unwrap();
+        store.set_default_country(Some("GB")).unwrap();
+        let arnold = store
+            .insert_contact("Arnold".to_owned(), entry("020 7946 0958"))
+            .unwrap();
+        let jack = store
+            .insert_contact("Jack".to_owned(), PhoneEntry::default())
+            .unwrap();
+        let uuid = store.read_contact(arnold).unwrap().unwrap().uuid;
+        assert_eq!(card_files(&dir).len(), 3);
+        assert!(card_files(&dir).contains(&format!("{uuid}.vcf")));
+
+        store.rename_contact(arnold, "Arnie".to_owned()).unwrap();
+        store.remove_contact(jack).unwrap();
+        assert_eq!(
+            card_files(&dir),
+            vec![INDEX_FILE_NAME.to_owned(), format!("{uuid}.vcf")]
+        );
+
+        let reopened = VdirStore::open(&dir).unwrap();
+        assert_eq!(reopened.default_country().unwrap().code, "GB");
+        assert_eq!(
+            reopened.read_all_contacts().unwrap(),
+            store.read_all_contacts().unwrap()
+        );
+        let mut reopened = reopened;
+        let mark = reopened
+            .insert_contact("Mark".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert!(mark > jack, "IDs are not handed out again");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn picks_up_cards_of_other_programs() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("from-phone.vcf"),
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:phone-42\r\nFN:Zoë\r\n\
+             TEL;TYPE=cell:+44 7700 900123\r\nNOTE:Met at the conference\r\nEND:VCARD\r\n",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "not a card").unwrap();
+
+        let mut store = VdirStore::open(&dir).unwrap();
+        let contacts = store.read_all_contacts().unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].name, "Zoë");
+        store
+            .modify_contact(contacts[0].id, entry("+44 20 7946 0958"))
+            .unwrap();
+
+        let text = fs::read_to_string(dir.join("from-phone.vcf")).unwrap();
+        assert!(text.contains("UID:phone-42\r\n"), "{text}");
+        assert!(text.contains("NOTE:Met at the conference\r\n"), "{text}");
+        assert!(
+            text.contains("TEL;TYPE=work:+44 20 7946 0958\r\n"),
+            "{text}"
+        );
+        assert!(!text.contains("7700"), "{text}");
+        let reopened = VdirStore::open(&dir).unwrap().read_all_contacts().unwrap();
+        assert_eq!(reopened[0].id, contacts[0].id);
+        // The UID is not a UUID, but the contact still has the same one every time.
+        assert_eq!(reopened[0].uuid, contacts[0].uuid);
+
+        let broken = "BEGIN:VCARD\r\nEND:VCARD\r\n";
+        fs::write(dir.join("broken.vcf"), broken).unwrap();
+        let mut store = VdirStore::open(&dir).unwrap();
+        let skipped = store.skipped_cards();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].starts_with("broken.vcf: "), "{skipped:?}");
+        assert_eq!(store.read_all_contacts().unwrap().len(), 1);
+        store
+            .insert_contact("Jack".to_owned(), PhoneEntry::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("broken.vcf")).unwrap(), broken);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_undo_changes_of_other_programs() {
+        let dir = temp_dir();
+        let mut store = VdirStore::open(&dir).unwrap();
+        let arnold = store
+            .insert_contact("Arnold".to_owned(), PhoneEntry::default())
+            .unwrap();
+        let uuid = store.read_contact(arnold).unwrap().unwrap().uuid;
+        let file_path = dir.join(format!("{uuid}.vcf"));
+
+        // Longer than before, so noticed even where modification times are coarse.
+        let edited = fs::read_to_string(&file_path)
+            .unwrap()
+            .replace("FN:Arnold", "FN:Arnold Baker");
+        fs::write(&file_path, edited).unwrap();
+
+        assert!(matches!(
+            store.rename_contact(arnold, "Arnie".to_owned()),
+            Err(PhoneBookError::Conflict(_))
+        ));
+        assert!(matches!(
+            store.remove_contact(arnold),
+            Err(PhoneBookError::Conflict(_))
+        ));
+        assert!(fs::read_to_string(&file_path)
+            .unwrap()
+            .contains("FN:Arnold Baker"));
+
+        let mut reopened = VdirStore::open(&dir).unwrap();
+        assert_eq!(
+            reopened.read_contact(arnold).unwrap().unwrap().name,
+            "Arnold Baker"
+        );
+        reopened.remove_contact(arnold).unwrap();
+        assert!(!file_path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}